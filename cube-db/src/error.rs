@@ -0,0 +1,53 @@
+//! # Errors
+//!
+//! Error returned by every repository method and by the migration runner.
+
+use std::fmt;
+
+/// A database error.
+///
+/// - `Sqlite`: A statement failed.
+/// - `Pool`: No pooled connection could be obtained.
+/// - `SchemaTooNew`: The database was migrated by a newer version of the server; `found` is its schema
+///   version and `supported` the latest version known to this build.
+#[derive(Debug)]
+pub enum Error {
+    Sqlite(rusqlite::Error),
+    Pool(r2d2::Error),
+    SchemaTooNew { found: u32, supported: u32 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Sqlite(e) => write!(f, "{e}"),
+            Error::Pool(e) => write!(f, "{e}"),
+            Error::SchemaTooNew { found, supported } => write!(
+                f,
+                "database schema version {found} is newer than the latest version supported by this build ({supported})"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Sqlite(e) => Some(e),
+            Error::Pool(e) => Some(e),
+            Error::SchemaTooNew { .. } => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::Sqlite(e)
+    }
+}
+
+impl From<r2d2::Error> for Error {
+    fn from(e: r2d2::Error) -> Self {
+        Error::Pool(e)
+    }
+}
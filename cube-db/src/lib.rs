@@ -0,0 +1,31 @@
+//! # Cube DB
+//!
+//! This crate is the database layer shared by `cube-server` and the Tauri backend.
+//!
+//! ## Features
+//! - Typed models for every table (see `models`).
+//! - The `Repository` trait, with one method per query the servers run.
+//! - `SqliteRepository`, the `rusqlite` implementation used by both binaries.
+//! - A pool of WAL-mode connections for concurrent servers (see `pool`).
+//! - Versioned schema migrations (see `schema`).
+//!
+//! ## Notes
+//! - Timestamps are stored as RFC 3339 text.
+//! - Methods are synchronous; callers decide how to run them (a connection pool on the blocking thread pool
+//!   in `cube-server`, a dedicated thread in the Tauri backend).
+
+mod error;
+pub mod models;
+pub mod pool;
+pub mod repository;
+pub mod schema;
+pub mod sqlite;
+
+pub use error::Error;
+pub use models::*;
+pub use pool::{open_pool, Pool, PooledRepository};
+pub use repository::Repository;
+pub use sqlite::SqliteRepository;
+
+/// Result of every repository method.
+pub type Result<T> = std::result::Result<T, Error>;
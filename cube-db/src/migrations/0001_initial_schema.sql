@@ -0,0 +1,55 @@
+CREATE TABLE IF NOT EXISTS uploads (
+    hash TEXT PRIMARY KEY,
+    filename TEXT,
+    size TEXT,
+    created_at TEXT NOT NULL,
+    device_id TEXT
+);
+
+CREATE TABLE IF NOT EXISTS tokens (
+    token TEXT PRIMARY KEY,
+    username TEXT NOT NULL,
+    ip TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    device_id TEXT,
+    device_name TEXT,
+    expires_at TEXT,
+    last_seen TEXT,
+    binding TEXT
+);
+
+CREATE TABLE IF NOT EXISTS auth_codes (
+    code TEXT PRIMARY KEY,
+    created_at TEXT NOT NULL,
+    ip TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS upload_sessions (
+    id TEXT PRIMARY KEY,
+    username TEXT NOT NULL,
+    filename TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    hash TEXT NOT NULL,
+    modified_at TEXT,
+    temp_path TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS upload_chunks (
+    session_id TEXT NOT NULL,
+    start_offset INTEGER NOT NULL,
+    length INTEGER NOT NULL,
+    PRIMARY KEY (session_id, start_offset)
+);
+
+CREATE TABLE IF NOT EXISTS transfer_jobs (
+    id TEXT PRIMARY KEY,
+    hash TEXT,
+    device_id TEXT,
+    state TEXT,
+    attempts INTEGER DEFAULT 0,
+    next_attempt_at TEXT,
+    last_error TEXT,
+    created_at TEXT,
+    updated_at TEXT
+);
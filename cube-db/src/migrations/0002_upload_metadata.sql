@@ -0,0 +1,25 @@
+-- Rebuilds `uploads` with an integer `size` and the metadata of received files.
+-- Sizes that are not a plain number of bytes are dropped.
+CREATE TABLE uploads_new (
+    hash TEXT PRIMARY KEY,
+    filename TEXT,
+    size INTEGER,
+    created_at TEXT NOT NULL,
+    device_id TEXT,
+    owner TEXT,
+    path TEXT,
+    modified_at TEXT,
+    uploaded_at TEXT
+);
+
+INSERT INTO uploads_new (hash, filename, size, created_at, device_id)
+SELECT
+    hash,
+    filename,
+    CASE WHEN trim(size) GLOB '[0-9]*' AND trim(size) NOT GLOB '*[^0-9]*' THEN CAST(trim(size) AS INTEGER) END,
+    COALESCE(created_at, strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+    device_id
+FROM uploads;
+
+DROP TABLE uploads;
+ALTER TABLE uploads_new RENAME TO uploads;
@@ -0,0 +1,14 @@
+-- EXIF metadata read from received files, one row per upload hash.
+CREATE TABLE IF NOT EXISTS photo_metadata (
+    hash TEXT PRIMARY KEY,
+    camera_make TEXT,
+    camera_model TEXT,
+    lens_model TEXT,
+    exposure_time TEXT,
+    f_number REAL,
+    iso INTEGER,
+    focal_length REAL,
+    gps_latitude REAL,
+    gps_longitude REAL,
+    taken_at TEXT
+);
@@ -0,0 +1,6 @@
+-- Grid thumbnail of each file, so listings don't have to look for it on disk.
+-- Existing rows are filled in by the servers at startup.
+ALTER TABLE uploads ADD COLUMN thumb TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_uploads_owner ON uploads(owner);
+CREATE INDEX IF NOT EXISTS idx_photo_metadata_camera_model ON photo_metadata(camera_model);
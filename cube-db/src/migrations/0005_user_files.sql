@@ -0,0 +1,26 @@
+-- Ownership of files, one row per user and content hash. `uploads` keeps one row per content.
+-- `path` is the user's own copy, `NULL` while the file was only announced by one of their devices.
+CREATE TABLE IF NOT EXISTS user_files (
+    username TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    filename TEXT,
+    path TEXT,
+    modified_at TEXT,
+    uploaded_at TEXT,
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (username, hash)
+);
+
+CREATE INDEX IF NOT EXISTS idx_user_files_hash ON user_files(hash);
+
+-- Received files belong to their uploader
+INSERT OR IGNORE INTO user_files (username, hash, filename, path, modified_at, uploaded_at, created_at)
+SELECT owner, hash, filename, path, modified_at, uploaded_at, created_at
+FROM uploads
+WHERE owner IS NOT NULL;
+
+-- Announced files belong to the user of the announcing device
+INSERT OR IGNORE INTO user_files (username, hash, filename, modified_at, created_at)
+SELECT tokens.username, uploads.hash, uploads.filename, uploads.modified_at, uploads.created_at
+FROM uploads
+JOIN tokens ON tokens.device_id = uploads.device_id;
@@ -0,0 +1,3 @@
+-- Where the content of each received file is stored in the blob store, `NULL` for files stored before it was
+-- recorded. Blobs stay where they were written when the upload directory changes.
+ALTER TABLE uploads ADD COLUMN blob TEXT;
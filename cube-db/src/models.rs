@@ -0,0 +1,308 @@
+//! # Models
+//!
+//! Rows of the database tables, as read and written by the `Repository`.
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// A file known to the server, announced with its thumbnail and/or received from a phone.
+///
+/// Read from `uploads`, one row per content, or as seen by one of its owners (see `Repository::find_owned_upload`),
+/// in which case the name, owner, path and times are that user's.
+///
+/// - `hash`: SHA-256 hash of the file.
+/// - `filename`: Original file name.
+/// - `size`: Size in bytes; the received size once the file is stored.
+/// - `device_id`: Device that announced the file.
+/// - `created_at`: When the row was first written.
+/// - `owner`: User who uploaded the file; `None` until it is received.
+/// - `path`: Where the file was saved; `None` until it is received.
+/// - `modified_at`: Capture time sent by the phone (`X-Modified-At`).
+/// - `uploaded_at`: When the file was stored.
+/// - `thumb`: File name of the grid thumbnail in `.thumbs`; `None` until one is stored.
+/// - `blob`: Where the content is stored in the blob store; `None` until it is received, or if it was stored
+///   before blob paths were recorded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Upload {
+    pub hash: String,
+    pub filename: Option<String>,
+    pub size: Option<u64>,
+    pub device_id: Option<String>,
+    pub created_at: Option<String>,
+    pub owner: Option<String>,
+    pub path: Option<String>,
+    pub modified_at: Option<String>,
+    pub uploaded_at: Option<String>,
+    pub thumb: Option<String>,
+    pub blob: Option<String>,
+}
+
+/// A file announced with its thumbnail; `created_at` is set by the repository.
+///
+/// - `owner`: User of the announcing device, who gets the file in their library.
+/// - `modified_at`: Capture time sent by the phone, if any.
+/// - `thumb`: File name of the stored grid thumbnail.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewUpload {
+    pub hash: String,
+    pub filename: String,
+    pub size: Option<u64>,
+    pub device_id: Option<String>,
+    pub owner: Option<String>,
+    pub modified_at: Option<DateTime<Utc>>,
+    pub thumb: Option<String>,
+}
+
+/// Order of the files listed by `Repository::query_uploads`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadSort {
+    /// EXIF capture date, falling back to the phone's capture time and then to when the row was written.
+    #[default]
+    CaptureDate,
+    /// When the file was stored, or announced if it was not received.
+    UploadDate,
+    /// File name, case-insensitive.
+    Name,
+    /// Size in bytes; files of unknown size sort as smallest.
+    Size,
+}
+
+/// Filters, order and page of `Repository::query_uploads`.
+///
+/// - `username`: User whose library is listed; `None` lists every known file once, as the desktop viewer sees it.
+/// - `captured_from`, `captured_to`: Inclusive range of capture dates, as sorted by `UploadSort::CaptureDate`.
+/// - `stored`: `Some(true)` for files the user uploaded (or anyone, without `username`), `Some(false)` for files that
+///   were only announced.
+/// - `camera_model`: EXIF camera model, case-insensitive.
+/// - `extension`: File name extension without the dot, case-insensitive.
+/// - `after`: `next_cursor` of the previous page; `None` for the first page.
+/// - `limit`: Maximum number of files in the page.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct UploadQuery {
+    pub username: Option<String>,
+    pub captured_from: Option<NaiveDateTime>,
+    pub captured_to: Option<NaiveDateTime>,
+    pub stored: Option<bool>,
+    pub camera_model: Option<String>,
+    pub extension: Option<String>,
+    pub sort: UploadSort,
+    pub descending: bool,
+    pub after: Option<UploadCursor>,
+    pub limit: u32,
+}
+
+/// Value of the sort field of a listed file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SortKey {
+    Integer(i64),
+    Text(String),
+}
+
+/// Position of the last file of a page, from which the next page starts.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UploadCursor {
+    pub key: SortKey,
+    pub hash: String,
+}
+
+/// A listed file with its EXIF metadata, if it was received and had any.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListedUpload {
+    pub upload: Upload,
+    pub metadata: Option<PhotoMetadata>,
+}
+
+/// What `Repository::remove_owned_file` removed.
+///
+/// - `path`: The user's copy, to delete from disk; `None` if the file was only announced.
+/// - `orphaned`: No user owns the content any more, so its thumbnails can be deleted too.
+/// - `blob`: The stored content of an orphaned file, to delete from disk; `None` if someone still owns the file or
+///   its blob path was not recorded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemovedFile {
+    pub path: Option<PathBuf>,
+    pub orphaned: bool,
+    pub blob: Option<PathBuf>,
+}
+
+/// A page of `Repository::query_uploads`.
+///
+/// - `total`: Number of files matching the filters, across every page.
+/// - `next_cursor`: Where the next page starts; `None` on the last page.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UploadPage {
+    pub items: Vec<ListedUpload>,
+    pub total: u64,
+    pub next_cursor: Option<UploadCursor>,
+}
+
+/// A file received from a phone and moved to its final path.
+///
+/// - `size`: Number of bytes received.
+/// - `owner`: User who uploaded the file.
+/// - `path`: The owner's copy; other users' copies of the same content are recorded separately.
+/// - `modified_at`: Capture time sent by the phone, if any.
+/// - `blob`: Where the content is stored in the blob store, shared by every copy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredFile {
+    pub hash: String,
+    pub filename: String,
+    pub size: u64,
+    pub owner: String,
+    pub path: PathBuf,
+    pub modified_at: Option<DateTime<Utc>>,
+    pub uploaded_at: DateTime<Utc>,
+    pub blob: PathBuf,
+}
+
+/// EXIF metadata read from a received file, completed with its embedded XMP metadata.
+///
+/// - `exposure_time`: As written by the camera, e.g. `1/250`.
+/// - `gps_latitude`, `gps_longitude`: Decimal degrees, negative south and west.
+/// - `taken_at`: `DateTimeOriginal`, the camera's local time when the photo was taken.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct PhotoMetadata {
+    pub hash: String,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub lens_model: Option<String>,
+    pub exposure_time: Option<String>,
+    pub f_number: Option<f64>,
+    pub iso: Option<u32>,
+    pub focal_length: Option<f64>,
+    pub gps_latitude: Option<f64>,
+    pub gps_longitude: Option<f64>,
+    pub taken_at: Option<NaiveDateTime>,
+}
+
+/// A pairing code.
+///
+/// - `created_at`: `None` if the stored value is not a valid date.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthCode {
+    pub code: String,
+    pub ip: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+/// Fields of a new `tokens` row.
+///
+/// - `ip`: Address the phone paired from.
+/// - `binding`: Token binding (`none`, `ip` or `subnet`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewToken {
+    pub token: String,
+    pub username: String,
+    pub ip: String,
+    pub device_id: String,
+    pub device_name: String,
+    pub binding: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// A session token as needed to authenticate a request.
+///
+/// - `expires_at`: `None` if missing or not a valid date; such tokens are treated as expired.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenRecord {
+    pub token: String,
+    pub username: String,
+    pub ip: Option<String>,
+    pub device_id: Option<String>,
+    pub binding: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// A paired device as returned by the administration endpoints.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Device {
+    pub id: String,
+    pub username: String,
+    pub device_name: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: Option<String>,
+    pub last_seen: Option<String>,
+    pub expires_at: Option<String>,
+    pub binding: Option<String>,
+}
+
+/// A resumable upload session.
+///
+/// - `temp_path`: Partial file in the upload directory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UploadSession {
+    pub id: String,
+    pub username: String,
+    pub filename: String,
+    pub size: u64,
+    pub hash: String,
+    pub modified_at: Option<DateTime<Utc>>,
+    pub temp_path: PathBuf,
+}
+
+/// State of a transfer job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferState {
+    /// Waiting for the owning device to connect.
+    Pending,
+    /// `send_raw` was sent, waiting for the upload to start.
+    Dispatched,
+    /// The phone opened an upload session for the hash.
+    Uploading,
+    /// The file was stored.
+    Done,
+    /// The file did not arrive in time.
+    Failed,
+}
+
+impl TransferState {
+    /// Value stored in the `state` column.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransferState::Pending => "pending",
+            TransferState::Dispatched => "dispatched",
+            TransferState::Uploading => "uploading",
+            TransferState::Done => "done",
+            TransferState::Failed => "failed",
+        }
+    }
+
+    /// Reads the `state` column. Unknown values are treated as `Pending`.
+    pub fn parse(value: &str) -> Self {
+        match value {
+            "dispatched" => TransferState::Dispatched,
+            "uploading" => TransferState::Uploading,
+            "done" => TransferState::Done,
+            "failed" => TransferState::Failed,
+            _ => TransferState::Pending,
+        }
+    }
+
+    /// Returns true for `Done` and `Failed`.
+    pub fn is_finished(&self) -> bool {
+        matches!(self, TransferState::Done | TransferState::Failed)
+    }
+}
+
+/// A row of the `transfer_jobs` table.
+///
+/// - `id`: Job ID, sent to desktop viewers with every update.
+/// - `hash`: Hash of the requested file.
+/// - `device_id`: Device that announced the file and is asked to upload it.
+/// - `state`: Current state of the job.
+/// - `attempts`: Number of `send_raw` messages sent so far.
+/// - `last_error`: Why the job failed, if it did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransferJob {
+    pub id: String,
+    pub hash: String,
+    pub device_id: String,
+    pub state: TransferState,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+}
@@ -0,0 +1,106 @@
+//! # Connection Pool
+//!
+//! A pool of SQLite connections for servers that run queries from many tasks at once.
+//!
+//! ## Features
+//! - `open_pool` migrates the database once, then hands out connections to it.
+//! - The database is switched to WAL mode, so readers do not wait for the writer, and writers wait up to
+//!   `BUSY_TIMEOUT` for each other instead of failing with `SQLITE_BUSY`.
+//! - `PooledRepository` is a `SqliteRepository` over a pooled connection, which goes back to the pool when
+//!   the repository is dropped.
+//!
+//! ## Notes
+//! - Pooled connections are blocking; async servers should use them from a blocking thread
+//!   (e.g. `tokio::task::spawn_blocking`).
+
+use r2d2::PooledConnection;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::Connection;
+use std::{path::Path, time::Duration};
+
+use crate::sqlite::{AsConnection, SqliteRepository};
+use crate::{schema, Result};
+
+/// A pool of connections to one database file.
+pub type Pool = r2d2::Pool<SqliteConnectionManager>;
+
+/// A `Repository` over a connection borrowed from a `Pool`.
+pub type PooledRepository = SqliteRepository<PooledConnection<SqliteConnectionManager>>;
+
+/// How long a connection waits for a lock held by another connection.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl AsConnection for PooledConnection<SqliteConnectionManager> {
+    fn as_connection(&self) -> &Connection {
+        self
+    }
+}
+
+impl PooledRepository {
+    /// Borrows a connection from `pool`, waiting for one to be free.
+    pub fn from_pool(pool: &Pool) -> Result<Self> {
+        Ok(SqliteRepository::new(pool.get()?))
+    }
+}
+
+/// Opens a pool of at most `max_size` connections to the database at `path`.
+///
+/// # Flow
+/// - Switches the database to WAL mode and applies pending migrations on a single connection.
+/// - Builds the pool; every new connection gets a busy timeout and `synchronous = NORMAL`, which is safe in WAL mode.
+///
+/// Fails with `Error::SchemaTooNew` if the database was migrated by a newer build.
+pub fn open_pool(path: impl AsRef<Path>, max_size: u32) -> Result<Pool> {
+    let conn = Connection::open(&path)?;
+    conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+    schema::migrate(&conn)?;
+    drop(conn);
+
+    let manager = SqliteConnectionManager::file(path).with_init(|conn| {
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.pragma_update(None, "synchronous", "NORMAL")
+    });
+
+    Ok(Pool::builder().max_size(max_size).build(manager)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Repository, StoredFile};
+    use chrono::Utc;
+
+    #[test]
+    fn pooled_connections_share_the_database() {
+        let path = std::env::temp_dir().join(format!("cube-db-pool-{}.db", std::process::id()));
+        let pool = open_pool(&path, 2).unwrap();
+
+        let writer = PooledRepository::from_pool(&pool).unwrap();
+        let reader = PooledRepository::from_pool(&pool).unwrap();
+
+        let mode: String = reader.connection().query_row("PRAGMA journal_mode", [], |row| row.get(0)).unwrap();
+        assert_eq!(mode, "wal");
+
+        // Readers see committed rows while the writer holds an open transaction
+        writer
+            .save_stored_file(&StoredFile {
+                hash: "a".to_string(),
+                filename: "a.jpg".to_string(),
+                size: 1,
+                owner: "ana".to_string(),
+                path: "uploads/a.jpg".into(),
+                modified_at: None,
+                uploaded_at: Utc::now(),
+                blob: "uploads/blobs/a".into(),
+            })
+            .unwrap();
+        writer.connection().execute_batch("BEGIN IMMEDIATE;").unwrap();
+        assert!(reader.file_stored("a").unwrap());
+        writer.connection().execute_batch("ROLLBACK;").unwrap();
+
+        drop((writer, reader, pool));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! # Repository
+//!
+//! Every query run by the servers, as typed methods.
+
+use chrono::{DateTime, Utc};
+use std::path::{Path, PathBuf};
+
+use crate::models::*;
+use crate::Result;
+
+/// Typed access to the `uploads`, `user_files`, `photo_metadata`, `auth_codes`, `tokens`, `upload_sessions`,
+/// `upload_chunks` and `transfer_jobs` tables.
+///
+/// `uploads` has one row per content hash, shared by every user; `user_files` records which users own it.
+pub trait Repository {
+    // --- Uploads ---
+
+    /// Returns true if a file with this hash was received and stored by any user; announced files do not count.
+    fn file_stored(&self, hash: &str) -> Result<bool>;
+
+    /// Returns the device that announced the file, if any.
+    fn upload_device(&self, hash: &str) -> Result<Option<String>>;
+
+    /// Records files announced with their thumbnails, in a single transaction, and adds them to the library of
+    /// the announcing user.
+    ///
+    /// Known files get the announcing device and thumbnail; their name, size and capture time are only
+    /// filled in if missing, so the metadata of a stored file is kept. Files already in another user's library
+    /// are skipped, and a device of another user is never replaced as the file's device.
+    fn announce_uploads(&self, uploads: &[NewUpload]) -> Result<()>;
+
+    /// Records a received file in the owner's library, merging it into the row of an announced file with the
+    /// same hash. The stored path of a file already received from another user is kept.
+    fn save_stored_file(&self, file: &StoredFile) -> Result<()>;
+
+    /// Returns true if a user other than `username` has the file in their library, stored or announced.
+    fn file_claimed_by_other(&self, hash: &str, username: &str) -> Result<bool>;
+
+    /// Returns true if `username` stored the file, or announced it and no other user has it in their library.
+    fn file_visible_to(&self, hash: &str, username: &str) -> Result<bool>;
+
+    /// Looks up a known file by hash.
+    fn find_upload(&self, hash: &str) -> Result<Option<Upload>>;
+
+    /// Looks up a file in the library of `username`, with that user's name, path and times.
+    fn find_owned_upload(&self, username: &str, hash: &str) -> Result<Option<Upload>>;
+
+    /// Removes a file from the library of `username`, in a single transaction.
+    ///
+    /// If another user still has a copy, it becomes the stored path of the file. If nobody owns the file any
+    /// more, its row and EXIF metadata are deleted. Returns `None` if the user did not own the file.
+    fn remove_owned_file(&self, username: &str, hash: &str) -> Result<Option<RemovedFile>>;
+
+    /// Lists every known file.
+    fn list_uploads(&self) -> Result<Vec<Upload>>;
+
+    /// Lists the received copy of every owner of every file, as seen by that owner, ordered by hash.
+    fn list_stored_copies(&self) -> Result<Vec<Upload>>;
+
+    /// Lists one page of the files of a user's library, or of every known file, that have a grid thumbnail and
+    /// match the filters of `query`.
+    fn query_uploads(&self, query: &UploadQuery) -> Result<UploadPage>;
+
+    /// Records where the content of a received file is stored in the blob store.
+    fn set_blob(&self, hash: &str, blob: &Path) -> Result<()>;
+
+    /// Records the grid thumbnail of a file.
+    fn set_thumb(&self, hash: &str, thumb: &str) -> Result<()>;
+
+    /// Hashes of the known files without a recorded grid thumbnail.
+    fn uploads_without_thumb(&self) -> Result<Vec<String>>;
+
+    // --- Photo metadata ---
+
+    /// Saves the EXIF metadata of a file, replacing any previous metadata for its hash.
+    fn save_photo_metadata(&self, metadata: &PhotoMetadata) -> Result<()>;
+
+    /// Lists the EXIF metadata of every file that had any.
+    fn list_photo_metadata(&self) -> Result<Vec<PhotoMetadata>>;
+
+    // --- Pairing codes ---
+
+    /// Saves a pairing code, replacing any code with the same value.
+    fn save_auth_code(&self, code: &str, ip: &str, created_at: DateTime<Utc>) -> Result<()>;
+
+    /// Deletes a pairing code and returns it, in a single statement, so it can only be used once even by concurrent
+    /// requests.
+    fn take_auth_code(&self, code: &str) -> Result<Option<AuthCode>>;
+
+    /// Deletes the codes created before `cutoff`. Returns the number of deleted codes.
+    fn delete_auth_codes_before(&self, cutoff: DateTime<Utc>) -> Result<usize>;
+
+    // --- Tokens and devices ---
+
+    /// Saves a new session token; `last_seen` starts at `created_at`.
+    fn insert_token(&self, token: &NewToken) -> Result<()>;
+
+    /// Looks up a session token.
+    fn find_token(&self, token: &str) -> Result<Option<TokenRecord>>;
+
+    /// Records that a token was used at `at`.
+    fn touch_token(&self, token: &str, at: DateTime<Utc>) -> Result<()>;
+
+    /// Replaces token `old` with `new`, keeping the device. Returns false if `old` does not exist.
+    fn replace_token(&self, old: &str, new: &str, expires_at: DateTime<Utc>, at: DateTime<Utc>) -> Result<bool>;
+
+    /// Lists every paired device, most recently seen first.
+    fn list_devices(&self) -> Result<Vec<Device>>;
+
+    /// Deletes the token of a device and returns it, in a single statement, or `None` if no device has this ID.
+    fn revoke_device(&self, device_id: &str) -> Result<Option<String>>;
+
+    // --- Upload sessions ---
+
+    /// Returns the ID of the session of `username` for `hash`, if any.
+    fn find_session_id(&self, username: &str, hash: &str) -> Result<Option<String>>;
+
+    /// Saves a new upload session.
+    fn insert_session(&self, session: &UploadSession) -> Result<()>;
+
+    /// Loads a session, if it belongs to `username`.
+    fn find_session(&self, id: &str, username: &str) -> Result<Option<UploadSession>>;
+
+    /// Deletes a session of `username` and its chunks, and returns it, in a single statement. When two callers
+    /// race, only one gets the session.
+    fn take_session(&self, id: &str, username: &str) -> Result<Option<UploadSession>>;
+
+    /// Records that `length` bytes were written at `offset`.
+    fn record_chunk(&self, session_id: &str, offset: u64, length: u64) -> Result<()>;
+
+    /// Returns the `(start, end)` byte ranges of the recorded chunks, ordered by start.
+    fn session_chunks(&self, session_id: &str) -> Result<Vec<(u64, u64)>>;
+
+    /// Forgets every chunk of a session.
+    fn clear_chunks(&self, session_id: &str) -> Result<()>;
+
+    /// Deletes a session and its chunks.
+    fn delete_session(&self, id: &str) -> Result<()>;
+
+    /// Deletes the sessions created before `cutoff` and their chunks. Returns the partial files of the deleted sessions.
+    fn delete_sessions_before(&self, cutoff: DateTime<Utc>) -> Result<Vec<PathBuf>>;
+
+    // --- Transfer jobs ---
+
+    /// Saves a new transfer job.
+    fn insert_job(&self, job: &TransferJob) -> Result<()>;
+
+    /// Writes the state, attempts and error of a job.
+    fn save_job(&self, job: &TransferJob, next_attempt_at: Option<DateTime<Utc>>) -> Result<()>;
+
+    /// Unfinished jobs for a hash on the paired devices of `username`, oldest first.
+    fn active_jobs_for_hash(&self, hash: &str, username: &str) -> Result<Vec<TransferJob>>;
+
+    /// Unfinished jobs of a device, oldest first.
+    fn active_jobs_for_device(&self, device_id: &str) -> Result<Vec<TransferJob>>;
+
+    /// Dispatched or uploading jobs whose next attempt is due at `now`, oldest first.
+    fn due_jobs(&self, now: DateTime<Utc>) -> Result<Vec<TransferJob>>;
+}
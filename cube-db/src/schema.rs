@@ -0,0 +1,285 @@
+//! # Schema
+//!
+//! Versioned migrations for the tables used by the `Repository`.
+//!
+//! ## Features
+//! - `MIGRATIONS` lists the up-migrations in order; each one runs once, in its own transaction.
+//! - Applied versions are recorded in the `schema_version` table, with a description and the time they ran.
+//! - Opening a database migrated by a newer build fails with `Error::SchemaTooNew` instead of touching it.
+//!
+//! ## Notes
+//! - To change the schema, add a new `Migration` at the end of `MIGRATIONS`; never edit an applied one.
+//! - Databases created before `schema_version` existed get the columns added since the first release and
+//!   are then migrated from version 1, whose tables are created with `IF NOT EXISTS`.
+//! - The desktop app's first release created its database through `tauri-plugin-sql`, with the tables of version
+//!   1 and a `_sqlx_migrations` table. It is upgraded like any database created before `schema_version`;
+//!   `_sqlx_migrations` is left alone.
+
+use chrono::Utc;
+use rusqlite::{params, Connection};
+
+use crate::{Error, Result};
+
+/// A schema change.
+///
+/// - `version`: Position in `MIGRATIONS`, starting at 1.
+/// - `description`: Short summary stored in `schema_version`.
+/// - `sql`: Statements run in a single batch.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+/// Every migration, oldest first.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        sql: include_str!("./migrations/0001_initial_schema.sql"),
+    },
+    Migration {
+        version: 2,
+        description: "upload metadata",
+        sql: include_str!("./migrations/0002_upload_metadata.sql"),
+    },
+    Migration {
+        version: 3,
+        description: "photo metadata",
+        sql: include_str!("./migrations/0003_photo_metadata.sql"),
+    },
+    Migration {
+        version: 4,
+        description: "upload listing",
+        sql: include_str!("./migrations/0004_upload_listing.sql"),
+    },
+    Migration {
+        version: 5,
+        description: "user files",
+        sql: include_str!("./migrations/0005_user_files.sql"),
+    },
+    Migration {
+        version: 6,
+        description: "blob paths",
+        sql: include_str!("./migrations/0006_blob_paths.sql"),
+    },
+];
+
+/// Columns added by hand to databases created before `schema_version`, as `(table, column definition)`.
+const LEGACY_COLUMNS: &[(&str, &str)] = &[
+    ("uploads", "device_id TEXT"),
+    ("tokens", "device_id TEXT"),
+    ("tokens", "device_name TEXT"),
+    ("tokens", "expires_at TEXT"),
+    ("tokens", "last_seen TEXT"),
+    ("tokens", "binding TEXT"),
+];
+
+/// Latest schema version known to this build.
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+/// Returns the schema version of the database, 0 if no migration ran yet.
+pub fn current_version(conn: &Connection) -> Result<u32> {
+    create_version_table(conn)?;
+    let version = conn.query_row("SELECT COALESCE(MAX(version), 0) FROM schema_version", [], |row| row.get(0))?;
+    Ok(version)
+}
+
+/// Applies the pending migrations.
+///
+/// # Flow
+/// - Refuses databases whose version is newer than `latest_version`.
+/// - Upgrades databases created before `schema_version` existed.
+/// - Runs every migration newer than the current version and records it.
+pub fn migrate(conn: &Connection) -> Result<()> {
+    let current = current_version(conn)?;
+    let supported = latest_version();
+
+    if current > supported {
+        return Err(Error::SchemaTooNew { found: current, supported });
+    }
+
+    if current == 0 && table_exists(conn, "uploads")? {
+        upgrade_legacy(conn)?;
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        let tx = conn.unchecked_transaction()?;
+        tx.execute_batch(migration.sql)?;
+        tx.execute(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, ?2, ?3)",
+            params![migration.version, migration.description, Utc::now().to_rfc3339()],
+        )?;
+        tx.commit()?;
+        println!("📄 Applied migration {}: {}", migration.version, migration.description);
+    }
+
+    Ok(())
+}
+
+fn create_version_table(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at TEXT NOT NULL
+        );",
+    )?;
+    Ok(())
+}
+
+fn table_exists(conn: &Connection, table: &str) -> Result<bool> {
+    let exists = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+        [table],
+        |row| row.get(0),
+    )?;
+    Ok(exists)
+}
+
+/// Brings a database created before `schema_version` to the columns of version 1.
+fn upgrade_legacy(conn: &Connection) -> Result<()> {
+    // Fails harmlessly when the table is missing or the column already exists
+    for (table, column) in LEGACY_COLUMNS {
+        let _ = conn.execute(&format!("ALTER TABLE {} ADD COLUMN {}", table, column), []);
+    }
+
+    // Give tokens issued before expiry existed a device ID and a fresh expiry date
+    if table_exists(conn, "tokens")? {
+        conn.execute_batch(
+            "UPDATE tokens SET device_id = lower(hex(randomblob(16))) WHERE device_id IS NULL;
+             UPDATE tokens SET expires_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now', '+30 days') WHERE expires_at IS NULL;",
+        )?;
+    }
+
+    println!("📄 Upgraded a database created before schema versioning");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_database_is_at_latest_version() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        assert!(table_exists(&conn, "transfer_jobs").unwrap());
+    }
+
+    #[test]
+    fn migrate_is_idempotent() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        migrate(&conn).unwrap();
+
+        let applied: u32 = conn.query_row("SELECT COUNT(*) FROM schema_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(applied as usize, MIGRATIONS.len());
+    }
+
+    #[test]
+    fn legacy_database_is_upgraded() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE uploads (hash TEXT PRIMARY KEY, filename TEXT, size TEXT, created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP);
+             CREATE TABLE tokens (token TEXT PRIMARY KEY, username TEXT, ip TEXT, created_at TIMESTAMP);
+             INSERT INTO tokens (token, username, ip) VALUES ('t', 'ana', '10.0.0.2');",
+        )
+        .unwrap();
+
+        migrate(&conn).unwrap();
+
+        let (device_id, expires_at): (Option<String>, Option<String>) = conn
+            .query_row("SELECT device_id, expires_at FROM tokens WHERE token = 't'", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert!(device_id.is_some());
+        assert!(expires_at.is_some());
+        conn.execute("UPDATE uploads SET device_id = 'd'", []).unwrap();
+        assert!(table_exists(&conn, "upload_sessions").unwrap());
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+    }
+
+    #[test]
+    fn upload_sizes_become_integers() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE uploads (hash TEXT PRIMARY KEY, filename TEXT, size TEXT, created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP);
+             INSERT INTO uploads (hash, filename, size) VALUES ('a', 'a.jpg', '2048'), ('b', 'b.jpg', '2 MB');",
+        )
+        .unwrap();
+
+        migrate(&conn).unwrap();
+
+        let mut stmt = conn.prepare("SELECT size FROM uploads ORDER BY hash").unwrap();
+        let sizes: Vec<Option<i64>> = stmt.query_map([], |row| row.get(0)).unwrap().collect::<rusqlite::Result<_>>().unwrap();
+        assert_eq!(sizes, vec![Some(2048), None]);
+    }
+
+    #[test]
+    fn desktop_database_is_adopted() {
+        // As created by the desktop app's first release, through `tauri-plugin-sql`
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE _sqlx_migrations (
+                 version BIGINT PRIMARY KEY,
+                 description TEXT NOT NULL,
+                 installed_on TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                 success BOOLEAN NOT NULL,
+                 checksum BLOB NOT NULL,
+                 execution_time BIGINT NOT NULL
+             );
+             INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+             VALUES (1, 'create tables', TRUE, x'00', 0);
+             CREATE TABLE uploads (hash TEXT PRIMARY KEY, filename TEXT, size TEXT, created_at TEXT NOT NULL);
+             CREATE TABLE tokens (token TEXT PRIMARY KEY, username TEXT NOT NULL, ip TEXT NOT NULL, created_at TEXT NOT NULL);
+             CREATE TABLE auth_codes (code TEXT PRIMARY KEY, created_at TEXT NOT NULL, ip TEXT NOT NULL);
+             INSERT INTO uploads (hash, filename, size, created_at) VALUES ('a', 'a.jpg', '2048', '2024-05-01T10:00:00Z');
+             INSERT INTO tokens (token, username, ip, created_at) VALUES ('t', 'ana', '10.0.0.2', '2024-05-01T10:00:00Z');",
+        )
+        .unwrap();
+
+        migrate(&conn).unwrap();
+
+        let (filename, size): (String, Option<i64>) = conn
+            .query_row("SELECT filename, size FROM uploads WHERE hash = 'a'", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!((filename.as_str(), size), ("a.jpg", Some(2048)));
+        let (username, expires_at): (String, Option<String>) = conn
+            .query_row("SELECT username, expires_at FROM tokens WHERE token = 't'", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(username, "ana");
+        assert!(expires_at.is_some());
+        let sqlx_versions: u32 = conn.query_row("SELECT COUNT(*) FROM _sqlx_migrations", [], |row| row.get(0)).unwrap();
+        assert_eq!(sqlx_versions, 1);
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+    }
+
+    #[test]
+    fn newer_database_is_refused() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        let newer = latest_version() + 1;
+        conn.execute(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, 'future', '')",
+            [newer],
+        )
+        .unwrap();
+
+        match migrate(&conn) {
+            Err(Error::SchemaTooNew { found, supported }) => {
+                assert_eq!(found, newer);
+                assert_eq!(supported, latest_version());
+            }
+            other => panic!("expected SchemaTooNew, got {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,1251 @@
+//! # SQLite Repository
+//!
+//! `rusqlite` implementation of the `Repository` trait.
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rusqlite::{params, params_from_iter, types::Value, Connection, OptionalExtension, Row};
+use std::path::{Path, PathBuf};
+
+use crate::models::*;
+use crate::repository::Repository;
+use crate::{schema, Result};
+
+/// Format of `photo_metadata.taken_at`, a local time without offset.
+const TAKEN_AT_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+/// Columns selected for every `Upload`, from `uploads u`.
+const UPLOAD_COLUMNS: &str = "u.hash, u.filename, u.size, u.device_id, u.created_at, u.owner, u.path, u.modified_at, u.uploaded_at, u.thumb, u.blob";
+
+/// Columns selected for an `Upload` as seen by one of its owners, from `user_files f JOIN uploads u`.
+const OWNED_UPLOAD_COLUMNS: &str =
+    "u.hash, f.filename, u.size, u.device_id, f.created_at, f.username, f.path, f.modified_at, f.uploaded_at, u.thumb, u.blob";
+
+/// Whether the `user_files f` row gives its user access to the file: they stored a copy, or they only announced
+/// it and no other user has it. An announcement cannot claim a file that is in someone else's library.
+const VISIBLE_FILE: &str =
+    "(f.path IS NOT NULL OR NOT EXISTS (SELECT 1 FROM user_files o WHERE o.hash = f.hash AND o.username != f.username))";
+
+/// Columns selected for every `PhotoMetadata`, from `photo_metadata pm`.
+const METADATA_COLUMNS: &str = "pm.hash, pm.camera_make, pm.camera_model, pm.lens_model, pm.exposure_time, pm.f_number, pm.iso, \
+     pm.focal_length, pm.gps_latitude, pm.gps_longitude, pm.taken_at";
+
+/// Columns selected for every `UploadSession`.
+const SESSION_COLUMNS: &str = "id, username, filename, size, hash, modified_at, temp_path";
+
+/// Columns selected for every `TransferJob`.
+const JOB_COLUMNS: &str = "id, hash, device_id, state, attempts, last_error";
+
+/// Gives access to a SQLite connection.
+pub trait AsConnection {
+    fn as_connection(&self) -> &Connection;
+}
+
+impl AsConnection for Connection {
+    fn as_connection(&self) -> &Connection {
+        self
+    }
+}
+
+/// A `Repository` backed by a SQLite connection.
+///
+/// `C` is an owned `Connection` by default, or a connection borrowed from the pool
+/// (see `pool::PooledRepository`).
+pub struct SqliteRepository<C = Connection> {
+    conn: C,
+}
+
+impl SqliteRepository {
+    /// Opens the database at `path` and applies pending migrations.
+    ///
+    /// Fails with `Error::SchemaTooNew` if the database was migrated by a newer build.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        schema::migrate(&conn)?;
+        Ok(SqliteRepository { conn })
+    }
+
+    /// Opens a new in-memory database with every table.
+    pub fn open_in_memory() -> Result<Self> {
+        let conn = Connection::open_in_memory()?;
+        schema::migrate(&conn)?;
+        Ok(SqliteRepository { conn })
+    }
+}
+
+impl<C: AsConnection> SqliteRepository<C> {
+    /// Wraps a connection whose schema is managed by the caller.
+    pub fn new(conn: C) -> Self {
+        SqliteRepository { conn }
+    }
+
+    /// Gives access to the underlying connection.
+    pub fn connection(&self) -> &Connection {
+        self.conn.as_connection()
+    }
+
+    fn select_jobs(&self, filter: &str, values: impl rusqlite::Params) -> Result<Vec<TransferJob>> {
+        let sql = format!("SELECT {JOB_COLUMNS} FROM transfer_jobs {filter} ORDER BY created_at");
+        let mut stmt = self.connection().prepare(&sql)?;
+        let jobs = stmt.query_map(values, job_from_row)?.collect::<rusqlite::Result<_>>()?;
+        Ok(jobs)
+    }
+}
+
+impl<C: AsConnection> Repository for SqliteRepository<C> {
+    fn file_stored(&self, hash: &str) -> Result<bool> {
+        let stored = self
+            .connection()
+            .query_row("SELECT EXISTS(SELECT 1 FROM uploads WHERE hash = ?1 AND path IS NOT NULL)", [hash], |row| row.get(0))?;
+        Ok(stored)
+    }
+
+    fn upload_device(&self, hash: &str) -> Result<Option<String>> {
+        let device_id: Option<Option<String>> = self
+            .connection()
+            .query_row("SELECT device_id FROM uploads WHERE hash = ?1", [hash], |row| row.get(0))
+            .optional()?;
+        Ok(device_id.flatten())
+    }
+
+    fn announce_uploads(&self, uploads: &[NewUpload]) -> Result<()> {
+        let tx = self.connection().unchecked_transaction()?;
+        let now = Utc::now().to_rfc3339();
+
+        for upload in uploads {
+            if let Some(owner) = &upload.owner {
+                let claimed: bool = tx.query_row(
+                    "SELECT EXISTS(SELECT 1 FROM user_files WHERE hash = ?1 AND username != ?2)",
+                    params![upload.hash, owner],
+                    |row| row.get(0),
+                )?;
+                if claimed {
+                    continue;
+                }
+            }
+
+            // The device of another user's token keeps receiving the file's `copy_files` requests
+            tx.execute(
+                "INSERT INTO uploads (hash, filename, size, device_id, modified_at, thumb, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                 ON CONFLICT(hash) DO UPDATE SET
+                     filename = COALESCE(uploads.filename, excluded.filename),
+                     size = COALESCE(uploads.size, excluded.size),
+                     modified_at = COALESCE(uploads.modified_at, excluded.modified_at),
+                     device_id = CASE
+                         WHEN uploads.device_id IS NULL
+                             OR uploads.device_id NOT IN (SELECT device_id FROM tokens WHERE username IS NOT ?8 AND device_id IS NOT NULL)
+                         THEN excluded.device_id
+                         ELSE uploads.device_id
+                     END,
+                     thumb = COALESCE(excluded.thumb, uploads.thumb)",
+                params![
+                    upload.hash,
+                    upload.filename,
+                    upload.size,
+                    upload.device_id,
+                    upload.modified_at.map(|dt| dt.to_rfc3339()),
+                    upload.thumb,
+                    now,
+                    upload.owner,
+                ],
+            )?;
+
+            if let Some(owner) = &upload.owner {
+                tx.execute(
+                    "INSERT INTO user_files (username, hash, filename, modified_at, created_at) VALUES (?1, ?2, ?3, ?4, ?5)
+                     ON CONFLICT(username, hash) DO UPDATE SET
+                         filename = COALESCE(user_files.filename, excluded.filename),
+                         modified_at = COALESCE(user_files.modified_at, excluded.modified_at)",
+                    params![owner, upload.hash, upload.filename, upload.modified_at.map(|dt| dt.to_rfc3339()), now],
+                )?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn save_stored_file(&self, file: &StoredFile) -> Result<()> {
+        let tx = self.connection().unchecked_transaction()?;
+        let values = params![
+            file.hash,
+            file.filename,
+            file.size,
+            file.owner,
+            file.path.to_string_lossy(),
+            file.modified_at.map(|dt| dt.to_rfc3339()),
+            file.uploaded_at.to_rfc3339(),
+            file.blob.to_string_lossy(),
+        ];
+
+        // The first stored copy stays the file's path; later owners only get their own `user_files` row
+        tx.execute(
+            "INSERT INTO uploads (hash, filename, size, owner, path, modified_at, uploaded_at, created_at, blob)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7, ?8)
+             ON CONFLICT(hash) DO UPDATE SET
+                 blob = excluded.blob,
+                 filename = CASE WHEN uploads.path IS NULL THEN excluded.filename ELSE uploads.filename END,
+                 size = excluded.size,
+                 owner = CASE WHEN uploads.path IS NULL THEN excluded.owner ELSE uploads.owner END,
+                 path = COALESCE(uploads.path, excluded.path),
+                 modified_at = CASE WHEN uploads.path IS NULL THEN excluded.modified_at ELSE uploads.modified_at END,
+                 uploaded_at = CASE WHEN uploads.path IS NULL THEN excluded.uploaded_at ELSE uploads.uploaded_at END",
+            values,
+        )?;
+        tx.execute(
+            "INSERT INTO user_files (username, hash, filename, path, modified_at, uploaded_at, created_at)
+             VALUES (?4, ?1, ?2, ?5, ?6, ?7, ?7)
+             ON CONFLICT(username, hash) DO UPDATE SET
+                 filename = excluded.filename,
+                 path = excluded.path,
+                 modified_at = excluded.modified_at,
+                 uploaded_at = excluded.uploaded_at",
+            &values[..7],
+        )?;
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn file_claimed_by_other(&self, hash: &str, username: &str) -> Result<bool> {
+        let claimed = self.connection().query_row(
+            "SELECT EXISTS(SELECT 1 FROM user_files WHERE hash = ?1 AND username != ?2)",
+            params![hash, username],
+            |row| row.get(0),
+        )?;
+        Ok(claimed)
+    }
+
+    fn file_visible_to(&self, hash: &str, username: &str) -> Result<bool> {
+        let visible = self.connection().query_row(
+            &format!("SELECT EXISTS(SELECT 1 FROM user_files f WHERE f.hash = ?1 AND f.username = ?2 AND {VISIBLE_FILE})"),
+            params![hash, username],
+            |row| row.get(0),
+        )?;
+        Ok(visible)
+    }
+
+    fn find_upload(&self, hash: &str) -> Result<Option<Upload>> {
+        let upload = self
+            .connection()
+            .query_row(&format!("SELECT {UPLOAD_COLUMNS} FROM uploads u WHERE u.hash = ?1"), [hash], upload_from_row)
+            .optional()?;
+        Ok(upload)
+    }
+
+    fn find_owned_upload(&self, username: &str, hash: &str) -> Result<Option<Upload>> {
+        let upload = self
+            .connection()
+            .query_row(
+                &format!("SELECT {OWNED_UPLOAD_COLUMNS} FROM user_files f JOIN uploads u ON u.hash = f.hash WHERE f.username = ?1 AND f.hash = ?2"),
+                params![username, hash],
+                upload_from_row,
+            )
+            .optional()?;
+        Ok(upload)
+    }
+
+    fn remove_owned_file(&self, username: &str, hash: &str) -> Result<Option<RemovedFile>> {
+        let tx = self.connection().unchecked_transaction()?;
+
+        let path: Option<Option<String>> = tx
+            .query_row("SELECT path FROM user_files WHERE username = ?1 AND hash = ?2", params![username, hash], |row| row.get(0))
+            .optional()?;
+        let Some(path) = path else { return Ok(None) };
+
+        tx.execute("DELETE FROM user_files WHERE username = ?1 AND hash = ?2", params![username, hash])?;
+        let owners: i64 = tx.query_row("SELECT COUNT(*) FROM user_files WHERE hash = ?1", [hash], |row| row.get(0))?;
+
+        let mut blob = None;
+        if owners == 0 {
+            blob = tx.query_row("SELECT blob FROM uploads WHERE hash = ?1", [hash], |row| row.get::<_, Option<String>>(0))?;
+            tx.execute("DELETE FROM uploads WHERE hash = ?1", [hash])?;
+            tx.execute("DELETE FROM photo_metadata WHERE hash = ?1", [hash])?;
+        } else {
+            // The removed copy was the file's path: use another owner's copy, or none if nobody else uploaded it
+            tx.execute(
+                "UPDATE uploads SET (owner, path) = (
+                     SELECT username, path FROM user_files WHERE hash = ?1 AND path IS NOT NULL ORDER BY uploaded_at LIMIT 1
+                 )
+                 WHERE hash = ?1 AND owner = ?2",
+                params![hash, username],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(Some(RemovedFile { path: path.map(PathBuf::from), orphaned: owners == 0, blob: blob.map(PathBuf::from) }))
+    }
+
+    fn list_uploads(&self) -> Result<Vec<Upload>> {
+        let mut stmt = self.connection().prepare(&format!("SELECT {UPLOAD_COLUMNS} FROM uploads u"))?;
+        let uploads = stmt.query_map([], upload_from_row)?.collect::<rusqlite::Result<_>>()?;
+        Ok(uploads)
+    }
+
+    fn list_stored_copies(&self) -> Result<Vec<Upload>> {
+        let mut stmt = self.connection().prepare(&format!(
+            "SELECT {OWNED_UPLOAD_COLUMNS} FROM user_files f JOIN uploads u ON u.hash = f.hash WHERE f.path IS NOT NULL ORDER BY f.hash, f.username"
+        ))?;
+        let copies = stmt.query_map([], upload_from_row)?.collect::<rusqlite::Result<_>>()?;
+        Ok(copies)
+    }
+
+    fn query_uploads(&self, query: &UploadQuery) -> Result<UploadPage> {
+        let key = sort_key(query.sort);
+        let mut filters = vec!["u.thumb IS NOT NULL".to_string()];
+        let mut values: Vec<Value> = Vec::new();
+
+        // Without a user, each file is listed once, with the name, path and times of its first stored copy
+        let files = match &query.username {
+            Some(username) => {
+                filters.extend(["f.username = ?".to_string(), VISIBLE_FILE.to_string()]);
+                values.push(Value::Text(username.clone()));
+                "user_files"
+            }
+            None => "(SELECT hash, filename, path, modified_at, uploaded_at, created_at, owner AS username FROM uploads)",
+        };
+
+        if let Some(from) = query.captured_from {
+            filters.push(format!("{} >= ?", sort_key(UploadSort::CaptureDate)));
+            values.push(Value::Text(from.format(TAKEN_AT_FORMAT).to_string()));
+        }
+        if let Some(to) = query.captured_to {
+            filters.push(format!("{} <= ?", sort_key(UploadSort::CaptureDate)));
+            values.push(Value::Text(to.format(TAKEN_AT_FORMAT).to_string()));
+        }
+        match query.stored {
+            Some(true) => filters.push("f.path IS NOT NULL".to_string()),
+            Some(false) => filters.push("f.path IS NULL".to_string()),
+            None => {}
+        }
+        if let Some(camera_model) = &query.camera_model {
+            filters.push("pm.camera_model = ? COLLATE NOCASE".to_string());
+            values.push(Value::Text(camera_model.clone()));
+        }
+        if let Some(extension) = &query.extension {
+            filters.push("lower(f.filename) LIKE '%.' || lower(?)".to_string());
+            values.push(Value::Text(extension.clone()));
+        }
+
+        let from = format!("FROM {files} f JOIN uploads u ON u.hash = f.hash LEFT JOIN photo_metadata pm ON pm.hash = u.hash");
+        let total: i64 = self.connection().query_row(
+            &format!("SELECT COUNT(*) {from} WHERE {}", filters.join(" AND ")),
+            params_from_iter(&values),
+            |row| row.get(0),
+        )?;
+
+        let (order, compare) = if query.descending { ("DESC", "<") } else { ("ASC", ">") };
+        if let Some(cursor) = &query.after {
+            filters.push(format!("({key}, u.hash) {compare} (?, ?)"));
+            values.push(match &cursor.key {
+                SortKey::Integer(value) => Value::Integer(*value),
+                SortKey::Text(value) => Value::Text(value.clone()),
+            });
+            values.push(Value::Text(cursor.hash.clone()));
+        }
+        // One extra row tells whether there is a next page
+        values.push(Value::Integer(i64::from(query.limit) + 1));
+
+        let sql = format!(
+            "SELECT {OWNED_UPLOAD_COLUMNS}, {METADATA_COLUMNS}, {key} {from} WHERE {} ORDER BY {key} {order}, u.hash {order} LIMIT ?",
+            filters.join(" AND "),
+        );
+        let mut stmt = self.connection().prepare(&sql)?;
+        let mut rows = stmt
+            .query_map(params_from_iter(&values), |row| {
+                let upload = upload_from_row(row)?;
+                let metadata = match row.get::<_, Option<String>>(11)? {
+                    Some(_) => Some(metadata_from_row(row, 11)?),
+                    None => None,
+                };
+                let key = match row.get(22)? {
+                    Value::Integer(value) => SortKey::Integer(value),
+                    Value::Text(value) => SortKey::Text(value),
+                    _ => SortKey::Text(String::new()),
+                };
+                Ok((ListedUpload { upload, metadata }, key))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let next_cursor = if rows.len() > query.limit as usize {
+            rows.truncate(query.limit as usize);
+            rows.last().map(|(item, key)| UploadCursor { key: key.clone(), hash: item.upload.hash.clone() })
+        } else {
+            None
+        };
+
+        Ok(UploadPage {
+            items: rows.into_iter().map(|(item, _)| item).collect(),
+            total: total as u64,
+            next_cursor,
+        })
+    }
+
+    fn set_blob(&self, hash: &str, blob: &Path) -> Result<()> {
+        self.connection().execute("UPDATE uploads SET blob = ?2 WHERE hash = ?1", params![hash, blob.to_string_lossy()])?;
+        Ok(())
+    }
+
+    fn set_thumb(&self, hash: &str, thumb: &str) -> Result<()> {
+        self.connection().execute("UPDATE uploads SET thumb = ?2 WHERE hash = ?1", params![hash, thumb])?;
+        Ok(())
+    }
+
+    fn uploads_without_thumb(&self) -> Result<Vec<String>> {
+        let mut stmt = self.connection().prepare("SELECT hash FROM uploads WHERE thumb IS NULL")?;
+        let hashes = stmt.query_map([], |row| row.get(0))?.collect::<rusqlite::Result<_>>()?;
+        Ok(hashes)
+    }
+
+    fn save_photo_metadata(&self, metadata: &PhotoMetadata) -> Result<()> {
+        self.connection().execute(
+            "INSERT OR REPLACE INTO photo_metadata
+                 (hash, camera_make, camera_model, lens_model, exposure_time, f_number, iso, focal_length, gps_latitude, gps_longitude, taken_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                metadata.hash,
+                metadata.camera_make,
+                metadata.camera_model,
+                metadata.lens_model,
+                metadata.exposure_time,
+                metadata.f_number,
+                metadata.iso,
+                metadata.focal_length,
+                metadata.gps_latitude,
+                metadata.gps_longitude,
+                metadata.taken_at.map(|t| t.format(TAKEN_AT_FORMAT).to_string()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn list_photo_metadata(&self) -> Result<Vec<PhotoMetadata>> {
+        let mut stmt = self.connection().prepare(&format!("SELECT {METADATA_COLUMNS} FROM photo_metadata pm"))?;
+        let metadata = stmt.query_map([], |row| metadata_from_row(row, 0))?.collect::<rusqlite::Result<_>>()?;
+        Ok(metadata)
+    }
+
+    fn save_auth_code(&self, code: &str, ip: &str, created_at: DateTime<Utc>) -> Result<()> {
+        self.connection().execute(
+            "INSERT OR REPLACE INTO auth_codes (code, created_at, ip) VALUES (?1, ?2, ?3)",
+            params![code, created_at.to_rfc3339(), ip],
+        )?;
+        Ok(())
+    }
+
+    fn take_auth_code(&self, code: &str) -> Result<Option<AuthCode>> {
+        // A single statement, so two requests with the same code cannot both read it before it is deleted
+        let taken = self
+            .connection()
+            .query_row("DELETE FROM auth_codes WHERE code = ?1 RETURNING code, ip, created_at", [code], |row| {
+                let created_at: Option<String> = row.get(2)?;
+                Ok(AuthCode {
+                    code: row.get(0)?,
+                    ip: row.get(1)?,
+                    created_at: parse_date(created_at),
+                })
+            })
+            .optional()?;
+        Ok(taken)
+    }
+
+    fn delete_auth_codes_before(&self, cutoff: DateTime<Utc>) -> Result<usize> {
+        let deleted = self.connection().execute("DELETE FROM auth_codes WHERE created_at < ?1", [cutoff.to_rfc3339()])?;
+        Ok(deleted)
+    }
+
+    fn insert_token(&self, token: &NewToken) -> Result<()> {
+        self.connection().execute(
+            "INSERT INTO tokens (token, username, ip, created_at, device_id, device_name, expires_at, last_seen, binding)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?4, ?8)",
+            params![
+                token.token,
+                token.username,
+                token.ip,
+                token.created_at.to_rfc3339(),
+                token.device_id,
+                token.device_name,
+                token.expires_at.to_rfc3339(),
+                token.binding,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn find_token(&self, token: &str) -> Result<Option<TokenRecord>> {
+        self.connection()
+            .query_row(
+                "SELECT token, username, ip, device_id, binding, expires_at FROM tokens WHERE token = ?1",
+                [token],
+                |row| {
+                    let expires_at: Option<String> = row.get(5)?;
+                    Ok(TokenRecord {
+                        token: row.get(0)?,
+                        username: row.get(1)?,
+                        ip: row.get(2)?,
+                        device_id: row.get(3)?,
+                        binding: row.get(4)?,
+                        expires_at: parse_date(expires_at),
+                    })
+                },
+            )
+            .optional()
+            .map_err(Into::into)
+    }
+
+    fn touch_token(&self, token: &str, at: DateTime<Utc>) -> Result<()> {
+        self.connection().execute("UPDATE tokens SET last_seen = ?1 WHERE token = ?2", params![at.to_rfc3339(), token])?;
+        Ok(())
+    }
+
+    fn replace_token(&self, old: &str, new: &str, expires_at: DateTime<Utc>, at: DateTime<Utc>) -> Result<bool> {
+        let updated = self.connection().execute(
+            "UPDATE tokens SET token = ?1, expires_at = ?2, last_seen = ?3 WHERE token = ?4",
+            params![new, expires_at.to_rfc3339(), at.to_rfc3339(), old],
+        )?;
+        Ok(updated > 0)
+    }
+
+    fn list_devices(&self) -> Result<Vec<Device>> {
+        let mut stmt = self.connection().prepare(
+            "SELECT device_id, username, device_name, ip, created_at, last_seen, expires_at, binding
+             FROM tokens ORDER BY last_seen DESC",
+        )?;
+
+        let devices = stmt
+            .query_map([], |row| {
+                Ok(Device {
+                    id: row.get(0)?,
+                    username: row.get(1)?,
+                    device_name: row.get(2)?,
+                    ip: row.get(3)?,
+                    created_at: row.get(4)?,
+                    last_seen: row.get(5)?,
+                    expires_at: row.get(6)?,
+                    binding: row.get(7)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(devices)
+    }
+
+    fn revoke_device(&self, device_id: &str) -> Result<Option<String>> {
+        let mut stmt = self.connection().prepare("DELETE FROM tokens WHERE device_id = ?1 RETURNING token")?;
+        let tokens = stmt.query_map([device_id], |row| row.get(0))?.collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(tokens.into_iter().next())
+    }
+
+    fn find_session_id(&self, username: &str, hash: &str) -> Result<Option<String>> {
+        self.connection()
+            .query_row(
+                "SELECT id FROM upload_sessions WHERE username = ?1 AND hash = ?2",
+                [username, hash],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(Into::into)
+    }
+
+    fn insert_session(&self, session: &UploadSession) -> Result<()> {
+        self.connection().execute(
+            "INSERT INTO upload_sessions (id, username, filename, size, hash, modified_at, temp_path, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                session.id,
+                session.username,
+                session.filename,
+                session.size as i64,
+                session.hash,
+                session.modified_at.map(|dt| dt.to_rfc3339()),
+                session.temp_path.to_string_lossy(),
+                Utc::now().to_rfc3339(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn find_session(&self, id: &str, username: &str) -> Result<Option<UploadSession>> {
+        self.connection()
+            .query_row(
+                &format!("SELECT {SESSION_COLUMNS} FROM upload_sessions WHERE id = ?1 AND username = ?2"),
+                [id, username],
+                session_from_row,
+            )
+            .optional()
+            .map_err(Into::into)
+    }
+
+    fn take_session(&self, id: &str, username: &str) -> Result<Option<UploadSession>> {
+        // A single statement, so two requests for the same session cannot both take it
+        let taken = self
+            .connection()
+            .query_row(
+                &format!("DELETE FROM upload_sessions WHERE id = ?1 AND username = ?2 RETURNING {SESSION_COLUMNS}"),
+                [id, username],
+                session_from_row,
+            )
+            .optional()?;
+        if taken.is_some() {
+            self.connection().execute("DELETE FROM upload_chunks WHERE session_id = ?1", [id])?;
+        }
+        Ok(taken)
+    }
+
+    fn record_chunk(&self, session_id: &str, offset: u64, length: u64) -> Result<()> {
+        self.connection().execute(
+            "INSERT OR REPLACE INTO upload_chunks (session_id, start_offset, length) VALUES (?1, ?2, ?3)",
+            params![session_id, offset as i64, length as i64],
+        )?;
+        Ok(())
+    }
+
+    fn session_chunks(&self, session_id: &str) -> Result<Vec<(u64, u64)>> {
+        let mut stmt = self
+            .connection()
+            .prepare("SELECT start_offset, length FROM upload_chunks WHERE session_id = ?1 ORDER BY start_offset")?;
+        let chunks = stmt
+            .query_map([session_id], |row| {
+                let start: i64 = row.get(0)?;
+                let length: i64 = row.get(1)?;
+                Ok((start as u64, (start + length) as u64))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(chunks)
+    }
+
+    fn clear_chunks(&self, session_id: &str) -> Result<()> {
+        self.connection().execute("DELETE FROM upload_chunks WHERE session_id = ?1", [session_id])?;
+        Ok(())
+    }
+
+    fn delete_session(&self, id: &str) -> Result<()> {
+        self.connection().execute("DELETE FROM upload_chunks WHERE session_id = ?1", [id])?;
+        self.connection().execute("DELETE FROM upload_sessions WHERE id = ?1", [id])?;
+        Ok(())
+    }
+
+    fn delete_sessions_before(&self, cutoff: DateTime<Utc>) -> Result<Vec<PathBuf>> {
+        let mut stmt = self.connection().prepare("DELETE FROM upload_sessions WHERE created_at < ?1 RETURNING temp_path")?;
+        let paths = stmt
+            .query_map([cutoff.to_rfc3339()], |row| row.get::<_, String>(0))?
+            .map(|path| path.map(PathBuf::from))
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        self.connection().execute("DELETE FROM upload_chunks WHERE session_id NOT IN (SELECT id FROM upload_sessions)", [])?;
+        Ok(paths)
+    }
+
+    fn insert_job(&self, job: &TransferJob) -> Result<()> {
+        let now = Utc::now().to_rfc3339();
+        self.connection().execute(
+            "INSERT INTO transfer_jobs (id, hash, device_id, state, attempts, last_error, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7)",
+            params![job.id, job.hash, job.device_id, job.state.as_str(), job.attempts, job.last_error, now],
+        )?;
+        Ok(())
+    }
+
+    fn save_job(&self, job: &TransferJob, next_attempt_at: Option<DateTime<Utc>>) -> Result<()> {
+        self.connection().execute(
+            "UPDATE transfer_jobs SET state = ?1, attempts = ?2, next_attempt_at = ?3, last_error = ?4, updated_at = ?5
+             WHERE id = ?6",
+            params![
+                job.state.as_str(),
+                job.attempts,
+                next_attempt_at.map(|dt| dt.to_rfc3339()),
+                job.last_error,
+                Utc::now().to_rfc3339(),
+                job.id,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn active_jobs_for_hash(&self, hash: &str, username: &str) -> Result<Vec<TransferJob>> {
+        self.select_jobs(
+            "WHERE hash = ?1 AND state NOT IN ('done', 'failed')
+               AND device_id IN (SELECT device_id FROM tokens WHERE username = ?2)",
+            [hash, username],
+        )
+    }
+
+    fn active_jobs_for_device(&self, device_id: &str) -> Result<Vec<TransferJob>> {
+        self.select_jobs("WHERE device_id = ?1 AND state NOT IN ('done', 'failed')", [device_id])
+    }
+
+    fn due_jobs(&self, now: DateTime<Utc>) -> Result<Vec<TransferJob>> {
+        self.select_jobs(
+            "WHERE state IN ('dispatched', 'uploading') AND next_attempt_at <= ?1",
+            [now.to_rfc3339()],
+        )
+    }
+}
+
+/// Reads an `Upload` selected with `UPLOAD_COLUMNS`.
+fn upload_from_row(row: &Row) -> rusqlite::Result<Upload> {
+    Ok(Upload {
+        hash: row.get(0)?,
+        filename: row.get(1)?,
+        size: row.get(2)?,
+        device_id: row.get(3)?,
+        created_at: row.get(4)?,
+        owner: row.get(5)?,
+        path: row.get(6)?,
+        modified_at: row.get(7)?,
+        uploaded_at: row.get(8)?,
+        thumb: row.get(9)?,
+        blob: row.get(10)?,
+    })
+}
+
+/// Reads a `PhotoMetadata` selected with `METADATA_COLUMNS`, starting at column `start`.
+fn metadata_from_row(row: &Row, start: usize) -> rusqlite::Result<PhotoMetadata> {
+    let taken_at: Option<String> = row.get(start + 10)?;
+    Ok(PhotoMetadata {
+        hash: row.get(start)?,
+        camera_make: row.get(start + 1)?,
+        camera_model: row.get(start + 2)?,
+        lens_model: row.get(start + 3)?,
+        exposure_time: row.get(start + 4)?,
+        f_number: row.get(start + 5)?,
+        iso: row.get(start + 6)?,
+        focal_length: row.get(start + 7)?,
+        gps_latitude: row.get(start + 8)?,
+        gps_longitude: row.get(start + 9)?,
+        taken_at: taken_at.and_then(|s| NaiveDateTime::parse_from_str(&s, TAKEN_AT_FORMAT).ok()),
+    })
+}
+
+/// SQL expression `query_uploads` sorts by, over the user's `user_files f` row. Never `NULL`, so it can be
+/// compared with a cursor.
+///
+/// Dates are cut to `TAKEN_AT_FORMAT`, so EXIF local times and RFC 3339 timestamps compare as text.
+fn sort_key(sort: UploadSort) -> &'static str {
+    match sort {
+        UploadSort::CaptureDate => "replace(substr(COALESCE(pm.taken_at, f.modified_at, f.created_at, ''), 1, 19), ' ', 'T')",
+        UploadSort::UploadDate => "replace(substr(COALESCE(f.uploaded_at, f.created_at, ''), 1, 19), ' ', 'T')",
+        UploadSort::Name => "lower(COALESCE(f.filename, ''))",
+        UploadSort::Size => "COALESCE(u.size, -1)",
+    }
+}
+
+/// Reads an `UploadSession` selected with `SESSION_COLUMNS`.
+fn session_from_row(row: &Row) -> rusqlite::Result<UploadSession> {
+    let modified_at: Option<String> = row.get(5)?;
+    let temp_path: String = row.get(6)?;
+    Ok(UploadSession {
+        id: row.get(0)?,
+        username: row.get(1)?,
+        filename: row.get(2)?,
+        size: row.get::<_, i64>(3)? as u64,
+        hash: row.get(4)?,
+        modified_at: parse_date(modified_at),
+        temp_path: PathBuf::from(temp_path),
+    })
+}
+
+/// Reads a `TransferJob` selected with `JOB_COLUMNS`.
+fn job_from_row(row: &Row) -> rusqlite::Result<TransferJob> {
+    let state: String = row.get(3)?;
+    Ok(TransferJob {
+        id: row.get(0)?,
+        hash: row.get(1)?,
+        device_id: row.get(2)?,
+        state: TransferState::parse(&state),
+        attempts: row.get(4)?,
+        last_error: row.get(5)?,
+    })
+}
+
+/// Parses an RFC 3339 column; missing or invalid values give `None`.
+fn parse_date(value: Option<String>) -> Option<DateTime<Utc>> {
+    value
+        .and_then(|s| DateTime::parse_from_rfc3339(&s).ok())
+        .map(|dt| dt.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn repo() -> SqliteRepository {
+        SqliteRepository::open_in_memory().unwrap()
+    }
+
+    fn new_upload(hash: &str, device_id: Option<&str>) -> NewUpload {
+        NewUpload {
+            hash: hash.to_string(),
+            filename: format!("{hash}.jpg"),
+            size: Some(42),
+            device_id: device_id.map(str::to_string),
+            owner: Some("ana".to_string()),
+            modified_at: None,
+            thumb: Some(format!("{hash}.jpg")),
+        }
+    }
+
+    fn stored_file(hash: &str) -> StoredFile {
+        StoredFile {
+            hash: hash.to_string(),
+            filename: "IMG_0001.CR3".to_string(),
+            size: 1024,
+            owner: "ana".to_string(),
+            path: PathBuf::from(format!("uploads/ana/2024/{hash}.CR3")),
+            modified_at: None,
+            uploaded_at: Utc::now(),
+            blob: PathBuf::from(format!("uploads/blobs/{hash}")),
+        }
+    }
+
+    fn new_token(token: &str, device_id: &str) -> NewToken {
+        let now = Utc::now();
+        NewToken {
+            token: token.to_string(),
+            username: "ana".to_string(),
+            ip: "192.168.0.10".to_string(),
+            device_id: device_id.to_string(),
+            device_name: "ana-phone".to_string(),
+            binding: "none".to_string(),
+            created_at: now,
+            expires_at: now + Duration::days(30),
+        }
+    }
+
+    fn new_job(id: &str, hash: &str, device_id: &str) -> TransferJob {
+        TransferJob {
+            id: id.to_string(),
+            hash: hash.to_string(),
+            device_id: device_id.to_string(),
+            state: TransferState::Pending,
+            attempts: 0,
+            last_error: None,
+        }
+    }
+
+    #[test]
+    fn open_is_idempotent() {
+        let repo = repo();
+        schema::migrate(repo.connection()).unwrap();
+        assert!(repo.list_uploads().unwrap().is_empty());
+    }
+
+    #[test]
+    fn announced_uploads_update_device() {
+        let repo = repo();
+        repo.announce_uploads(&[new_upload("a", Some("d1")), new_upload("b", None)]).unwrap();
+        repo.announce_uploads(&[new_upload("a", Some("d2"))]).unwrap();
+
+        assert!(!repo.file_stored("a").unwrap());
+        assert_eq!(repo.upload_device("a").unwrap().as_deref(), Some("d2"));
+        assert_eq!(repo.upload_device("b").unwrap(), None);
+        assert_eq!(repo.upload_device("c").unwrap(), None);
+        assert_eq!(repo.list_uploads().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn stored_file_merges_with_announcement() {
+        let repo = repo();
+        repo.announce_uploads(&[new_upload("a", Some("d1"))]).unwrap();
+        repo.save_stored_file(&stored_file("a")).unwrap();
+        repo.announce_uploads(&[new_upload("a", Some("d2"))]).unwrap();
+
+        assert!(repo.file_stored("a").unwrap());
+        assert_eq!(repo.find_upload("a").unwrap().and_then(|u| u.owner).as_deref(), Some("ana"));
+        assert_eq!(repo.find_upload("b").unwrap(), None);
+        let uploads = repo.list_uploads().unwrap();
+        assert_eq!(uploads.len(), 1);
+
+        let upload = &uploads[0];
+        assert_eq!(upload.filename.as_deref(), Some("IMG_0001.CR3"));
+        assert_eq!(upload.size, Some(1024));
+        assert_eq!(upload.device_id.as_deref(), Some("d2"));
+        assert_eq!(upload.owner.as_deref(), Some("ana"));
+        assert_eq!(upload.path.as_deref(), Some("uploads/ana/2024/a.CR3"));
+        assert!(upload.uploaded_at.is_some());
+        assert!(upload.created_at.is_some());
+    }
+
+    #[test]
+    fn files_are_visible_to_their_owners() {
+        let repo = repo();
+        repo.announce_uploads(&[new_upload("a", Some("d1")), NewUpload { owner: None, ..new_upload("b", None) }]).unwrap();
+        repo.save_stored_file(&StoredFile { owner: "bob".to_string(), ..stored_file("c") }).unwrap();
+
+        assert!(repo.file_visible_to("a", "ana").unwrap());
+        assert!(!repo.file_visible_to("b", "ana").unwrap());
+        assert!(repo.file_visible_to("c", "bob").unwrap());
+        assert!(!repo.file_visible_to("c", "ana").unwrap());
+        assert!(!repo.file_visible_to("d", "ana").unwrap());
+    }
+
+    #[test]
+    fn announcements_do_not_claim_files_of_other_users() {
+        let repo = repo();
+        repo.insert_token(&new_token("t1", "d1")).unwrap();
+        repo.announce_uploads(&[new_upload("a", Some("d1"))]).unwrap();
+        repo.save_stored_file(&StoredFile { owner: "bob".to_string(), ..stored_file("b") }).unwrap();
+
+        // Bob cannot add Ana's announced file to his library, replace its thumbnail or take its device
+        let bob = |hash: &str| NewUpload {
+            owner: Some("bob".to_string()),
+            thumb: Some("bob.jpg".to_string()),
+            ..new_upload(hash, Some("d2"))
+        };
+        repo.announce_uploads(&[bob("a"), bob("b")]).unwrap();
+        assert!(repo.file_claimed_by_other("a", "bob").unwrap());
+        assert!(!repo.file_claimed_by_other("a", "ana").unwrap());
+        assert!(!repo.file_visible_to("a", "bob").unwrap());
+        let upload = repo.find_upload("a").unwrap().unwrap();
+        assert_eq!((upload.device_id.as_deref(), upload.thumb.as_deref()), (Some("d1"), Some("a.jpg")));
+
+        // Ana announcing a file Bob stored does not give her access to it
+        repo.announce_uploads(&[new_upload("c", None)]).unwrap();
+        repo.save_stored_file(&StoredFile { owner: "bob".to_string(), ..stored_file("c") }).unwrap();
+        assert!(!repo.file_visible_to("c", "ana").unwrap());
+        assert!(repo.file_visible_to("c", "bob").unwrap());
+        let listed = repo.query_uploads(&UploadQuery { username: Some("ana".to_string()), limit: 10, ..Default::default() }).unwrap();
+        assert_eq!(listed.items.iter().map(|item| item.upload.hash.as_str()).collect::<Vec<_>>(), ["a"]);
+
+        // The desktop viewer lists every file once
+        let listed = repo.query_uploads(&UploadQuery { limit: 10, ..Default::default() }).unwrap();
+        assert_eq!(listed.items.iter().map(|item| item.upload.hash.as_str()).collect::<Vec<_>>(), ["a", "b", "c"]);
+        assert_eq!(listed.items[2].upload.owner.as_deref(), Some("bob"));
+    }
+
+    #[test]
+    fn owners_share_one_stored_file() {
+        let repo = repo();
+        repo.save_stored_file(&stored_file("a")).unwrap();
+        let bob = StoredFile {
+            filename: "copy.CR3".to_string(),
+            owner: "bob".to_string(),
+            path: PathBuf::from("uploads/bob/2024/copy.CR3"),
+            ..stored_file("a")
+        };
+        repo.save_stored_file(&bob).unwrap();
+
+        let upload = repo.find_upload("a").unwrap().unwrap();
+        assert_eq!((upload.owner.as_deref(), upload.path.as_deref()), (Some("ana"), Some("uploads/ana/2024/a.CR3")));
+        let owned = repo.find_owned_upload("bob", "a").unwrap().unwrap();
+        assert_eq!((owned.filename.as_deref(), owned.path.as_deref()), (Some("copy.CR3"), Some("uploads/bob/2024/copy.CR3")));
+        let copies: Vec<_> = repo.list_stored_copies().unwrap().into_iter().map(|u| u.owner.unwrap()).collect();
+        assert_eq!(copies, ["ana", "bob"]);
+
+        // Removing the first copy makes the other one the stored path
+        let removed = repo.remove_owned_file("ana", "a").unwrap().unwrap();
+        assert_eq!(removed, RemovedFile { path: Some(PathBuf::from("uploads/ana/2024/a.CR3")), orphaned: false, blob: None });
+        assert_eq!(repo.find_upload("a").unwrap().and_then(|u| u.path).as_deref(), Some("uploads/bob/2024/copy.CR3"));
+        assert_eq!(repo.remove_owned_file("ana", "a").unwrap(), None);
+
+        let removed = repo.remove_owned_file("bob", "a").unwrap().unwrap();
+        assert!(removed.orphaned);
+        assert_eq!(removed.blob, Some(PathBuf::from("uploads/blobs/a")));
+        assert_eq!(repo.find_upload("a").unwrap(), None);
+        assert!(!repo.file_stored("a").unwrap());
+    }
+
+    #[test]
+    fn photo_metadata_round_trips() {
+        let repo = repo();
+        let metadata = PhotoMetadata {
+            hash: "a".to_string(),
+            camera_model: Some("EOS R6".to_string()),
+            exposure_time: Some("1/250".to_string()),
+            f_number: Some(2.8),
+            iso: Some(400),
+            gps_latitude: Some(-23.55),
+            gps_longitude: Some(-46.63),
+            taken_at: NaiveDateTime::parse_from_str("2023-05-01T10:00:00", TAKEN_AT_FORMAT).ok(),
+            ..Default::default()
+        };
+        repo.save_photo_metadata(&metadata).unwrap();
+        repo.save_photo_metadata(&metadata).unwrap();
+
+        assert_eq!(repo.list_photo_metadata().unwrap(), vec![metadata]);
+    }
+
+    #[test]
+    fn uploads_are_paged_in_order() {
+        let repo = repo();
+        let uploads: Vec<NewUpload> = (0..5)
+            .map(|i| NewUpload { size: Some(i % 3), ..new_upload(&format!("h{i}"), None) })
+            .collect();
+        repo.announce_uploads(&uploads).unwrap();
+        repo.announce_uploads(&[NewUpload { thumb: None, ..new_upload("no-thumb", None) }]).unwrap();
+
+        let mut query = UploadQuery {
+            username: Some("ana".to_string()),
+            sort: UploadSort::Size,
+            descending: true,
+            limit: 2,
+            ..Default::default()
+        };
+        let mut hashes = Vec::new();
+        loop {
+            let page = repo.query_uploads(&query).unwrap();
+            assert_eq!(page.total, 5);
+            hashes.extend(page.items.into_iter().map(|item| item.upload.hash));
+            match page.next_cursor {
+                Some(cursor) => query.after = Some(cursor),
+                None => break,
+            }
+        }
+
+        // Sizes 2, 1, 1, 0, 0; equal sizes by hash
+        assert_eq!(hashes, ["h2", "h4", "h1", "h3", "h0"]);
+    }
+
+    #[test]
+    fn uploads_are_filtered() {
+        let repo = repo();
+        repo.announce_uploads(&[new_upload("a", None), new_upload("b", None), new_upload("c", None)]).unwrap();
+        repo.save_stored_file(&stored_file("a")).unwrap();
+        repo.save_photo_metadata(&PhotoMetadata {
+            hash: "a".to_string(),
+            camera_model: Some("EOS R6".to_string()),
+            taken_at: NaiveDateTime::parse_from_str("2023-05-01T10:00:00", TAKEN_AT_FORMAT).ok(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let hashes = |query: UploadQuery| -> Vec<String> {
+            let page = repo.query_uploads(&UploadQuery { username: Some("ana".to_string()), limit: 10, ..query }).unwrap();
+            assert_eq!(page.total as usize, page.items.len());
+            page.items.into_iter().map(|item| item.upload.hash).collect()
+        };
+
+        assert_eq!(hashes(UploadQuery { stored: Some(true), ..Default::default() }), ["a"]);
+        assert_eq!(hashes(UploadQuery { stored: Some(false), ..Default::default() }), ["b", "c"]);
+        assert_eq!(hashes(UploadQuery { camera_model: Some("eos r6".to_string()), ..Default::default() }), ["a"]);
+        assert_eq!(hashes(UploadQuery { extension: Some("cr3".to_string()), ..Default::default() }), ["a"]);
+        assert_eq!(hashes(UploadQuery { extension: Some("JPG".to_string()), ..Default::default() }), ["b", "c"]);
+
+        let may = |day: u32| chrono::NaiveDate::from_ymd_opt(2023, 5, day).unwrap().and_hms_opt(0, 0, 0);
+        assert_eq!(hashes(UploadQuery { captured_from: may(1), captured_to: may(2), ..Default::default() }), ["a"]);
+
+        let query = UploadQuery { username: Some("ana".to_string()), stored: Some(true), limit: 10, ..Default::default() };
+        let page = repo.query_uploads(&query).unwrap();
+        assert_eq!(page.items[0].metadata.as_ref().and_then(|m| m.camera_model.as_deref()), Some("EOS R6"));
+        assert_eq!(page.items[0].upload.thumb.as_deref(), Some("a.jpg"));
+    }
+
+    #[test]
+    fn thumbs_are_recorded() {
+        let repo = repo();
+        repo.announce_uploads(&[new_upload("a", None)]).unwrap();
+        repo.save_stored_file(&stored_file("b")).unwrap();
+        assert_eq!(repo.uploads_without_thumb().unwrap(), ["b"]);
+
+        repo.set_thumb("b", "b.jpg").unwrap();
+        repo.announce_uploads(&[NewUpload { thumb: None, ..new_upload("a", None) }]).unwrap();
+        assert!(repo.uploads_without_thumb().unwrap().is_empty());
+    }
+
+    #[test]
+    fn auth_codes_are_single_use() {
+        let repo = repo();
+        let now = Utc::now();
+        repo.save_auth_code("ABC123", "192.168.0.10", now).unwrap();
+
+        let code = repo.take_auth_code("ABC123").unwrap().unwrap();
+        assert_eq!(code.ip.as_deref(), Some("192.168.0.10"));
+        assert_eq!(code.created_at.map(|dt| dt.timestamp()), Some(now.timestamp()));
+        assert_eq!(repo.take_auth_code("ABC123").unwrap(), None);
+    }
+
+    #[test]
+    fn old_auth_codes_are_deleted() {
+        let repo = repo();
+        let now = Utc::now();
+        repo.save_auth_code("OLD", "ip", now - Duration::minutes(5)).unwrap();
+        repo.save_auth_code("NEW", "ip", now).unwrap();
+
+        assert_eq!(repo.delete_auth_codes_before(now - Duration::minutes(1)).unwrap(), 1);
+        assert!(repo.take_auth_code("OLD").unwrap().is_none());
+        assert!(repo.take_auth_code("NEW").unwrap().is_some());
+    }
+
+    #[test]
+    fn tokens_round_trip() {
+        let repo = repo();
+        let token = new_token("t1", "d1");
+        repo.insert_token(&token).unwrap();
+
+        let found = repo.find_token("t1").unwrap().unwrap();
+        assert_eq!(found.username, "ana");
+        assert_eq!(found.device_id.as_deref(), Some("d1"));
+        assert_eq!(found.binding.as_deref(), Some("none"));
+        assert_eq!(found.expires_at.map(|dt| dt.timestamp()), Some(token.expires_at.timestamp()));
+        assert_eq!(repo.find_token("t2").unwrap(), None);
+    }
+
+    #[test]
+    fn invalid_expiry_reads_as_none() {
+        let repo = repo();
+        repo.insert_token(&new_token("t1", "d1")).unwrap();
+        repo.connection().execute("UPDATE tokens SET expires_at = 'soon'", []).unwrap();
+
+        assert_eq!(repo.find_token("t1").unwrap().unwrap().expires_at, None);
+    }
+
+    #[test]
+    fn replaced_token_keeps_device() {
+        let repo = repo();
+        repo.insert_token(&new_token("old", "d1")).unwrap();
+
+        let later = Utc::now() + Duration::days(60);
+        assert!(repo.replace_token("old", "new", later, Utc::now()).unwrap());
+        assert!(!repo.replace_token("old", "newer", later, Utc::now()).unwrap());
+        assert_eq!(repo.find_token("old").unwrap(), None);
+        assert_eq!(repo.find_token("new").unwrap().unwrap().device_id.as_deref(), Some("d1"));
+    }
+
+    #[test]
+    fn devices_are_listed_by_last_seen_and_revoked() {
+        let repo = repo();
+        repo.insert_token(&new_token("t1", "d1")).unwrap();
+        repo.insert_token(&new_token("t2", "d2")).unwrap();
+        repo.touch_token("t1", Utc::now() + Duration::minutes(1)).unwrap();
+
+        let devices = repo.list_devices().unwrap();
+        assert_eq!(devices.iter().map(|d| d.id.as_str()).collect::<Vec<_>>(), ["d1", "d2"]);
+
+        assert_eq!(repo.revoke_device("d1").unwrap().as_deref(), Some("t1"));
+        assert_eq!(repo.revoke_device("d1").unwrap(), None);
+        assert_eq!(repo.find_token("t1").unwrap(), None);
+    }
+
+    #[test]
+    fn sessions_and_chunks() {
+        let repo = repo();
+        let session = UploadSession {
+            id: "s1".to_string(),
+            username: "ana".to_string(),
+            filename: "a.cr3".to_string(),
+            size: 10,
+            hash: "h".to_string(),
+            modified_at: None,
+            temp_path: PathBuf::from("/tmp/.s1.part"),
+        };
+        repo.insert_session(&session).unwrap();
+
+        assert_eq!(repo.find_session_id("ana", "h").unwrap().as_deref(), Some("s1"));
+        assert_eq!(repo.find_session("s1", "ana").unwrap(), Some(session));
+        assert_eq!(repo.find_session("s1", "bob").unwrap(), None);
+
+        repo.record_chunk("s1", 4, 6).unwrap();
+        repo.record_chunk("s1", 0, 4).unwrap();
+        assert_eq!(repo.session_chunks("s1").unwrap(), [(0, 4), (4, 10)]);
+
+        repo.clear_chunks("s1").unwrap();
+        assert!(repo.session_chunks("s1").unwrap().is_empty());
+
+        repo.record_chunk("s1", 0, 10).unwrap();
+        repo.delete_session("s1").unwrap();
+        assert_eq!(repo.find_session("s1", "ana").unwrap(), None);
+        assert!(repo.session_chunks("s1").unwrap().is_empty());
+    }
+
+    #[test]
+    fn sessions_are_taken_once() {
+        let repo = repo();
+        let session = UploadSession {
+            id: "s1".to_string(),
+            username: "ana".to_string(),
+            filename: "a.cr3".to_string(),
+            size: 10,
+            hash: "h".to_string(),
+            modified_at: None,
+            temp_path: PathBuf::from("/tmp/.s1.part"),
+        };
+        repo.insert_session(&session).unwrap();
+        repo.record_chunk("s1", 0, 10).unwrap();
+
+        assert_eq!(repo.take_session("s1", "bob").unwrap(), None);
+        assert_eq!(repo.take_session("s1", "ana").unwrap(), Some(session));
+        assert_eq!(repo.take_session("s1", "ana").unwrap(), None);
+        assert!(repo.session_chunks("s1").unwrap().is_empty());
+    }
+
+    #[test]
+    fn expired_sessions_are_deleted() {
+        let repo = repo();
+        let session = UploadSession {
+            id: "s1".to_string(),
+            username: "ana".to_string(),
+            filename: "a.cr3".to_string(),
+            size: 10,
+            hash: "h".to_string(),
+            modified_at: None,
+            temp_path: PathBuf::from("/tmp/.s1.part"),
+        };
+        repo.insert_session(&session).unwrap();
+        repo.record_chunk("s1", 0, 4).unwrap();
+
+        assert!(repo.delete_sessions_before(Utc::now() - Duration::hours(1)).unwrap().is_empty());
+        assert!(repo.find_session("s1", "ana").unwrap().is_some());
+
+        let deleted = repo.delete_sessions_before(Utc::now() + Duration::seconds(1)).unwrap();
+        assert_eq!(deleted, [PathBuf::from("/tmp/.s1.part")]);
+        assert_eq!(repo.find_session("s1", "ana").unwrap(), None);
+        assert!(repo.session_chunks("s1").unwrap().is_empty());
+    }
+
+    #[test]
+    fn jobs_by_hash_and_device() {
+        let repo = repo();
+        repo.insert_token(&new_token("t1", "d1")).unwrap();
+        repo.insert_token(&new_token("t2", "d2")).unwrap();
+        repo.insert_token(&NewToken { username: "bia".to_string(), ..new_token("t3", "d3") }).unwrap();
+        repo.insert_job(&new_job("j1", "h1", "d1")).unwrap();
+        repo.insert_job(&new_job("j2", "h2", "d1")).unwrap();
+
+        let mut done = new_job("j3", "h1", "d2");
+        repo.insert_job(&done).unwrap();
+        done.state = TransferState::Done;
+        repo.save_job(&done, None).unwrap();
+        repo.insert_job(&new_job("j4", "h1", "d3")).unwrap();
+
+        let ids = |jobs: Vec<TransferJob>| jobs.into_iter().map(|j| j.id).collect::<Vec<_>>();
+        assert_eq!(ids(repo.active_jobs_for_hash("h1", "ana").unwrap()), ["j1"]);
+        assert_eq!(ids(repo.active_jobs_for_hash("h1", "bia").unwrap()), ["j4"]);
+        assert_eq!(ids(repo.active_jobs_for_device("d1").unwrap()), ["j1", "j2"]);
+        assert!(repo.active_jobs_for_device("d2").unwrap().is_empty());
+    }
+
+    #[test]
+    fn due_jobs_are_dispatched_ones_past_their_retry_time() {
+        let repo = repo();
+        let now = Utc::now();
+
+        let mut due = new_job("due", "h1", "d1");
+        repo.insert_job(&due).unwrap();
+        due.state = TransferState::Dispatched;
+        due.attempts = 1;
+        repo.save_job(&due, Some(now - Duration::seconds(1))).unwrap();
+
+        let mut later = new_job("later", "h2", "d1");
+        repo.insert_job(&later).unwrap();
+        later.state = TransferState::Uploading;
+        repo.save_job(&later, Some(now + Duration::minutes(10))).unwrap();
+
+        repo.insert_job(&new_job("pending", "h3", "d1")).unwrap();
+
+        let jobs = repo.due_jobs(now).unwrap();
+        assert_eq!(jobs, [due]);
+    }
+
+    #[test]
+    fn transfer_state_round_trips() {
+        for state in [
+            TransferState::Pending,
+            TransferState::Dispatched,
+            TransferState::Uploading,
+            TransferState::Done,
+            TransferState::Failed,
+        ] {
+            assert_eq!(TransferState::parse(state.as_str()), state);
+        }
+        assert_eq!(TransferState::parse("bogus"), TransferState::Pending);
+    }
+}
@@ -1,5 +1,22 @@
 import { app, BrowserWindow, ipcMain, dialog } from "electron";
+import { readFile } from "fs/promises";
+import os from "os";
 import path from "path";
+import { fileURLToPath } from "url";
+
+const __dirname = path.dirname(fileURLToPath(import.meta.url));
+
+// Pasta de dados locais do usuário, a mesma de `dirs::data_local_dir` no servidor
+function dataLocalDir() {
+  switch (process.platform) {
+    case "win32":
+      return process.env.LOCALAPPDATA ?? path.join(os.homedir(), "AppData", "Local");
+    case "darwin":
+      return path.join(os.homedir(), "Library", "Application Support");
+    default:
+      return process.env.XDG_DATA_HOME ?? path.join(os.homedir(), ".local", "share");
+  }
+}
 
 function createWindow() {
   const win = new BrowserWindow({
@@ -7,6 +24,7 @@ function createWindow() {
     height: 800,
     webPreferences: {
       nodeIntegration: true,
+      preload: path.join(__dirname, "preload.cjs"),
     },
   });
 
@@ -26,4 +44,10 @@ ipcMain.handle("select-folder", async () => {
   return result.filePaths[0];
 });
 
+// Chave desktop gerada pelo servidor a cada execução (ver cube-server/src/utils/desktop_key.rs)
+ipcMain.handle("desktop-key", async () => {
+  const file = path.join(dataLocalDir(), "Cube", "desktop.key");
+  return (await readFile(file, "utf8")).trim();
+});
+
 app.whenReady().then(createWindow);
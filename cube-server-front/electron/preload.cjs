@@ -0,0 +1,6 @@
+const { contextBridge, ipcRenderer } = require("electron");
+
+// Expõe à página só o necessário para falar com o servidor local
+contextBridge.exposeInMainWorld("cube", {
+  desktopKey: () => ipcRenderer.invoke("desktop-key"),
+});
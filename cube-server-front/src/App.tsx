@@ -6,11 +6,11 @@ import { useWebSocket } from "./hooks/useWebSocket";
 import { ConnectionStatus } from "./components/ConnectionStatus";
 
 const App = () => {
-  const [token, setToken] = useState<string | null>(null);
+  const [pairedUser, setPairedUser] = useState<string | null>(null);
   const [isOnline, setIsOnline] = useState(false);
 
   const { send } = useWebSocket({
-    onTokenReceived: setToken,
+    onPaired: setPairedUser,
     onStatusChange: setIsOnline,
   });
 
@@ -20,7 +20,7 @@ const App = () => {
       style={{ height: "100vh", padding: 24, position: "relative" }}
     >
       <ConnectionStatus online={isOnline} />
-      {!token ? <QrCodePanel /> : <PhotoGrid send={send} />}
+      {!pairedUser ? <QrCodePanel /> : <PhotoGrid send={send} />}
     </FluentProvider>
   );
 };
@@ -58,11 +58,24 @@ const classNames = mergeStyleSets({
 
 type Props = {
   photo: Photo;
+  desktopKey: string | null;
   selected: boolean;
   onClick: () => void;
 };
 
-export const PhotoItem: React.FC<Props> = ({ photo, selected, onClick }) => {
+// A URL da thumb leva a chave desktop, já que <img> não envia cabeçalhos
+const thumbUrl = (photo: Photo, desktopKey: string) => {
+  const url = new URL(`http://127.0.0.1:8080${photo.url}`);
+  url.searchParams.set("desktop_key", desktopKey);
+  return url.toString();
+};
+
+export const PhotoItem: React.FC<Props> = ({
+  photo,
+  desktopKey,
+  selected,
+  onClick,
+}) => {
   const getIcon = () => {
     switch (photo.status) {
       case "success":
@@ -84,7 +97,7 @@ export const PhotoItem: React.FC<Props> = ({ photo, selected, onClick }) => {
         }`}
       >
         <Image
-          src={`http://bruno-linux:8080${photo.url}`}
+          src={desktopKey ? thumbUrl(photo, desktopKey) : undefined}
           width={120}
           height={120}
           imageFit={ImageFit.cover}
@@ -1,9 +1,10 @@
 import React, { useEffect, useState } from "react";
 import { Stack, Text } from "@fluentui/react";
-import { Photo } from "./types";
+import { Photo, PhotoPage } from "./types";
 import { useSelection } from "./useSelection";
 import { PhotoItem } from "./PhotoItem";
 import { PhotoToolbar } from "./PhotoToolbar";
+import { desktopKey } from "../desktopKey";
 
 export const PhotoGrid: React.FC = ({
   send,
@@ -11,6 +12,7 @@ export const PhotoGrid: React.FC = ({
   send: (data: any) => void;
 }) => {
   const [photos, setPhotos] = useState<Photo[]>([]);
+  const [key, setKey] = useState<string | null>(null);
   const {
     selectedIds,
     toggleSelection,
@@ -19,13 +21,31 @@ export const PhotoGrid: React.FC = ({
     selectAll,
   } = useSelection(photos);
 
+  // 🔑 Chave desktop, usada para mostrar as thumbs
+  useEffect(() => {
+    desktopKey()
+      .then(setKey)
+      .catch((err) => console.error("❌ Chave desktop indisponível:", err));
+  }, []);
+
   // 🔄 Carrega fotos da API
   useEffect(() => {
     const loadPhotos = async () => {
       try {
-        const res = await fetch("http://bruno-linux:8080/api/thumbs/list");
-        const data = await res.json();
-        setPhotos(data);
+        const headers = { Authorization: `Desktop ${await desktopKey()}` };
+
+        // A API devolve páginas; segue o cursor até a última
+        let cursor: string | null = null;
+        do {
+          const url = new URL("http://127.0.0.1:8080/api/thumbs/list");
+          if (cursor) url.searchParams.set("cursor", cursor);
+          const res = await fetch(url, { headers });
+          if (!res.ok) throw new Error(`${res.status} ${await res.text()}`);
+          const page: PhotoPage = await res.json();
+          const first = cursor === null;
+          setPhotos((prev) => (first ? page.items : [...prev, ...page.items]));
+          cursor = page.next_cursor;
+        } while (cursor);
       } catch (err) {
         console.error("❌ Erro ao carregar thumbs:", err);
       }
@@ -36,10 +56,13 @@ export const PhotoGrid: React.FC = ({
 
   const sendConfigToServer = async (folderPath: string): Promise<string> => {
     try {
-      const response = await fetch("http://bruno-linux:8080/set-config", {
+      const response = await fetch("http://127.0.0.1:8080/set-config", {
         method: "POST",
-        headers: { "Content-Type": "application/json" },
-        body: JSON.stringify({ folder: folderPath }),
+        headers: {
+          "Content-Type": "application/json",
+          Authorization: `Desktop ${await desktopKey()}`,
+        },
+        body: JSON.stringify({ upload_dir: folderPath }),
       });
       console.log("✅ Configuração enviada com sucesso");
 
@@ -81,6 +104,7 @@ export const PhotoGrid: React.FC = ({
           <PhotoItem
             key={photo.id}
             photo={photo}
+            desktopKey={key}
             selected={isSelected(photo.id)}
             onClick={() => toggleSelection(photo.id)}
           />
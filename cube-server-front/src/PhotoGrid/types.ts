@@ -7,3 +7,9 @@ export type Photo = {
   size: string;
   status: PhotoStatus;
 };
+
+export type PhotoPage = {
+  items: Photo[];
+  total: number;
+  next_cursor: string | null;
+};
@@ -0,0 +1,5 @@
+// Chave que identifica este app desktop para o servidor da mesma máquina. O servidor gera uma nova a cada
+// execução, então ela é lida de novo a cada uso.
+export function desktopKey(): Promise<string> {
+  return window.cube.desktopKey();
+}
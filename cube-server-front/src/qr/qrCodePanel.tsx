@@ -1,5 +1,6 @@
 import React from "react";
 import { QrCard } from "./QrCard";
+import { desktopKey } from "../desktopKey";
 
 export const QrCodePanel: React.FC = () => {
   const [link, setLink] = React.useState<string | null>(null);
@@ -7,7 +8,11 @@ export const QrCodePanel: React.FC = () => {
   React.useEffect(() => {
     const fetchCode = async () => {
       try {
-        const res = await fetch("http://bruno-linux:8080/generate_code");
+        // Só o app desktop pode gerar códigos de pareamento
+        const res = await fetch("http://127.0.0.1:8080/generate_code", {
+          headers: { Authorization: `Desktop ${await desktopKey()}` },
+        });
+        if (!res.ok) throw new Error(`${res.status} ${await res.text()}`);
         const json = await res.json();
         const fullLink = `http://${json.ip}:8080?code=${json.code}`;
         setLink(fullLink);
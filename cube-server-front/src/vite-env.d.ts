@@ -1 +1,8 @@
 /// <reference types="vite/client" />
+
+// Exposto por electron/preload.cjs
+interface Window {
+  cube: {
+    desktopKey: () => Promise<string>;
+  };
+}
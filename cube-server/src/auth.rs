@@ -0,0 +1,375 @@
+//! # Authentication Middleware
+//!
+//! This module enforces the session tokens issued by `auth_handler` on protected routes.
+//!
+//! ## Features
+//! - `AuthUser`: Axum extractor that resolves the caller's token to a username using the `tokens` table.
+//! - `require_auth`: Middleware that rejects requests without a valid token and makes the resolved
+//!   `AuthUser` available to the handlers behind it.
+//! - `require_desktop`: Middleware that only lets the desktop app of the server machine through, used for the
+//!   device administration endpoints.
+//! - `DesktopViewer`: Axum extractor for the desktop app running on the server machine, which proves itself
+//!   with the desktop key generated at each launch (see `utils::desktop_key`).
+//! - `Caller`: Axum extractor for routes open to both, resolved as a `DesktopViewer` when a desktop key is sent
+//!   and as an `AuthUser` otherwise.
+//!
+//! Expired tokens are rejected, and each accepted request updates the token's `last_seen` time.
+//! Tokens issued with a `TokenBinding` are also rejected when used from another IP or subnet.
+//!
+//! ## Token Sources
+//! - `Authorization: Bearer <token>` header, for regular HTTP requests.
+//! - `?token=<token>` query parameter, only for WebSocket upgrades, since browsers cannot set headers
+//!   on WebSocket connections.
+//!
+//! ## Desktop Key Sources
+//! - `Authorization: Desktop <key>` header.
+//! - `?desktop_key=<key>` query parameter, for WebSocket connections and image URLs, which cannot set headers.
+//!
+//! ## Open Routes
+//! - `/ping` and `/auth` need neither a token nor the desktop key.
+//! - `/generate_code` needs the desktop key. It used to be open like `/auth`, but then any host on the network could
+//!   fetch a code and pair itself, which the single-use codes and the `/auth` lockout cannot prevent. Phones only
+//!   call `/auth`, with the code the desktop app shows them.
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, Query, Request},
+    http::{header, request::Parts, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use cube_db::Repository;
+use serde::Deserialize;
+use std::{net::{IpAddr, SocketAddr}, sync::Arc};
+
+use crate::state::AppState;
+
+/// The authenticated caller of a request.
+///
+/// - `token`: The session token sent by the client.
+/// - `username`: The username the session token was issued to.
+/// - `device_id`: The paired device the session token belongs to.
+#[derive(Clone)]
+pub struct AuthUser {
+    pub token: String,
+    pub username: String,
+    pub device_id: String,
+}
+
+/// Restricts where a token may be used from, chosen by the client when pairing.
+///
+/// - `None`: The token works from any address.
+/// - `Ip`: The token only works from the IP it was issued to.
+/// - `Subnet`: The token only works from the subnet it was issued to (/24 for IPv4, /64 for IPv6).
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenBinding {
+    #[default]
+    None,
+    Ip,
+    Subnet,
+}
+
+impl TokenBinding {
+    /// Value stored in the `tokens.binding` column.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TokenBinding::None => "none",
+            TokenBinding::Ip => "ip",
+            TokenBinding::Subnet => "subnet",
+        }
+    }
+
+    /// Reads a `tokens.binding` column; missing or unknown values mean no binding.
+    pub fn parse(value: Option<&str>) -> Self {
+        match value {
+            Some("ip") => TokenBinding::Ip,
+            Some("subnet") => TokenBinding::Subnet,
+            _ => TokenBinding::None,
+        }
+    }
+
+    /// Returns true if a token issued to `issued_ip` may be used from `client`.
+    pub fn allows(self, issued_ip: Option<&str>, client: IpAddr) -> bool {
+        if self == TokenBinding::None {
+            return true;
+        }
+        let Some(issued) = issued_ip.and_then(|ip| ip.parse::<IpAddr>().ok()) else {
+            return false;
+        };
+        let (issued, client) = (issued.to_canonical(), client.to_canonical());
+
+        match self {
+            TokenBinding::None => true,
+            TokenBinding::Ip => issued == client,
+            TokenBinding::Subnet => same_subnet(issued, client),
+        }
+    }
+}
+
+/// The desktop app of the server machine, which sent the desktop key of this launch from the loopback interface.
+///
+/// Desktop apps reach the server at `127.0.0.1`: a host name that resolves to the machine's LAN address arrives on
+/// that interface instead and is rejected.
+pub struct DesktopViewer;
+
+/// The caller of a route used by both paired devices and the desktop app.
+///
+/// - `User`: A paired device, which only sees its user's library.
+/// - `Desktop`: The desktop app, which sees every user's library.
+pub enum Caller {
+    User(AuthUser),
+    Desktop,
+}
+
+/// Query string carrying the desktop key.
+#[derive(Deserialize)]
+struct DesktopKeyQuery {
+    desktop_key: String,
+}
+
+/// Query string carrying a token on WebSocket upgrades.
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: String,
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for AuthUser {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        // Already resolved by `require_auth`
+        if let Some(user) = parts.extensions.get::<AuthUser>() {
+            return Ok(user.clone());
+        }
+
+        let token = token_from_parts(parts).ok_or((StatusCode::UNAUTHORIZED, "Missing token"))?;
+
+        let lookup = token.clone();
+        let record = state.db(move |repo| repo.find_token(&lookup)).await.unwrap_or(None);
+
+        let (username, issued_ip, binding, device_id) = match record {
+            Some(record) if !is_expired(record.expires_at) => {
+                (record.username, record.ip, record.binding, record.device_id.unwrap_or_default())
+            }
+            Some(_) => return Err((StatusCode::UNAUTHORIZED, "Expired token")),
+            None => return Err((StatusCode::UNAUTHORIZED, "Invalid token")),
+        };
+
+        let client_ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        let allowed = match client_ip {
+            Some(ip) => TokenBinding::parse(binding.as_deref()).allows(issued_ip.as_deref(), ip),
+            None => TokenBinding::parse(binding.as_deref()) == TokenBinding::None,
+        };
+        if !allowed {
+            return Err((StatusCode::UNAUTHORIZED, "Token not valid from this network"));
+        }
+
+        let touched = token.clone();
+        let _ = state.db(move |repo| repo.touch_token(&touched, Utc::now())).await;
+
+        Ok(AuthUser { token, username, device_id })
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for DesktopViewer {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        let local = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .is_some_and(|ConnectInfo(addr)| addr.ip().is_loopback());
+        if !local {
+            return Err((StatusCode::FORBIDDEN, "Only available on the server machine"));
+        }
+
+        match desktop_key_from_parts(parts) {
+            Some(key) if keys_match(&key, &state.desktop_key) => Ok(DesktopViewer),
+            Some(_) => Err((StatusCode::UNAUTHORIZED, "Invalid desktop key")),
+            None => Err((StatusCode::UNAUTHORIZED, "Missing desktop key")),
+        }
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for Caller {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        if desktop_key_from_parts(parts).is_some() {
+            DesktopViewer::from_request_parts(parts, state).await.map(|DesktopViewer| Caller::Desktop)
+        } else {
+            AuthUser::from_request_parts(parts, state).await.map(Caller::User)
+        }
+    }
+}
+
+/// Middleware that requires a valid token on every request it wraps.
+///
+/// The resolved `AuthUser` is stored in the request extensions so handlers can extract it again
+/// without another database lookup.
+pub async fn require_auth(user: AuthUser, mut request: Request, next: Next) -> Response {
+    request.extensions_mut().insert(user);
+    next.run(request).await
+}
+
+/// Middleware that only lets the desktop app of the server machine through (see `DesktopViewer`).
+pub async fn require_desktop(_: DesktopViewer, request: Request, next: Next) -> Response {
+    next.run(request).await
+}
+
+/// Returns true if `expires_at` is in the past. Tokens without a valid expiry are treated as expired.
+fn is_expired(expires_at: Option<DateTime<Utc>>) -> bool {
+    match expires_at {
+        Some(expires) => expires <= Utc::now(),
+        None => true,
+    }
+}
+
+/// Returns true if both addresses are in the same /24 (IPv4) or /64 (IPv6) network.
+fn same_subnet(a: IpAddr, b: IpAddr) -> bool {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => a.octets()[..3] == b.octets()[..3],
+        (IpAddr::V6(a), IpAddr::V6(b)) => a.segments()[..4] == b.segments()[..4],
+        _ => false,
+    }
+}
+
+/// Reads the desktop key from the `Authorization` header or the query string.
+fn desktop_key_from_parts(parts: &Parts) -> Option<String> {
+    let header = parts
+        .headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Desktop "))
+        .map(|key| key.trim().to_string());
+
+    header.or_else(|| {
+        Query::<DesktopKeyQuery>::try_from_uri(&parts.uri)
+            .ok()
+            .map(|Query(query)| query.desktop_key)
+    })
+}
+
+/// Compares two keys in constant time, so the time taken does not reveal how much of a guess was right.
+fn keys_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Reads the token from the `Authorization` header, or from the query string on WebSocket upgrades.
+fn token_from_parts(parts: &Parts) -> Option<String> {
+    let bearer = parts
+        .headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|t| t.trim().to_string());
+
+    if bearer.is_some() {
+        return bearer;
+    }
+
+    let is_upgrade = parts
+        .headers
+        .get(header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+
+    if is_upgrade {
+        return Query::<TokenQuery>::try_from_uri(&parts.uri)
+            .ok()
+            .map(|Query(query)| query.token);
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(uri: &str, headers: &[(header::HeaderName, &str)]) -> Parts {
+        let mut request = axum::http::Request::builder().uri(uri);
+        for (name, value) in headers {
+            request = request.header(name, *value);
+        }
+        request.body(()).unwrap().into_parts().0
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn unbound_tokens_work_anywhere() {
+        assert!(TokenBinding::None.allows(Some("10.0.0.2"), ip("203.0.113.9")));
+        assert!(TokenBinding::None.allows(None, ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn ip_binding_needs_the_same_address() {
+        assert!(TokenBinding::Ip.allows(Some("192.168.0.10"), ip("192.168.0.10")));
+        assert!(TokenBinding::Ip.allows(Some("192.168.0.10"), ip("::ffff:192.168.0.10")));
+        assert!(!TokenBinding::Ip.allows(Some("192.168.0.10"), ip("192.168.0.11")));
+        assert!(!TokenBinding::Ip.allows(None, ip("192.168.0.10")));
+        assert!(!TokenBinding::Ip.allows(Some("not an ip"), ip("192.168.0.10")));
+    }
+
+    #[test]
+    fn subnet_binding_needs_the_same_network() {
+        assert!(TokenBinding::Subnet.allows(Some("192.168.0.10"), ip("192.168.0.200")));
+        assert!(!TokenBinding::Subnet.allows(Some("192.168.0.10"), ip("192.168.1.10")));
+        assert!(TokenBinding::Subnet.allows(Some("fd00:1:2:3::10"), ip("fd00:1:2:3::20")));
+        assert!(!TokenBinding::Subnet.allows(Some("fd00:1:2:3::10"), ip("fd00:1:2:4::10")));
+        assert!(!TokenBinding::Subnet.allows(Some("192.168.0.10"), ip("fd00:1:2:3::10")));
+    }
+
+    #[test]
+    fn binding_round_trips_through_the_database_value() {
+        for binding in [TokenBinding::None, TokenBinding::Ip, TokenBinding::Subnet] {
+            assert_eq!(TokenBinding::parse(Some(binding.as_str())), binding);
+        }
+        assert_eq!(TokenBinding::parse(Some("mac")), TokenBinding::None);
+        assert_eq!(TokenBinding::parse(None), TokenBinding::None);
+    }
+
+    #[test]
+    fn keys_must_match_exactly() {
+        assert!(keys_match("abc123", "abc123"));
+        assert!(!keys_match("abc124", "abc123"));
+        assert!(!keys_match("abc12", "abc123"));
+        assert!(!keys_match("", "abc123"));
+    }
+
+    #[test]
+    fn token_from_header_or_websocket_query() {
+        let bearer = parts("/api/thumbs", &[(header::AUTHORIZATION, "Bearer t1")]);
+        assert_eq!(token_from_parts(&bearer).as_deref(), Some("t1"));
+
+        let query = parts("/api/thumbs?token=t1", &[]);
+        assert_eq!(token_from_parts(&query), None);
+
+        let upgrade = parts("/ws?token=t1", &[(header::UPGRADE, "websocket")]);
+        assert_eq!(token_from_parts(&upgrade).as_deref(), Some("t1"));
+    }
+
+    #[test]
+    fn desktop_key_from_header_or_query() {
+        let header = parts("/admin/devices", &[(header::AUTHORIZATION, "Desktop k1")]);
+        assert_eq!(desktop_key_from_parts(&header).as_deref(), Some("k1"));
+
+        let query = parts("/thumbs/a.jpg?desktop_key=k1", &[]);
+        assert_eq!(desktop_key_from_parts(&query).as_deref(), Some("k1"));
+
+        let bearer = parts("/admin/devices", &[(header::AUTHORIZATION, "Bearer t1")]);
+        assert_eq!(desktop_key_from_parts(&bearer), None);
+    }
+}
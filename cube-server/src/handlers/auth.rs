@@ -1,46 +1,69 @@
+//! # Authentication Module
+//!
+//! This module implements a simple code-based authentication flow for opening a session on the server.
+//! The flow consists of two main endpoints:
+//!
+//! - **Code Generation (`generate_code_handler`)**: Generates a random 6-character code, saves it in the database along with the requester's IP, and returns it to the desktop app together with the server's IP. The code expires in 60 seconds.
+//! - **Authentication (`auth_handler`)**: Receives a code and username, validates and consumes the code, generates a UUID token for the session bound to the phone's IP, and notifies desktop viewers that a phone was paired.
+//! - **Refresh (`refresh_handler`)**: Replaces the caller's token with a new one that has a fresh expiry, keeping the same device.
+//!
+//! A background task (`start_code_sweeper`) periodically deletes expired codes and forgets old failed attempts.
+//!
+//! ## Structures
+//! - `CodeResponse`: Response when generating a code (code, ip, expires_in).
+//! - `AuthRequest`: Payload for authentication (code, username, optional device name and token binding).
+//! - `AuthResponse`: Response when authenticating or refreshing (token, expires_at).
+//!
+//! ## Authentication Flow
+//! 1. The desktop app requests an authentication code and shows it as a QR code.
+//! 2. The server generates and returns the code, IP, and expiration time.
+//! 3. The phone sends the code and username for authentication.
+//! 4. If the code is valid, the server generates a token, saves it in the database, and notifies desktop viewers via WebSocket.
+//! 5. The client receives the token and sends it as `Authorization: Bearer <token>` on subsequent requests.
+//!
+//! ## Notes
+//! - Only the desktop app of the server machine can generate codes (see `auth::require_desktop`), so a host on the
+//!   network cannot pair itself without someone showing it a code.
+//! - Codes expire after `CODE_TTL_SECS` seconds and can only be used once.
+//! - Tokens expire after `TOKEN_TTL_DAYS` days unless refreshed.
+//! - After `MAX_FAILED_ATTEMPTS` failed attempts (see `utils::throttle`), the client IP is locked out of `/auth` for a while.
+//! - The IP returned by `generate_code_handler` is the server's, so the phone knows where to connect.
+//!   The IPs stored in `auth_codes` and `tokens` are the real client addresses taken from the connection.
+//! - A token issued with `binding: "ip"` or `binding: "subnet"` is rejected when used from another IP or
+//!   subnet (see `auth::TokenBinding`).
+//! - All tokens and codes are stored in SQLite.
+//! - Real-time notifications are sent via WebSocket.
+
 use axum::{
-    extract::{Json, State},
+    extract::{ConnectInfo, Json, State},
     http::StatusCode,
     response::IntoResponse,
     Json as AxumJson,
 };
 use rand::{distributions::Alphanumeric, Rng};
-use rusqlite::params;
+use cube_db::{NewToken, Repository};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use uuid::Uuid;
-use chrono::Utc;
-use axum::extract::ws::Message;
+use chrono::{DateTime, Utc};
 
+use crate::auth::{AuthUser, TokenBinding};
 use crate::state::AppState;
+use crate::utils::blob::BLOB_DIR;
+use crate::ws::{protocol::ServerMessage, registry::ClientRole};
 use local_ip_address::local_ip;
-use serde_json::json;
 
-/// # Authentication Module
-///
-/// This module implements a simple code-based authentication flow for opening a session on the server.
-/// The flow consists of two main endpoints:
-///
-/// - **Code Generation (`generate_code_handler`)**: Generates a random 6-character code, saves it in the database along with the server's IP, and returns it to the client. The code expires in 60 seconds.
-/// - **Authentication (`auth_handler`)**: Receives a code and username, validates the code in the database, generates a UUID token for the session, and notifies all connected WebSocket clients with the new token.
-///
-/// ## Structures
-/// - `CodeResponse`: Response when generating a code (code, ip, expires_in).
-/// - `AuthRequest`: Payload for authentication (code, username).
-/// - `AuthResponse`: Response when authenticating (token).
-///
-/// ## Authentication Flow
-/// 1. The client requests an authentication code.
-/// 2. The server generates and returns the code, IP, and expiration time.
-/// 3. The client sends the code and username for authentication.
-/// 4. If the code is valid, the server generates a token, saves it in the database, and notifies via WebSocket.
-/// 5. The client receives the token for use in subsequent requests.
-///
-/// ## Notes
-/// - The code does not check for expiration, only existence.
-/// - The returned IP is always the server's, not the client's.
-/// - All tokens and codes are stored in SQLite.
-/// - Real-time notifications are sent via WebSocket.
+/// Number of seconds a pairing code stays valid.
+pub const CODE_TTL_SECS: u64 = 60;
+
+/// Number of days a session token stays valid.
+pub const TOKEN_TTL_DAYS: i64 = 30;
+
+/// Interval between two runs of the expired code sweeper.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Longest accepted username, in bytes.
+const MAX_USERNAME_LEN: usize = 64;
 
 /// Response when generating an authentication code.
 #[derive(Serialize)]
@@ -55,96 +78,189 @@ pub struct CodeResponse {
 pub struct AuthRequest {
     pub code: String,
     pub username: String,
+    pub device_name: Option<String>,
+    #[serde(default)]
+    pub binding: TokenBinding,
 }
 
-/// Response when authenticating.
+/// Response when authenticating or refreshing a token.
 #[derive(Serialize)]
 pub struct AuthResponse {
     pub token: String,
+    pub expires_at: String,
 }
 
-/// Generates a 6-character code, saves it in the database, and returns it to the client.
+/// Generates a 6-character code, saves it in the database, and returns it to the desktop app.
+///
+/// Only reachable with the desktop key, see `main.rs`.
 ///
 /// # Flow
 /// - Generates a random code.
-/// - Saves it in the database with timestamp and IP.
-/// - Returns JSON with code, IP, and expiration time.
+/// - Saves it in the database with timestamp and the requester's IP; a failed write gives `500 Internal Server Error`.
+/// - Returns JSON with code, the server's IP, and expiration time.
 pub async fn generate_code_handler(
     State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
 ) -> impl IntoResponse {
     let code = generate_code(6);
-    let expires_in = 60;
+    let expires_in = CODE_TTL_SECS;
 
     let ip = match local_ip() {
         Ok(ip) => ip.to_string(),
         Err(_) => "127.0.0.1".to_string(),
     };
 
-
     let now = Utc::now();
 
-    let db = state.db.lock().await;
-    let _ = db.execute(
-        "INSERT OR REPLACE INTO auth_codes (code, created_at, ip) VALUES (?1, ?2, ?3)",
-        params![code, now.to_rfc3339(), ip],
-    );
+    let (saved_code, client_ip) = (code.clone(), addr.ip().to_canonical().to_string());
+    if let Err(e) = state.db(move |repo| repo.save_auth_code(&saved_code, &client_ip, now)).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Error saving code: {e}")).into_response();
+    }
 
     AxumJson(CodeResponse {
         code,
         ip,
         expires_in,
-    })
+    }).into_response()
 }
 
 /// Authenticates the user using code and username, returns a session token.
 ///
 /// # Flow
-/// - Validates code in the database.
-/// - Generates a UUID token.
-/// - Saves the token in the database.
-/// - Notifies WebSocket clients.
-/// - Returns token in JSON.
+/// - Rejects the request if the client IP is locked out.
+/// - Rejects usernames that are not a plain directory name (see `valid_username`).
+/// - Looks up the code and deletes it, so it cannot be used twice.
+/// - Rejects unknown or expired codes and records the failure for the client IP.
+/// - Generates a UUID token with an expiry date.
+/// - Saves the token in the database along with the client IP, the requested binding, a new device ID and the device name.
+///   A failed write gives `500 Internal Server Error`, so the phone never gets a token the server does not know.
+/// - Notifies desktop viewers with a `paired` message.
+/// - Returns token and expiry in JSON.
 pub async fn auth_handler(
     State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Json(payload): Json<AuthRequest>,
 ) -> impl IntoResponse {
-    let db = state.db.lock().await;
+    let client_ip = addr.ip();
 
-    println!("⚠️ Autenticando com o código {}", payload.code);
+    if let Some(remaining) = state.auth_throttle.lock().await.locked_for(client_ip) {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            format!("Too many failed attempts, try again in {} seconds", remaining.as_secs()),
+        ).into_response();
+    }
 
-    let result: rusqlite::Result<String> = db.query_row(
-        "SELECT ip FROM auth_codes WHERE code = ?1",
-        [payload.code.clone()],
-        |row| row.get(0),
-    );
+    if !valid_username(&payload.username) {
+        return (StatusCode::BAD_REQUEST, "Invalid username").into_response();
+    }
 
-    let ip: String = match result {
-        Ok(ip) => ip,
-        Err(_) => return (StatusCode::UNAUTHORIZED, "Código inválido").into_response(),
-    };
+    // Codes are single-use: consume it whatever the outcome
+    let code = payload.code.clone();
+    let result = state.db(move |repo| repo.take_auth_code(&code)).await.unwrap_or(None);
+
+    match result {
+        Some(code) if !is_expired(code.created_at) => {}
+        Some(_) => {
+            state.auth_throttle.lock().await.record_failure(client_ip);
+            return (StatusCode::UNAUTHORIZED, "Código expirado").into_response();
+        }
+        None => {
+            state.auth_throttle.lock().await.record_failure(client_ip);
+            println!("🚫 Invalid code from {}", client_ip);
+            return (StatusCode::UNAUTHORIZED, "Código inválido").into_response();
+        }
+    }
 
     let token = Uuid::new_v4().to_string();
     let now = Utc::now();
+    let expires_at = now + chrono::Duration::days(TOKEN_TTL_DAYS);
+    let device_id = Uuid::new_v4().to_string();
+    let device_name = payload.device_name.clone().unwrap_or_else(|| payload.username.clone());
 
     // Salve the new token in the database
-    let _ = db.execute(
-        "INSERT INTO tokens (token, username, ip, created_at) VALUES (?1, ?2, ?3, ?4)",
-        params![token, payload.username, ip, now.to_rfc3339()],
-    );
+    let new_token = NewToken {
+        token: token.clone(),
+        username: payload.username.clone(),
+        ip: client_ip.to_canonical().to_string(),
+        device_id: device_id.clone(),
+        device_name: device_name.clone(),
+        binding: payload.binding.as_str().to_string(),
+        created_at: now,
+        expires_at,
+    };
+    if let Err(e) = state.db(move |repo| repo.insert_token(&new_token)).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Error saving token: {e}")).into_response();
+    }
 
-    drop(db); // Release the lock before sending the message
+    state.auth_throttle.lock().await.record_success(client_ip);
 
-    // 🔔 Notificar via WebSocket
-    let msg = json!({
-        "token": token
-    }).to_string();
+    // 🔔 Notify desktop viewers; the token itself is only returned to the phone
+    let paired = ServerMessage::Paired { username: payload.username.clone(), device_id, device_name };
+    state.ws_state.lock().await.send_to_role(ClientRole::Desktop, &paired);
+
+    (StatusCode::OK, AxumJson(AuthResponse { token, expires_at: expires_at.to_rfc3339() })).into_response()
+}
 
-    let clients = state.ws_state.lock().await;
-    for tx in clients.iter() {
-        let _ = tx.send(Message::Text(msg.clone()));
+/// Replaces the caller's token with a new one, extending the session.
+///
+/// # Flow
+/// - Generates a new UUID token with a fresh expiry date.
+/// - Replaces the old token in the database, keeping device ID, name and IP.
+/// - Moves open WebSocket connections of the old token to the new one.
+/// - Returns the new token and expiry in JSON.
+pub async fn refresh_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+) -> impl IntoResponse {
+    let token = Uuid::new_v4().to_string();
+    let now = Utc::now();
+    let expires_at = now + chrono::Duration::days(TOKEN_TTL_DAYS);
+
+    let (old, new) = (user.token.clone(), token.clone());
+    if let Err(e) = state.db(move |repo| repo.replace_token(&old, &new, expires_at, now)).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Error refreshing token: {e}")).into_response();
+    }
+
+    state.ws_state.lock().await.replace_token(&user.token, &token);
+
+    (StatusCode::OK, AxumJson(AuthResponse { token, expires_at: expires_at.to_rfc3339() })).into_response()
+}
+
+/// Periodically deletes expired pairing codes and forgets old failed attempts.
+///
+/// Runs forever; spawn it once at startup.
+pub async fn start_code_sweeper(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let cutoff = Utc::now() - chrono::Duration::seconds(CODE_TTL_SECS as i64);
+        if let Err(e) = state.db(move |repo| repo.delete_auth_codes_before(cutoff)).await {
+            eprintln!("Error purging auth codes: {}", e);
+        }
+
+        state.auth_throttle.lock().await.purge();
     }
+}
 
-    (StatusCode::OK, AxumJson(AuthResponse { token })).into_response()
+/// Returns true if `username` can safely become a directory under the upload folder:
+/// 1 to `MAX_USERNAME_LEN` ASCII letters, digits, `.`, `_` or `-`, excluding `.`, `..` and the blob store directory.
+fn valid_username(username: &str) -> bool {
+    !username.is_empty()
+        && username.len() <= MAX_USERNAME_LEN
+        && username.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        && username != "."
+        && username != ".."
+        && username != BLOB_DIR
+}
+
+/// Returns true if a code created at `created_at` is older than `CODE_TTL_SECS`. Codes without a valid date are expired.
+fn is_expired(created_at: Option<DateTime<Utc>>) -> bool {
+    match created_at {
+        Some(created) => Utc::now().signed_duration_since(created).num_seconds() >= CODE_TTL_SECS as i64,
+        None => true,
+    }
 }
 
 /// Generates an alphanumeric code of `len` characters.
@@ -0,0 +1,130 @@
+//! # Files Handler
+//!
+//! This module serves the original files received from phones back to their owners and to the desktop viewer, and
+//! lets owners remove files from their library.
+//!
+//! ## Endpoints
+//! - **download_file_handler** (`GET /api/files/:hash`): Streams a stored file, with `Range` and conditional
+//!   request support, so viewers can preview originals and phones can resume interrupted downloads. Accepts a
+//!   session token or the desktop key (see `auth::Caller`).
+//! - **delete_file_handler** (`DELETE /api/files/:hash`): Removes a file from the caller's library. The blob
+//!   and thumbnails of the content are deleted once no user owns it any more.
+//!
+//! ## Notes
+//! - The `ETag` of a file is its quoted SHA-256 hash, which never changes for a given content.
+//! - Files outside the caller's library are reported as not found, even if another user owns the same content. The
+//!   desktop viewer sees every user's files.
+//! - Files that were only announced with a thumbnail cannot be downloaded.
+
+use axum::{
+    body::Body,
+    extract::{Path, Request, State},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use cube_db::Repository;
+use std::sync::Arc;
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
+
+use crate::auth::{AuthUser, Caller};
+use crate::state::AppState;
+use crate::utils::{blob::blob_path, etag::{etag, etag_listed, if_range_matches}, file::discard_file, hash::is_valid_hash, thumbnail::remove_thumbnails};
+
+/// Streams an original file to a user who uploaded it, from that user's copy, or to the desktop viewer.
+///
+/// # Flow
+/// - Rejects hashes that are not 64 lowercase hex characters with `400 Bad Request`.
+/// - Looks up the caller's path; unknown files, files not received yet and files of other users give `404 Not Found`.
+///   The desktop viewer gets the first stored copy, whoever owns it.
+/// - Answers `304 Not Modified` when `If-None-Match` lists the file's `ETag`.
+/// - Ignores `Range` when `If-Range` does not name the file's `ETag`.
+/// - Streams the file, or a single byte range with `206 Partial Content`; unsatisfiable ranges give
+///   `416 Range Not Satisfiable`.
+pub async fn download_file_handler(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+    Path(hash): Path<String>,
+    mut request: Request,
+) -> Response {
+    if !is_valid_hash(&hash) {
+        return (StatusCode::BAD_REQUEST, "Invalid hash").into_response();
+    }
+
+    let lookup = hash.clone();
+    let found = state.db(move |repo| match caller {
+        Caller::User(AuthUser { username, .. }) => repo.find_owned_upload(&username, &lookup),
+        Caller::Desktop => repo.find_upload(&lookup),
+    });
+    let upload = match found.await {
+        Ok(upload) => upload,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Error looking up file: {e}")).into_response(),
+    };
+    let Some(path) = upload.and_then(|u| u.path) else {
+        return (StatusCode::NOT_FOUND, "File not found").into_response();
+    };
+
+    let etag = etag(&hash);
+    if request.headers().get(header::IF_NONE_MATCH).is_some_and(|value| etag_listed(value, &etag)) {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+    }
+    if request.headers().get(header::IF_RANGE).is_some_and(|value| !if_range_matches(value, &etag)) {
+        request.headers_mut().remove(header::RANGE);
+    }
+
+    // ServeFile handles Range, HEAD and the content type
+    let mut response = ServeFile::new(&path).oneshot(request).await.unwrap_or_else(|e| match e {}).map(Body::new);
+
+    if response.status().is_success() {
+        let headers = response.headers_mut();
+        if let Ok(value) = HeaderValue::from_str(&etag) {
+            headers.insert(header::ETAG, value);
+        }
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("private"));
+    }
+
+    response
+}
+
+/// Removes a file from the caller's library.
+///
+/// # Flow
+/// - Rejects hashes that are not 64 lowercase hex characters with `400 Bad Request`.
+/// - Removes the file from the caller's library in the database; files they do not own give `404 Not Found`.
+/// - Deletes the caller's copy from disk. Copies of other users are reflinks or hard links to the blob, or separate
+///   files, so they are not affected.
+/// - Deletes the blob and the thumbnails once no user owns the file any more.
+pub async fn delete_file_handler(
+    State(state): State<Arc<AppState>>,
+    AuthUser { username, .. }: AuthUser,
+    Path(hash): Path<String>,
+) -> Response {
+    if !is_valid_hash(&hash) {
+        return (StatusCode::BAD_REQUEST, "Invalid hash").into_response();
+    }
+
+    let lookup = hash.clone();
+    let owner = username.clone();
+    let removed = match state.db(move |repo| repo.remove_owned_file(&owner, &lookup)).await {
+        Ok(Some(removed)) => removed,
+        Ok(None) => return (StatusCode::NOT_FOUND, "File not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Error deleting file: {e}")).into_response(),
+    };
+
+    if let Some(path) = &removed.path {
+        discard_file(path).await;
+    }
+    if removed.orphaned {
+        // Blobs stored before their path was recorded are in the current upload directory
+        let blob = match removed.blob {
+            Some(blob) => blob,
+            None => blob_path(std::path::Path::new(state.upload_dir.read().await.as_str()), &hash),
+        };
+        discard_file(&blob).await;
+        let thumb_hash = hash.clone();
+        let _ = tokio::task::spawn_blocking(move || remove_thumbnails(std::path::Path::new(".thumbs"), &thumb_hash)).await;
+    }
+
+    println!("🗑️ {} deleted {}", username, hash);
+    (StatusCode::OK, "File deleted").into_response()
+}
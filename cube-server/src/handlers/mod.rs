@@ -1,4 +1,8 @@
 pub mod auth;
 pub mod upload_raw;
+pub mod upload_session;
 pub mod config;
-pub mod thumbs;
\ No newline at end of file
+pub mod devices;
+pub mod thumbs;
+pub mod files;
+pub mod storage;
@@ -0,0 +1,175 @@
+//! # Storage Check Handler
+//!
+//! This module keeps the blob store and the per-user folder tree in sync (see `utils::blob`).
+//!
+//! Received files are stored once under `blobs/ab/cdef…` in the upload directory they were received in, and each
+//! owner gets a reflink, hard link or copy at `user/year/month/filename`. If either side is deleted or lost,
+//! `fsck` rebuilds it from the other.
+//!
+//! ## Endpoints
+//! - **fsck_handler** (`POST /admin/fsck`): Checks every stored copy and returns a `FsckReport`. Only accepts
+//!   the desktop app of the server machine (see `auth::require_desktop`).
+//!
+//! ## Startup
+//! - **start_fsck**: Runs `fsck` once in the background, which also moves files stored before the blob store
+//!   into it.
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use cube_db::Repository;
+use serde::Serialize;
+use std::{path::PathBuf, sync::Arc};
+
+use crate::state::AppState;
+use crate::utils::{blob::{blob_path, check_copy, CopyCheck}, hash::is_valid_hash};
+
+/// Result of `fsck`.
+///
+/// - `checked`: Number of stored copies checked.
+/// - `restored`: Copies materialized again from their blob.
+/// - `rebuilt`: Blobs rebuilt from a copy.
+/// - `corrupted`: Copies whose blob is missing and whose content no longer matches the hash.
+/// - `lost`: Copies whose blob and file are both missing.
+/// - `failed`: Copies that could not be checked or repaired, with the error.
+#[derive(Serialize, Default)]
+pub struct FsckReport {
+    pub checked: usize,
+    pub restored: usize,
+    pub rebuilt: usize,
+    pub corrupted: Vec<PathBuf>,
+    pub lost: Vec<PathBuf>,
+    pub failed: Vec<String>,
+}
+
+impl FsckReport {
+    /// True if every copy was healthy and nothing was repaired.
+    pub fn is_clean(&self) -> bool {
+        self.restored + self.rebuilt + self.corrupted.len() + self.lost.len() + self.failed.len() == 0
+    }
+}
+
+/// Checks the blob store and the per-user folder tree, and rebuilds whichever side is missing.
+pub async fn fsck_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    match fsck(&state).await {
+        Ok(report) => (StatusCode::OK, Json(report)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Error checking storage: {e}")).into_response(),
+    }
+}
+
+/// Runs `fsck` once; spawn it at startup.
+pub async fn start_fsck(state: Arc<AppState>) {
+    match fsck(&state).await {
+        Ok(report) if report.is_clean() => {}
+        Ok(report) => println!(
+            "🧰 Storage check: {} copies, {} restored, {} blobs rebuilt, {} corrupted, {} lost, {} failed",
+            report.checked,
+            report.restored,
+            report.rebuilt,
+            report.corrupted.len(),
+            report.lost.len(),
+            report.failed.len(),
+        ),
+        Err(e) => eprintln!("Error checking storage: {e}"),
+    }
+}
+
+/// Checks every user's copy of every received file against its blob.
+///
+/// # Flow
+/// - Each blob is looked up at its recorded path, so blobs stored before the upload directory changed are still
+///   found. Files stored before blob paths were recorded use the blob store of the current upload directory, and
+///   get that path recorded once the blob is present.
+/// - Missing copies are materialized again from their blob, at the path recorded in the database.
+/// - Missing blobs are rebuilt from a copy whose content still matches the hash.
+/// - Copies that cannot be repaired are listed in the report.
+pub async fn fsck(state: &AppState) -> cube_db::Result<FsckReport> {
+    let base = PathBuf::from(state.upload_dir.read().await.as_str());
+    let copies = state.db(|repo| repo.list_stored_copies()).await?;
+
+    let mut report = FsckReport::default();
+    for copy in copies {
+        let Some(path) = copy.path.map(PathBuf::from) else { continue };
+        report.checked += 1;
+
+        let (blob, recorded) = match copy.blob {
+            Some(blob) => (PathBuf::from(blob), true),
+            None if is_valid_hash(&copy.hash) => (blob_path(&base, &copy.hash), false),
+            None => {
+                report.corrupted.push(path);
+                continue;
+            }
+        };
+
+        let checked = check_copy(&blob, &copy.hash, &path).await;
+        match &checked {
+            Ok(CopyCheck::Healthy) => {}
+            Ok(CopyCheck::Restored) => report.restored += 1,
+            Ok(CopyCheck::Rebuilt) => report.rebuilt += 1,
+            Ok(CopyCheck::Corrupted) => report.corrupted.push(path),
+            Ok(CopyCheck::Lost) => report.lost.push(path),
+            Err(e) => report.failed.push(format!("{}: {e}", path.to_string_lossy())),
+        }
+
+        if !recorded && matches!(checked, Ok(CopyCheck::Healthy | CopyCheck::Restored | CopyCheck::Rebuilt)) {
+            let hash = copy.hash.clone();
+            state.db(move |repo| repo.set_blob(&hash, &blob)).await?;
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{file::hash_file, path::{tests::TempDir, CollisionPolicy}, throttle::AuthThrottle};
+    use crate::ws::registry::Registry;
+    use chrono::Utc;
+    use cube_db::{open_pool, StoredFile};
+    use tokio::sync::{Mutex, RwLock};
+
+    fn state(dir: &TempDir) -> AppState {
+        AppState {
+            upload_dir: Arc::new(RwLock::new(dir.0.to_string_lossy().to_string())),
+            collision_policy: Arc::new(RwLock::new(CollisionPolicy::default())),
+            db_pool: open_pool(dir.0.join("uploads.db"), 2).unwrap(),
+            ws_state: Arc::new(Mutex::new(Registry::default())),
+            auth_throttle: Arc::new(Mutex::new(AuthThrottle::default())),
+            desktop_key: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn fsck_records_the_blob_of_files_stored_before_blob_paths() {
+        let dir = TempDir::new();
+        let state = state(&dir);
+        let copy = dir.0.join("ana/2024/03/IMG_0001.CR2");
+        tokio::fs::create_dir_all(copy.parent().unwrap()).await.unwrap();
+        tokio::fs::write(&copy, "a").await.unwrap();
+        let hash = hash_file(&copy).await.unwrap();
+
+        let file = StoredFile {
+            hash: hash.clone(),
+            filename: "IMG_0001.CR2".to_string(),
+            size: 1,
+            owner: "ana".to_string(),
+            path: copy.clone(),
+            modified_at: None,
+            uploaded_at: Utc::now(),
+            blob: PathBuf::new(),
+        };
+        state.db(move |repo| repo.save_stored_file(&file)).await.unwrap();
+        // Rows stored before `0006_blob_paths` have no blob
+        state.db_pool.get().unwrap().execute("UPDATE uploads SET blob = NULL", []).unwrap();
+
+        let report = fsck(&state).await.unwrap();
+        assert_eq!((report.checked, report.rebuilt), (1, 1));
+
+        let blob = blob_path(&dir.0, &hash);
+        assert_eq!(tokio::fs::read_to_string(&blob).await.unwrap(), "a");
+        let lookup = hash.clone();
+        let upload = state.db(move |repo| repo.find_upload(&lookup)).await.unwrap().unwrap();
+        assert_eq!(upload.blob.map(PathBuf::from), Some(blob));
+
+        assert!(fsck(&state).await.unwrap().is_clean());
+    }
+}
@@ -3,35 +3,119 @@
 //! This module provides endpoints for uploading and listing photo thumbnails.
 //!
 //! ## Endpoints
-//! - **upload_thumbs_handler**: Receives a list of thumbnails in base64, saves them to disk, and updates the database.
-//! - **list_thumbs_handler**: Lists all thumbnails available in the `.thumbs` directory, returning their metadata.
+//! - **upload_thumbs_handler**: Receives a list of thumbnails in base64, validates and saves them to disk, updates the
+//!   database, and reports which ones were accepted.
+//! - **upload_thumbs_multipart_handler**: Same as `upload_thumbs_handler`, with the thumbnails streamed as binary
+//!   `multipart/form-data` parts.
+//! - **list_thumbs_handler**: Lists the caller's files that have a thumbnail, or every user's for the desktop
+//!   viewer, one page at a time, with filters and sorting.
+//! - **serve_thumb_handler** (`GET /thumbs/:file`): Serves a thumbnail file to its owner, with cache headers.
+//!
+//! ## Startup
+//! - **index_existing_thumbs**: Records the thumbnails already in `.thumbs` for files stored before the database
+//!   tracked them.
 //!
 //! ## Structures
-//! - `ThumbPayload`: Payload for uploading a thumbnail (id, name, size, hash, status, thumb_base64, modified_at).
-//! - `Photo`: Metadata returned when listing thumbnails (id, url, name, size, status).
+//! - `ThumbMetadata`: Metadata of an uploaded thumbnail (id, name, size, hash, status, modified_at).
+//! - `ThumbPayload`: JSON payload for uploading a thumbnail (`ThumbMetadata` and thumb_base64).
+//! - `ThumbResult`: Per-item result of an upload (id, hash, status, reason).
+//! - `ListParams`: Query parameters of `list_thumbs_handler` (cursor, limit, filters and sort).
+//! - `Photo`: Metadata returned when listing thumbnails (id, url, name, size, status, and EXIF metadata when
+//!   the RAW file was received).
+//! - `PhotoPage`: A page of `Photo`s, with the number of matching files and the cursor of the next page.
 
-use axum::{extract::{State, Json}, response::IntoResponse};
+use axum::{
+    body::Body,
+    extract::{multipart::{Field, MultipartError}, Multipart, Path as UrlPath, Query, Request, State, Json},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
 use serde::{Deserialize, Serialize};
 use base64::{engine::general_purpose, Engine};
-use std::{fs, path::PathBuf, sync::Arc};
-use chrono::DateTime;
+use std::collections::HashSet;
+use std::sync::Arc;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime};
+use cube_db::{NewUpload, PhotoMetadata, Repository, UploadCursor, UploadQuery, UploadSort};
 use std::path::Path;
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
 
 
+use crate::auth::{AuthUser, Caller};
 use crate::state::AppState;
+use crate::utils::{
+    etag::{etag, etag_listed},
+    hash::is_valid_hash,
+    thumbnail::{find_thumb, save_uploaded_thumbnail, thumb_hash, MAX_UPLOADED_THUMB_BYTES},
+};
 
-/// Payload for uploading a thumbnail.
+/// Metadata sent with each uploaded thumbnail.
 #[derive(Deserialize)]
-pub struct ThumbPayload {
+#[allow(dead_code)]
+pub struct ThumbMetadata {
     id: String,
     name: String,
     size: String,
     hash: String,
     status: String,
-    thumb_base64: String,
     modified_at: Option<DateTime<chrono::Utc>>,
 }
 
+/// Payload for uploading a thumbnail as JSON: its metadata and the base64-encoded image.
+#[derive(Deserialize)]
+pub struct ThumbPayload {
+    #[serde(flatten)]
+    metadata: ThumbMetadata,
+    thumb_base64: String,
+}
+
+/// Reason given for thumbnails of files that another user already has.
+const CLAIMED: &str = "already in another user's library";
+
+/// Largest accepted `metadata` part of a multipart upload, in bytes.
+const MAX_METADATA_BYTES: usize = 16 * 1024;
+
+/// Largest accepted multipart upload, in bytes. Enforced by the router (see `main`).
+pub const MAX_MULTIPART_BYTES: usize = 64 * 1024 * 1024;
+
+/// Most parts accepted in one multipart upload: a `metadata` and a `thumb` part for each of 256 thumbnails.
+const MAX_MULTIPART_PARTS: usize = 512;
+
+/// Number of files per page when `limit` is not given.
+const DEFAULT_PAGE_SIZE: u32 = 100;
+
+/// Largest accepted `limit`.
+const MAX_PAGE_SIZE: u32 = 500;
+
+/// `Cache-Control` of served thumbnails. Their names are derived from the file's hash, so a URL always shows the
+/// same photo and clients never need to ask again.
+const THUMB_CACHE_CONTROL: &str = "private, max-age=31536000, immutable";
+
+/// Query parameters of `/api/thumbs/list`; every one is optional.
+///
+/// - `cursor`: `next_cursor` of the previous page.
+/// - `limit`: Files per page, up to `MAX_PAGE_SIZE`.
+/// - `from`, `to`: Inclusive capture date range, as `YYYY-MM-DD` or `YYYY-MM-DDTHH:MM:SS`.
+/// - `status`: `success` for files the caller uploaded, `uploading` for files only announced with a thumbnail.
+/// - `camera`: EXIF camera model, case-insensitive.
+/// - `type`: File extension, e.g. `cr3`.
+/// - `sort`: `capture_date` (default), `upload_date`, `name` or `size`.
+/// - `order`: `asc` or `desc`; newest or largest first by default, `asc` when sorting by name.
+#[derive(Deserialize)]
+pub struct ListParams {
+    cursor: Option<String>,
+    limit: Option<u32>,
+    from: Option<String>,
+    to: Option<String>,
+    status: Option<String>,
+    camera: Option<String>,
+    #[serde(rename = "type")]
+    file_type: Option<String>,
+    #[serde(default)]
+    sort: UploadSort,
+    order: Option<String>,
+}
+
 /// Metadata for a photo thumbnail.
 #[derive(Serialize)]
 pub struct Photo {
@@ -40,91 +124,465 @@ pub struct Photo {
     pub name: String,
     pub size: String,
     pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<PhotoMetadata>,
 }
 
-/// Receives a list of thumbnails, saves them to disk, and updates the database.
+/// A page of `/api/thumbs/list`.
+///
+/// - `total`: Number of files matching the filters, across every page.
+/// - `next_cursor`: Pass as `cursor` to get the next page; `null` on the last page.
+#[derive(Serialize)]
+pub struct PhotoPage {
+    pub items: Vec<Photo>,
+    pub total: u64,
+    pub next_cursor: Option<String>,
+}
+
+/// Outcome of one uploaded thumbnail.
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ThumbStatus {
+    Accepted,
+    Rejected,
+}
+
+/// Result returned for each item of an `/api/thumbs` request.
+///
+/// - `reason`: Why the thumbnail was rejected; absent when accepted.
+#[derive(Serialize)]
+pub struct ThumbResult {
+    pub id: String,
+    pub hash: String,
+    pub status: ThumbStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl ThumbResult {
+    /// Rejects a part that cannot be tied to an item, so the result has no id or hash.
+    fn rejected(reason: impl Into<String>) -> Self {
+        ThumbResult { id: String::new(), hash: String::new(), status: ThumbStatus::Rejected, reason: Some(reason.into()) }
+    }
+
+    /// Rejects an item, keeping its id and hash so the client can match the result.
+    fn rejected_item(metadata: ThumbMetadata, reason: impl Into<String>) -> Self {
+        ThumbResult { id: metadata.id, hash: metadata.hash, status: ThumbStatus::Rejected, reason: Some(reason.into()) }
+    }
+}
+
+/// Receives a list of base64-encoded thumbnails, validates them, saves them to disk, and updates the database.
 ///
 /// # Flow
-/// - Ensures the `.thumbs` directory exists.
-/// - Decodes each thumbnail from base64 and saves it as a JPEG file.
-/// - Inserts or updates the thumbnail metadata in the database.
-/// - Returns a success message.
+/// - Rejects items whose data is not valid base64, and files another user already has (see `claimed_by_others`).
+/// - Validates and stores each thumbnail (see `store_thumb`).
+/// - Records the accepted thumbnails (see `announce_thumbs`).
+/// - Returns one `ThumbResult` per item, in request order.
 pub async fn upload_thumbs_handler(
     State(state): State<Arc<AppState>>,
+    user: AuthUser,
     Json(payload): Json<Vec<ThumbPayload>>,
+) -> Response {
+    let hashes = payload.iter().map(|item| item.metadata.hash.clone()).collect();
+    let claimed = match claimed_by_others(&state, &user.username, hashes).await {
+        Ok(claimed) => claimed,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to look up files: {e}")).into_response(),
+    };
+
+    // Decoding and re-encoding images is CPU-bound
+    let processed = tokio::task::spawn_blocking(move || {
+        let mut results = Vec::with_capacity(payload.len());
+        let mut uploads = Vec::with_capacity(payload.len());
+
+        for ThumbPayload { metadata, thumb_base64 } in payload {
+            if claimed.contains(&metadata.hash) {
+                results.push(ThumbResult::rejected_item(metadata, CLAIMED));
+                continue;
+            }
+            // Base64 takes 4 characters for every 3 bytes
+            if thumb_base64.len() / 4 * 3 > MAX_UPLOADED_THUMB_BYTES {
+                results.push(ThumbResult::rejected_item(metadata, too_large()));
+                continue;
+            }
+
+            let (result, upload) = match general_purpose::STANDARD.decode(&thumb_base64) {
+                Ok(bytes) => store_thumb(metadata, &bytes, &user),
+                Err(_) => (ThumbResult::rejected_item(metadata, "invalid base64"), None),
+            };
+            results.push(result);
+            uploads.extend(upload);
+        }
+
+        (results, uploads)
+    })
+    .await;
+
+    let (results, uploads) = match processed {
+        Ok(processed) => processed,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    };
+
+    Json(announce_thumbs(&state, results, uploads).await).into_response()
+}
+
+/// Receives thumbnails as a `multipart/form-data` stream, without base64 or buffering the whole batch.
+///
+/// Each thumbnail is sent as a `metadata` part with the same JSON fields as `ThumbPayload` (without
+/// `thumb_base64`), followed by a `thumb` part with the JPEG or PNG bytes. Other parts are ignored.
+///
+/// # Flow
+/// - Reads the parts one at a time; a `metadata` part is read up to `MAX_METADATA_BYTES` and a `thumb` part up to
+///   `MAX_UPLOADED_THUMB_BYTES`.
+/// - Rejects metadata that is too large or not valid JSON, `thumb` parts without metadata, metadata without a
+///   `thumb` part and files another user already has (see `claimed_by_others`).
+/// - Validates and stores each thumbnail (see `store_thumb`) and records the accepted ones (see `announce_thumbs`).
+/// - Returns one `ThumbResult` per item, in request order, or `400 Bad Request` if the body is not valid multipart.
+///   Bodies larger than `MAX_MULTIPART_BYTES` or with more than `MAX_MULTIPART_PARTS` parts give
+///   `413 Payload Too Large`.
+pub async fn upload_thumbs_multipart_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+    mut multipart: Multipart,
 ) -> impl IntoResponse {
-    let thumb_dir = PathBuf::from(".thumbs");
-    if !thumb_dir.exists() {
-        if let Err(e) = fs::create_dir_all(&thumb_dir) {
-            return format!("Erro ao criar diretório .thumbs: {e}");
+    let mut results = Vec::new();
+    let mut uploads = Vec::new();
+    let mut pending: Option<ThumbMetadata> = None;
+    let mut parts = 0;
+
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return (e.status(), format!("Invalid multipart body: {e}")).into_response(),
+        };
+        parts += 1;
+        if parts > MAX_MULTIPART_PARTS {
+            return (StatusCode::PAYLOAD_TOO_LARGE, format!("More than {MAX_MULTIPART_PARTS} parts")).into_response();
+        }
+
+        match field.name() {
+            Some("metadata") => {
+                if let Some(metadata) = pending.take() {
+                    results.push(ThumbResult::rejected_item(metadata, "missing thumb part"));
+                }
+
+                let bytes = match read_limited(field, MAX_METADATA_BYTES).await {
+                    Ok(Some(bytes)) => bytes,
+                    Ok(None) => {
+                        results.push(ThumbResult::rejected(format!("metadata is larger than {MAX_METADATA_BYTES} bytes")));
+                        continue;
+                    }
+                    Err(e) => return (e.status(), format!("Invalid multipart body: {e}")).into_response(),
+                };
+                match serde_json::from_slice::<ThumbMetadata>(&bytes) {
+                    Ok(metadata) => pending = Some(metadata),
+                    Err(e) => results.push(ThumbResult::rejected(format!("invalid metadata: {e}"))),
+                }
+            }
+            Some("thumb") => {
+                let Some(metadata) = pending.take() else {
+                    results.push(ThumbResult::rejected("thumb part without metadata"));
+                    continue;
+                };
+
+                let bytes = match read_limited(field, MAX_UPLOADED_THUMB_BYTES).await {
+                    Ok(Some(bytes)) => bytes,
+                    Ok(None) => {
+                        results.push(ThumbResult::rejected_item(metadata, too_large()));
+                        continue;
+                    }
+                    Err(e) => return (e.status(), format!("Invalid multipart body: {e}")).into_response(),
+                };
+
+                match claimed_by_others(&state, &user.username, vec![metadata.hash.clone()]).await {
+                    Ok(claimed) if claimed.is_empty() => {}
+                    Ok(_) => {
+                        results.push(ThumbResult::rejected_item(metadata, CLAIMED));
+                        continue;
+                    }
+                    Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to look up files: {e}")).into_response(),
+                }
+
+                let user = user.clone();
+                let (result, upload) = match tokio::task::spawn_blocking(move || store_thumb(metadata, &bytes, &user)).await {
+                    Ok(processed) => processed,
+                    Err(e) => std::panic::resume_unwind(e.into_panic()),
+                };
+                results.push(result);
+                uploads.extend(upload);
+            }
+            _ => {}
         }
     }
 
-    let mut db = state.db.lock().await;
-    let tx = db.transaction().expect("Failed to create transaction");
+    if let Some(metadata) = pending {
+        results.push(ThumbResult::rejected_item(metadata, "missing thumb part"));
+    }
+
+    Json(announce_thumbs(&state, results, uploads).await).into_response()
+}
 
-    for item in payload {
-        let file_path = thumb_dir.join(format!("{}.jpg", item.hash));
+/// Validates and stores one uploaded thumbnail.
+///
+/// Runs on a blocking thread. Returns the item's result and, if it was accepted, the row to announce.
+fn store_thumb(metadata: ThumbMetadata, bytes: &[u8], user: &AuthUser) -> (ThumbResult, Option<NewUpload>) {
+    if !is_valid_hash(&metadata.hash) {
+        return (ThumbResult::rejected_item(metadata, "invalid hash"), None);
+    }
 
-        // Save thumbnail to disk
-        if let Ok(bytes) = general_purpose::STANDARD.decode(&item.thumb_base64) {
-            if let Err(e) = fs::write(&file_path, bytes) {
-                eprintln!("Erro ao salvar thumb {}: {e}", file_path.display());
-                continue;
+    let thumb = match save_uploaded_thumbnail(bytes, Path::new(".thumbs"), &metadata.hash) {
+        Ok(path) => path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+        Err(reason) => {
+            eprintln!("Thumb {} rejeitada: {reason}", metadata.hash);
+            return (ThumbResult::rejected_item(metadata, reason), None);
+        }
+    };
+
+    let upload = NewUpload {
+        hash: metadata.hash.clone(),
+        filename: metadata.name,
+        size: metadata.size.trim().parse().ok(),
+        device_id: Some(user.device_id.clone()),
+        owner: Some(user.username.clone()),
+        modified_at: metadata.modified_at,
+        thumb: Some(thumb),
+    };
+    let result = ThumbResult { id: metadata.id, hash: metadata.hash, status: ThumbStatus::Accepted, reason: None };
+    (result, Some(upload))
+}
+
+/// Returns the hashes among `hashes` that a user other than `username` already has in their library.
+///
+/// Their thumbnails are neither replaced nor announced, so a user cannot claim someone else's file, or change
+/// the photo its owners see, by sending a thumbnail with its hash.
+async fn claimed_by_others(state: &AppState, username: &str, hashes: Vec<String>) -> cube_db::Result<HashSet<String>> {
+    let username = username.to_string();
+    state
+        .db(move |repo| {
+            let mut claimed = HashSet::new();
+            for hash in hashes {
+                if repo.file_claimed_by_other(&hash, &username)? {
+                    claimed.insert(hash);
+                }
             }
+            Ok(claimed)
+        })
+        .await
+}
+
+/// Inserts or updates the accepted thumbnails in the database, along with the sending device, so `copy_files`
+/// requests can be routed to it. Rejected items are not recorded.
+///
+/// If the database write fails, every accepted item is reported as rejected.
+async fn announce_thumbs(state: &AppState, mut results: Vec<ThumbResult>, uploads: Vec<NewUpload>) -> Vec<ThumbResult> {
+    if let Err(e) = state.db(move |repo| repo.announce_uploads(&uploads)).await {
+        eprintln!("Erro ao inserir thumbs no DB: {e}");
+        for result in results.iter_mut().filter(|r| r.status == ThumbStatus::Accepted) {
+            result.status = ThumbStatus::Rejected;
+            result.reason = Some("could not save to the database".to_string());
         }
+    }
+
+    results
+}
 
-        // Insert on database
-        tx.execute(
-            "INSERT OR REPLACE INTO uploads (hash, filename, size) VALUES (?1, ?2, ?3)",
-            [&item.hash, &item.name, &item.size],
-        ).expect("Failed to insert or update file in database");
+/// Reads a multipart field into memory; `None` if it is larger than `limit` bytes.
+async fn read_limited(mut field: Field<'_>, limit: usize) -> Result<Option<Vec<u8>>, MultipartError> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = field.chunk().await? {
+        if bytes.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+        bytes.extend_from_slice(&chunk);
     }
+    Ok(Some(bytes))
+}
 
-    tx.commit().expect("Failed to commit transaction");
-    "Thumbs recebidos e processados com sucesso".to_string()
+fn too_large() -> String {
+    format!("thumbnail is larger than {MAX_UPLOADED_THUMB_BYTES} bytes")
 }
 
-/// Lists all thumbnails available in the `.thumbs` directory.
+/// Lists one page of the caller's files that have a thumbnail: the ones they uploaded or announced. The desktop
+/// viewer gets every user's files (see `auth::Caller`).
 ///
 /// # Flow
-/// - Reads thumbnail metadata from the database.
-/// - Checks if the corresponding JPEG file exists in `.thumbs`.
-/// - Returns a list of `Photo` objects as JSON.
+/// - Builds the filters, sort and cursor from the query parameters; invalid values give `400 Bad Request`.
+/// - Queries the page and the number of matching files in the database, which records each file's thumbnail,
+///   so `.thumbs` is not read.
+/// - Returns a `PhotoPage` as JSON.
 pub async fn list_thumbs_handler(
     State(state): State<Arc<AppState>>,
-) -> Json<Vec<Photo>> {
-    let mut result = Vec::new();
-    let thumb_dir = Path::new(".thumbs");
-
-    let db = state.db.lock().await;
-    let mut stmt = db
-        .prepare("SELECT hash, filename, size FROM uploads")
-        .unwrap();
-
-    let rows = stmt
-        .query_map([], |row| {
-            let hash: String = row.get(0)?;
-            let filename: String = row.get(1)?;
-            let size: String = row.get(2)?;
-            Ok((hash, filename, size))
+    caller: Caller,
+    Query(params): Query<ListParams>,
+) -> Response {
+    let username = match caller {
+        Caller::User(user) => Some(user.username),
+        Caller::Desktop => None,
+    };
+    let query = match upload_query(username, params) {
+        Ok(query) => query,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    let page = match state.db(move |repo| repo.query_uploads(&query)).await {
+        Ok(page) => page,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to query uploads: {e}")).into_response(),
+    };
+
+    let items = page
+        .items
+        .into_iter()
+        .map(|item| Photo {
+            id: item.upload.hash,
+            url: format!("/thumbs/{}", item.upload.thumb.unwrap_or_default()),
+            name: item.upload.filename.unwrap_or_default(),
+            size: item.upload.size.map(|size| size.to_string()).unwrap_or_default(),
+            status: if item.upload.path.is_some() { "success" } else { "uploading" }.to_string(),
+            metadata: item.metadata,
         })
-        .expect("Failed to query uploads");
-
-    for row in rows.flatten() {
-        let (hash, filename, size) = row;
-        let path = thumb_dir.join(format!("{}.jpg", hash));
-
-        if path.exists() {
-            result.push(Photo {
-                id: hash.clone(),
-                url: format!("/thumbs/{}.jpg", hash),
-                name: filename,
-                size,
-                status: "uploading".to_string(),
-            });
+        .collect();
+
+    Json(PhotoPage { items, total: page.total, next_cursor: page.next_cursor.as_ref().map(encode_cursor) }).into_response()
+}
+
+/// Turns the query parameters of `/api/thumbs/list` into an `UploadQuery`, or explains which one is invalid.
+fn upload_query(username: Option<String>, params: ListParams) -> Result<UploadQuery, String> {
+    let stored = match params.status.as_deref() {
+        None => None,
+        Some("success") => Some(true),
+        Some("uploading") => Some(false),
+        Some(other) => return Err(format!("invalid status: {other}")),
+    };
+
+    let descending = match params.order.as_deref() {
+        None => params.sort != UploadSort::Name,
+        Some("asc") => false,
+        Some("desc") => true,
+        Some(other) => return Err(format!("invalid order: {other}")),
+    };
+
+    let extension = params.file_type.map(|t| t.trim_start_matches('.').to_string());
+    if extension.as_deref().is_some_and(|e| e.is_empty() || !e.chars().all(|c| c.is_ascii_alphanumeric())) {
+        return Err("invalid type".to_string());
+    }
+
+    Ok(UploadQuery {
+        username,
+        captured_from: params.from.as_deref().map(|d| parse_date_bound(d, NaiveTime::MIN)).transpose()?,
+        captured_to: params.to.as_deref().map(|d| parse_date_bound(d, NaiveTime::from_hms_opt(23, 59, 59).unwrap())).transpose()?,
+        stored,
+        camera_model: params.camera,
+        extension,
+        sort: params.sort,
+        descending,
+        after: params.cursor.as_deref().map(decode_cursor).transpose()?,
+        limit: params.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE),
+    })
+}
+
+/// Parses `YYYY-MM-DDTHH:MM:SS`, or `YYYY-MM-DD` at `time`.
+fn parse_date_bound(value: &str, time: NaiveTime) -> Result<NaiveDateTime, String> {
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S")
+        .or_else(|_| NaiveDate::parse_from_str(value, "%Y-%m-%d").map(|date| date.and_time(time)))
+        .map_err(|_| format!("invalid date: {value}"))
+}
+
+/// Cursors are opaque to clients: URL-safe base64 of the JSON `UploadCursor`.
+fn encode_cursor(cursor: &UploadCursor) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(serde_json::to_vec(cursor).unwrap_or_default())
+}
+
+fn decode_cursor(value: &str) -> Result<UploadCursor, String> {
+    general_purpose::URL_SAFE_NO_PAD
+        .decode(value)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .ok_or_else(|| "invalid cursor".to_string())
+}
+
+/// Serves a thumbnail from `.thumbs`.
+///
+/// # Flow
+/// - Only serves names written by `utils::thumbnail` (`<hash>.jpg`, `<hash>_1600.png`...); others give `404 Not Found`.
+/// - With a session token, only thumbnails of files in the caller's library are served; others give `404 Not Found`.
+///   There is no sharing between users yet, so "shared with the caller" is not checked; once shares exist, a
+///   thumbnail shared with the caller should be served here too.
+/// - With the desktop key, every thumbnail is served: the desktop viewer shows every user's photos, as on `/ws`.
+/// - Requests without either are rejected (see `auth::Caller`).
+/// - Thumbnails missing from `.thumbs`, such as those of deleted files, give `404 Not Found`, even to a client that
+///   has them cached.
+/// - Answers `304 Not Modified` when `If-None-Match` lists the thumbnail's `ETag`.
+/// - Serves the file with an immutable `Cache-Control` and an `ETag` derived from the hash.
+pub async fn serve_thumb_handler(
+    State(state): State<Arc<AppState>>,
+    caller: Caller,
+    UrlPath(file_name): UrlPath<String>,
+    request: Request,
+) -> Response {
+    let Some(hash) = thumb_hash(&file_name).map(str::to_string) else {
+        return (StatusCode::NOT_FOUND, "Thumbnail not found").into_response();
+    };
+
+    if let Caller::User(AuthUser { username, .. }) = caller {
+        let lookup = hash.clone();
+        match state.db(move |repo| repo.file_visible_to(&lookup, &username)).await {
+            Ok(true) => {}
+            Ok(false) => return (StatusCode::NOT_FOUND, "Thumbnail not found").into_response(),
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Error looking up thumbnail: {e}")).into_response(),
         }
     }
-    Json(result)
+
+    let path = Path::new(".thumbs").join(&file_name);
+    // A deleted thumbnail must not be confirmed as still valid in the client's cache
+    if !tokio::fs::metadata(&path).await.is_ok_and(|metadata| metadata.is_file()) {
+        return (StatusCode::NOT_FOUND, "Thumbnail not found").into_response();
+    }
+
+    // The size suffix is part of the tag, so each size of a thumbnail is cached separately
+    let etag = etag(file_name.rsplit_once('.').map_or(hash.as_str(), |(stem, _)| stem));
+    let cache_headers = [(header::ETAG, etag.clone()), (header::CACHE_CONTROL, THUMB_CACHE_CONTROL.to_string())];
+    if request.headers().get(header::IF_NONE_MATCH).is_some_and(|value| etag_listed(value, &etag)) {
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+
+    let mut response = ServeFile::new(path)
+        .oneshot(request)
+        .await
+        .unwrap_or_else(|e| match e {})
+        .map(Body::new);
+
+    if response.status().is_success() {
+        for (name, value) in cache_headers {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                response.headers_mut().insert(name, value);
+            }
+        }
+    }
+
+    response
 }
 
+/// Records the grid thumbnails found in `.thumbs` for files that have none in the database, such as files
+/// stored by earlier versions. Later thumbnails are recorded when they are written.
+///
+/// Runs once; spawn it at startup.
+pub async fn index_existing_thumbs(state: Arc<AppState>) {
+    let indexed = state
+        .db(|repo| {
+            let mut indexed = 0;
+            for hash in repo.uploads_without_thumb()? {
+                if let Some(path) = find_thumb(Path::new(".thumbs"), &hash) {
+                    repo.set_thumb(&hash, &path.file_name().unwrap_or_default().to_string_lossy())?;
+                    indexed += 1;
+                }
+            }
+            Ok(indexed)
+        })
+        .await;
+
+    match indexed {
+        Ok(0) => {}
+        Ok(indexed) => println!("🖼️ Indexed {indexed} existing thumbnails"),
+        Err(e) => eprintln!("Error indexing thumbnails: {e}"),
+    }
+}
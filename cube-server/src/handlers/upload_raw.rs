@@ -6,50 +6,57 @@
 //! - **upload_raw_handler**: Receives a file upload (with metadata in headers), saves it to disk, updates the database, and notifies connected WebSocket clients.
 //!
 //! ## Flow
-//! 1. Extracts username, filename, and modification date from HTTP headers.
-//! 2. Reads the file body and computes its hash.
-//! 3. Checks if the file (by hash) already exists in the database; if so, ignores the upload.
-//! 4. Determines the output path based on user and date, and saves the file.
-//! 5. Inserts the file metadata into the database.
-//! 6. Notifies all connected WebSocket clients about the new upload.
-//! 7. Returns a success message.
+//! 1. Takes the username from the caller's session token, and filename and modification date from HTTP headers.
+//! 2. Streams the file body to a temporary file in the upload directory, computing its hash on the way.
+//! 3. Checks if the user already uploaded a file with the same hash; if so, discards the temporary file.
+//! 4. Reads the file's EXIF metadata, moves the content into the blob store (`blobs/ab/cdef…`, see
+//!    `utils::blob`) unless another user already stored it, and reflinks, hard links or copies it at the output
+//!    path based on user and capture date (EXIF `DateTimeOriginal`, or `X-Modified-At`). A different file with
+//!    the same name is never overwritten: the configured `CollisionPolicy` picks another name.
+//! 5. Records the saved path, size, owner, capture time, upload time and EXIF metadata in the database.
+//! 6. Generates the file's thumbnails, so files uploaded without `/api/thumbs` still show in the grid. Content
+//!    already stored by another user keeps its thumbnails.
+//! 7. Notifies all connected WebSocket clients about the new upload, with its final path, and completes its
+//!    transfer jobs.
+//! 8. Returns a success message.
+//!
+//! Steps 3 to 7 live in [`store_upload`] so that resumable uploads (see `upload_session`) finish
+//! exactly like single-request uploads.
 
 use axum::{
-    body::{to_bytes, Body},
-    extract::{ws::Message, State},
-    http::HeaderMap,
+    body::Body,
+    extract::State,
+    http::{HeaderMap, StatusCode},
     response::IntoResponse,
 };
 use axum::debug_handler;
-use std::{sync::Arc};
+use std::{io, path::{Path, PathBuf}, sync::Arc};
 use chrono::{DateTime, Utc};
 use uuid::Uuid;
-use rusqlite::params;
+use cube_db::{Repository, StoredFile};
 
+use crate::auth::AuthUser;
 use crate::state::AppState;
-use crate::utils::{file::save_file, hash::compute_hash, path::get_output_path};
+use crate::ws::{protocol::ServerMessage, registry::ClientRole, transfers};
+use crate::utils::{blob::store_blob, exif::read_exif, file::{discard_file, stream_to_temp, TempUpload}, path::link_output_path, thumbnail::generate_thumbnails};
 
 /// Handles RAW file uploads.
 ///
 /// # Flow
-/// - Extracts metadata from headers.
-/// - Reads and saves the file.
-/// - Checks for duplicates by hash.
+/// - Extracts metadata from headers; the username comes from the session token.
+/// - Streams the body to a temporary file while hashing it.
+/// - Checks for duplicates by hash in the user's library.
+/// - Moves the file to its final path.
 /// - Updates the database.
 /// - Notifies WebSocket clients.
-/// - Returns a status message.
+/// - Returns a status message; failures to store the file give `500 Internal Server Error`.
 #[debug_handler]
 pub async fn upload_raw_handler(
     State(state): State<Arc<AppState>>,
+    AuthUser { username, .. }: AuthUser,
     headers: HeaderMap,
     body: Body,
 ) -> impl IntoResponse {
-    let username = headers
-        .get("X-Username")
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("default")
-        .to_string();
-
     let filename = headers
         .get("X-Filename")
         .and_then(|v| v.to_str().ok())
@@ -62,52 +69,158 @@ pub async fn upload_raw_handler(
         .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
         .map(|dt| dt.with_timezone(&Utc));
 
-    let data = match to_bytes(body, usize::MAX).await {
-        Ok(bytes) => bytes.to_vec(),
-        Err(_) => return "Error reading file".to_string(),
+    let dir = state.upload_dir.read().await.clone();
+
+    let temp = match stream_to_temp(&PathBuf::from(&dir), body.into_data_stream()).await {
+        Ok(temp) => temp,
+        Err(_) => return "Error reading file".into_response(),
     };
 
-    let hash = compute_hash(&data);
+    match store_upload(&state, temp, &dir, &username, &filename, modified_at).await {
+        Ok(StoreOutcome::Stored(_)) => "Upload Ended!".into_response(),
+        Ok(StoreOutcome::Duplicate) => "The file already exists".into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Error saving file: {e}")).into_response(),
+    }
+}
+
+/// Result of [`store_upload`].
+pub enum StoreOutcome {
+    /// The file was moved to the given path.
+    Stored(PathBuf),
+    /// The user already uploaded a file with the same hash; the temporary file was discarded.
+    Duplicate,
+}
 
-    let db = state.db.lock().await;
-    let exists: bool = db
-        .query_row(
-            "SELECT EXISTS(SELECT 1 FROM uploads WHERE hash = ?1)",
-            [hash.clone()],
-            |row| row.get(0),
-        )
+/// Moves a fully received temporary file into the upload directory.
+///
+/// # Flow
+/// - Checks for duplicates by hash in the user's library; duplicates are discarded. Files only announced with a
+///   thumbnail are not duplicates.
+/// - Reads the EXIF metadata of the file.
+/// - Atomically moves the file into the blob store, or discards it if the content is already stored, at its recorded
+///   blob path or in the current upload directory.
+/// - Links the blob into the user's folder, by date; the EXIF capture date is preferred over `modified_at`. Taken
+///   names are resolved with the configured `CollisionPolicy`, and existing files are never replaced (see
+///   `utils::path::link_output_path`). If linking fails, a blob stored by this upload is removed again.
+/// - Records the file in the database: path, size, owner, capture and upload times, and its EXIF metadata. If the
+///   file cannot be recorded, the copy and a blob stored by this upload are removed and the error is returned, so
+///   the client retries; a failure to record the metadata is only logged.
+/// - Generates its thumbnails in `.thumbs` (see `utils::thumbnail`) and records the grid thumbnail, unless the
+///   content was already stored.
+/// - Notifies the uploader's devices and desktop viewers with a `copied` event carrying the final path.
+/// - Marks the transfer jobs waiting for this hash on the user's devices as `done`, for stored files and duplicates alike.
+pub async fn store_upload(
+    state: &AppState,
+    temp: TempUpload,
+    dir: &str,
+    username: &str,
+    filename: &str,
+    modified_at: Option<DateTime<Utc>>,
+) -> io::Result<StoreOutcome> {
+    let hash = temp.hash.clone();
+
+    let lookup = hash.clone();
+    let owner = username.to_string();
+    let owned = state
+        .db(move |repo| Ok(repo.find_owned_upload(&owner, &lookup)?.is_some_and(|u| u.path.is_some())))
+        .await
         .unwrap_or(false);
 
-    if exists {
+    if owned {
+        discard_file(&temp.path).await;
         println!("📦 File {} already exists", hash);
-        return "The file already exists".to_string();
+        transfers::mark_done(state, &hash, username).await;
+        return Ok(StoreOutcome::Duplicate);
     }
 
-    let dir = state.upload_dir.read().await;
-    let path = get_output_path(&dir, &username, &filename, modified_at).await;
+    // The capture date from EXIF wins over the phone's modification date for the year/month folders
+    let exif_path = temp.path.clone();
+    let exif_hash = hash.clone();
+    let metadata = tokio::task::spawn_blocking(move || read_exif(&exif_path, &exif_hash)).await.ok().flatten();
+    let taken_at = metadata.as_ref().and_then(|m| m.taken_at).map(|t| t.and_utc()).or(modified_at);
+
+    // The bytes live once in the blob store; the user's folder gets a link to them
+    let lookup = hash.clone();
+    let recorded = state.db(move |repo| repo.find_upload(&lookup)).await.ok().flatten().and_then(|u| u.blob).map(PathBuf::from);
+    let (blob, known) = match store_blob(Path::new(dir), recorded.as_deref(), &temp).await {
+        Ok(stored) => stored,
+        Err(e) => {
+            discard_file(&temp.path).await;
+            return Err(e);
+        }
+    };
 
-    save_file(&path, &data).await;
+    let policy = *state.collision_policy.read().await;
+    let path = match link_output_path(&blob, dir, username, filename, &hash, taken_at, policy).await {
+        Ok(path) => path,
+        Err(e) => {
+            // A blob stored by this upload is not recorded anywhere yet; one that was already stored stays
+            if !known {
+                discard_file(&blob).await;
+            }
+            return Err(e);
+        }
+    };
 
-    db.execute(
-        "INSERT INTO uploads (hash, filename) VALUES (?1, ?2)",
-        params![hash, filename],
-    )
-    .unwrap();
+    let file = StoredFile {
+        hash: hash.clone(),
+        filename: filename.to_string(),
+        size: temp.size,
+        owner: username.to_string(),
+        path: path.clone(),
+        modified_at,
+        uploaded_at: Utc::now(),
+        blob: blob.clone(),
+    };
+    if let Err(e) = state.db(move |repo| repo.save_stored_file(&file)).await {
+        // Nothing refers to the copy, or to a blob stored by this upload, so a retry starts over cleanly
+        discard_file(&path).await;
+        if !known {
+            discard_file(&blob).await;
+        }
+        return Err(io::Error::other(format!("error recording upload {hash}: {e}")));
+    }
+    if let Some(metadata) = metadata {
+        if let Err(e) = state.db(move |repo| repo.save_photo_metadata(&metadata)).await {
+            println!("❌ Error saving metadata of {}: {}", hash, e);
+        }
+    }
 
-    println!("✅ Received and Saved: {}", path.to_string_lossy());
+    println!("✅ Received and Saved: {} ({} bytes)", path.to_string_lossy(), temp.size);
+
+    // Generate the thumbnails before notifying, so viewers find them when they refresh the grid
+    if !known {
+        let thumb_source = path.clone();
+        let thumb_hash = hash.clone();
+        let generated = tokio::task::spawn_blocking(move || generate_thumbnails(&thumb_source, Path::new(".thumbs"), &thumb_hash))
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e)));
+        match generated {
+            Ok(thumb) => {
+                let thumb_hash = hash.clone();
+                let thumb = thumb.file_name().unwrap_or_default().to_string_lossy().to_string();
+                if let Err(e) = state.db(move |repo| repo.set_thumb(&thumb_hash, &thumb)).await {
+                    println!("❌ Error saving thumbnail of {}: {}", hash, e);
+                }
+            }
+            Err(e) => println!("⚠️ No thumbnail for {}: {}", hash, e),
+        }
+    }
 
-    // Send notification to WebSocket clients
-    let confirmation = serde_json::json!({
-        "event": "copied",
-        "hash": hash,
-        "status": "success",
-        "path": path.to_string_lossy(),
-    });
+    // Send notification to the uploader's devices and to desktop viewers
+    let confirmation = ServerMessage::Copied {
+        hash: hash.to_string(),
+        status: "success".to_string(),
+        path: path.to_string_lossy().to_string(),
+    };
 
-    let clients = state.ws_state.lock().await;
-    for client in clients.iter() {
-        let _ = client.send(Message::Text(confirmation.to_string()));
+    {
+        let mut clients = state.ws_state.lock().await;
+        clients.send_to_user(username, &confirmation);
+        clients.send_to_role(ClientRole::Desktop, &confirmation);
     }
 
-    "Upload Ended!".to_string()
+    transfers::mark_done(state, &hash, username).await;
+
+    Ok(StoreOutcome::Stored(path))
 }
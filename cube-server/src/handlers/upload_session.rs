@@ -0,0 +1,398 @@
+//! # Resumable Upload Handler
+//!
+//! This module provides a resumable, chunked alternative to `/upload_raw` for large files sent over
+//! unreliable connections.
+//!
+//! ## Endpoints
+//! - **create_session_handler** (`POST /upload_raw/sessions`): Opens a session for a file with a known size and SHA-256 hash.
+//! - **session_status_handler** (`GET /upload_raw/sessions/:id`): Returns the byte ranges received so far.
+//! - **upload_chunk_handler** (`PUT /upload_raw/sessions/:id?offset=N`): Writes the request body at `offset` in the partial file.
+//! - **finalize_session_handler** (`POST /upload_raw/sessions/:id/finalize`): Verifies the hash and stores the file like `/upload_raw`.
+//!
+//! ## Flow
+//! 1. The client creates a session with filename, size, hash and optional modification date.
+//! 2. The client PUTs chunks in any order; each received range is recorded in SQLite.
+//! 3. After a disconnect, the client asks for the session status and only resends missing ranges.
+//! 4. Once every byte is present, the client finalizes the session.
+//! 5. The server checks the hash and runs the same dedup, placement and notification as `upload_raw_handler`.
+//!
+//! ## Notes
+//! - Sessions and received ranges live in the `upload_sessions` and `upload_chunks` tables, and the
+//!   partial file stays in the upload directory, so a session survives a server restart.
+//! - Creating a session for a hash the same user is already uploading returns the existing session.
+//! - Sessions belong to the user of the token that created them; other users get `404`.
+//! - Sessions expire `SESSION_TTL_HOURS` after they were created. A background task (`start_session_sweeper`)
+//!   deletes expired sessions and their partial files at startup and then every `SWEEP_INTERVAL`.
+
+use axum::{
+    body::Body,
+    extract::{Json, Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json as AxumJson,
+};
+use chrono::{DateTime, Utc};
+use cube_db::{Repository, UploadSession};
+use serde::{Deserialize, Serialize};
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use uuid::Uuid;
+
+use crate::handlers::upload_raw::{store_upload, StoreOutcome};
+use crate::auth::AuthUser;
+use crate::state::AppState;
+use crate::utils::file::{discard_file, hash_file, write_stream_at, TempUpload};
+use crate::utils::hash::is_valid_hash;
+use crate::ws::transfers;
+
+/// Number of hours an upload session stays valid, counted from its creation.
+pub const SESSION_TTL_HOURS: i64 = 48;
+
+/// Interval between two runs of the expired session sweeper.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Payload for creating an upload session.
+#[derive(Deserialize)]
+pub struct CreateSessionRequest {
+    pub filename: String,
+    pub size: u64,
+    pub hash: String,
+    pub modified_at: Option<DateTime<Utc>>,
+}
+
+/// Query string for uploading a chunk.
+#[derive(Deserialize)]
+pub struct ChunkQuery {
+    pub offset: u64,
+}
+
+/// State of an upload session.
+///
+/// `received` holds the merged `[start, end)` byte ranges already stored on the server.
+#[derive(Serialize)]
+pub struct SessionStatus {
+    pub id: String,
+    pub filename: String,
+    pub size: u64,
+    pub hash: String,
+    pub received: Vec<[u64; 2]>,
+    pub complete: bool,
+}
+
+/// Response when finalizing a session.
+#[derive(Serialize)]
+pub struct FinalizeResponse {
+    pub hash: String,
+    pub status: String,
+    pub path: Option<String>,
+}
+
+/// Opens a new upload session, or returns the pending one for the same user and hash.
+///
+/// # Flow
+/// - Takes the username from the session token.
+/// - Rejects hashes that are not a SHA-256 in hexadecimal with `400 Bad Request`.
+/// - Reuses an existing session for the same user and hash, if any.
+/// - Otherwise stores a new session pointing at a partial file in the upload directory.
+/// - Marks the transfer jobs waiting for this hash on the user's devices as `uploading`.
+/// - Returns the session status.
+pub async fn create_session_handler(
+    State(state): State<Arc<AppState>>,
+    AuthUser { username, .. }: AuthUser,
+    Json(payload): Json<CreateSessionRequest>,
+) -> impl IntoResponse {
+    let hash = payload.hash.to_lowercase();
+
+    if !is_valid_hash(&hash) {
+        return (StatusCode::BAD_REQUEST, "Invalid hash").into_response();
+    }
+
+    if payload.size == 0 {
+        return (StatusCode::BAD_REQUEST, "Empty files must be sent to /upload_raw").into_response();
+    }
+
+    let (owner, lookup) = (username.clone(), hash.clone());
+    let existing = state.db(move |repo| repo.find_session_id(&owner, &lookup)).await.unwrap_or(None);
+
+    let id = match existing {
+        Some(id) => id,
+        None => {
+            let id = Uuid::new_v4().to_string();
+            let dir = state.upload_dir.read().await.clone();
+            let temp_path = PathBuf::from(&dir).join(format!(".{}.part", id));
+
+            let session = UploadSession {
+                id: id.clone(),
+                username: username.clone(),
+                filename: payload.filename,
+                size: payload.size,
+                hash: hash.clone(),
+                modified_at: payload.modified_at,
+                temp_path,
+            };
+
+            if let Err(e) = state.db(move |repo| repo.insert_session(&session)).await {
+                return (StatusCode::INTERNAL_SERVER_ERROR, format!("Error creating session: {e}")).into_response();
+            }
+            id
+        }
+    };
+
+    transfers::mark_uploading(&state, &hash, &username).await;
+
+    match session_status(&state, &id, &username).await {
+        Some(status) => (StatusCode::OK, AxumJson(status)).into_response(),
+        None => (StatusCode::NOT_FOUND, "Session not found").into_response(),
+    }
+}
+
+/// Returns the received ranges of an upload session.
+pub async fn session_status_handler(
+    State(state): State<Arc<AppState>>,
+    AuthUser { username, .. }: AuthUser,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match session_status(&state, &id, &username).await {
+        Some(status) => (StatusCode::OK, AxumJson(status)).into_response(),
+        None => (StatusCode::NOT_FOUND, "Session not found").into_response(),
+    }
+}
+
+/// Writes one chunk of an upload session.
+///
+/// # Flow
+/// - Loads the session.
+/// - Writes the body at `offset` in the partial file, refusing bytes past the declared size.
+/// - Records the received range.
+/// - Returns the updated session status.
+pub async fn upload_chunk_handler(
+    State(state): State<Arc<AppState>>,
+    AuthUser { username, .. }: AuthUser,
+    Path(id): Path<String>,
+    Query(query): Query<ChunkQuery>,
+    body: Body,
+) -> impl IntoResponse {
+    let session = match load_session(&state, &id, &username).await {
+        Some(session) => session,
+        None => return (StatusCode::NOT_FOUND, "Session not found").into_response(),
+    };
+
+    if query.offset >= session.size {
+        return (StatusCode::BAD_REQUEST, "Offset is past the end of the file").into_response();
+    }
+
+    let written = match write_stream_at(&session.temp_path, query.offset, session.size, body.into_data_stream()).await {
+        Ok(written) => written,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("Error writing chunk: {e}")).into_response(),
+    };
+
+    if written > 0 {
+        let (session_id, offset) = (id.clone(), query.offset);
+        if let Err(e) = state.db(move |repo| repo.record_chunk(&session_id, offset, written)).await {
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("Error recording chunk: {e}")).into_response();
+        }
+    }
+
+    match session_status(&state, &id, &username).await {
+        Some(status) => (StatusCode::OK, AxumJson(status)).into_response(),
+        None => (StatusCode::NOT_FOUND, "Session not found").into_response(),
+    }
+}
+
+/// Completes an upload session.
+///
+/// # Flow
+/// - Checks that every byte of the file has been received.
+/// - Deletes the session, so a concurrent finalize of the same session gets `404 Not Found` instead of storing the
+///   partial file a second time.
+/// - Verifies the SHA-256 hash of the assembled file; on mismatch, the partial file is discarded and the upload
+///   must be restarted with a new session.
+/// - Stores the file through `store_upload` (dedup, output path, database, WebSocket notification).
+pub async fn finalize_session_handler(
+    State(state): State<Arc<AppState>>,
+    AuthUser { username, .. }: AuthUser,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let session = match load_session(&state, &id, &username).await {
+        Some(session) => session,
+        None => return (StatusCode::NOT_FOUND, "Session not found").into_response(),
+    };
+
+    let received = received_ranges(&state, &id).await;
+    if !is_complete(&received, session.size) {
+        return (StatusCode::CONFLICT, "Upload is incomplete").into_response();
+    }
+
+    // Claim the session before reading the file; only one request gets it
+    let (session_id, owner) = (id.clone(), username.clone());
+    let session = match state.db(move |repo| repo.take_session(&session_id, &owner)).await {
+        Ok(Some(session)) => session,
+        Ok(None) => return (StatusCode::NOT_FOUND, "Session not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Error finalizing session: {e}")).into_response(),
+    };
+
+    let hash = match hash_file(&session.temp_path).await {
+        Ok(hash) => hash,
+        Err(e) => {
+            discard_file(&session.temp_path).await;
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("Error reading file: {e}")).into_response();
+        }
+    };
+
+    if hash != session.hash {
+        discard_file(&session.temp_path).await;
+        return (StatusCode::UNPROCESSABLE_ENTITY, "Hash mismatch, upload must be restarted").into_response();
+    }
+
+    let temp = TempUpload {
+        path: session.temp_path.clone(),
+        hash: hash.clone(),
+        size: session.size,
+    };
+    let dir = state.upload_dir.read().await.clone();
+    let outcome = store_upload(&state, temp, &dir, &session.username, &session.filename, session.modified_at).await;
+
+    let response = match outcome {
+        Ok(StoreOutcome::Stored(path)) => FinalizeResponse {
+            hash,
+            status: "success".to_string(),
+            path: Some(path.to_string_lossy().to_string()),
+        },
+        Ok(StoreOutcome::Duplicate) => FinalizeResponse {
+            hash,
+            status: "exists".to_string(),
+            path: None,
+        },
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Error saving file: {e}")).into_response(),
+    };
+
+    (StatusCode::OK, AxumJson(response)).into_response()
+}
+
+/// Periodically deletes the sessions older than `SESSION_TTL_HOURS` and their partial files. The first run
+/// happens at startup.
+pub async fn start_session_sweeper(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let cutoff = Utc::now() - chrono::Duration::hours(SESSION_TTL_HOURS);
+        match state.db(move |repo| repo.delete_sessions_before(cutoff)).await {
+            Ok(paths) => {
+                for path in &paths {
+                    discard_file(path).await;
+                }
+                if !paths.is_empty() {
+                    println!("🧹 Deleted {} expired upload sessions", paths.len());
+                }
+            }
+            Err(e) => eprintln!("Error purging upload sessions: {}", e),
+        }
+    }
+}
+
+/// Loads a session row by ID, if it belongs to `username`.
+async fn load_session(state: &AppState, id: &str, username: &str) -> Option<UploadSession> {
+    let (id, username) = (id.to_string(), username.to_string());
+    state.db(move |repo| repo.find_session(&id, &username)).await.unwrap_or(None)
+}
+
+/// Builds the status of a session, merging its received chunks into ranges.
+async fn session_status(state: &AppState, id: &str, username: &str) -> Option<SessionStatus> {
+    let session = load_session(state, id, username).await?;
+    let received = received_ranges(state, id).await;
+    let complete = is_complete(&received, session.size);
+
+    Some(SessionStatus {
+        id: session.id,
+        filename: session.filename,
+        size: session.size,
+        hash: session.hash,
+        received,
+        complete,
+    })
+}
+
+/// Reads the chunks of a session and merges overlapping or adjacent ones.
+async fn received_ranges(state: &AppState, id: &str) -> Vec<[u64; 2]> {
+    let session_id = id.to_string();
+    let chunks = state.db(move |repo| repo.session_chunks(&session_id)).await.unwrap_or_default();
+    merge_ranges(chunks)
+}
+
+/// Merges `(start, end)` chunks, ordered by start, into disjoint `[start, end)` ranges.
+fn merge_ranges(chunks: Vec<(u64, u64)>) -> Vec<[u64; 2]> {
+    let mut ranges: Vec<[u64; 2]> = Vec::new();
+    for (start, end) in chunks {
+        match ranges.last_mut() {
+            Some(last) if start <= last[1] => last[1] = last[1].max(end),
+            _ => ranges.push([start, end]),
+        }
+    }
+    ranges
+}
+
+/// Returns true when the ranges cover the whole file.
+fn is_complete(ranges: &[[u64; 2]], size: u64) -> bool {
+    match ranges {
+        [] => size == 0,
+        [range] => range[0] == 0 && range[1] >= size,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cube_db::{SqliteRepository, UploadSession};
+
+    #[test]
+    fn overlapping_chunks_are_merged() {
+        let ranges = merge_ranges(vec![(0, 6), (4, 10)]);
+        assert_eq!(ranges, [[0, 10]]);
+        assert!(is_complete(&ranges, 10));
+
+        // A chunk inside an earlier one does not shrink it
+        assert_eq!(merge_ranges(vec![(0, 10), (2, 4)]), [[0, 10]]);
+    }
+
+    #[test]
+    fn adjacent_chunks_are_merged() {
+        let ranges = merge_ranges(vec![(0, 4), (4, 8), (8, 10)]);
+        assert_eq!(ranges, [[0, 10]]);
+        assert!(is_complete(&ranges, 10));
+    }
+
+    #[test]
+    fn a_gap_keeps_the_upload_incomplete() {
+        let ranges = merge_ranges(vec![(0, 4), (6, 10)]);
+        assert_eq!(ranges, [[0, 4], [6, 10]]);
+        assert!(!is_complete(&ranges, 10));
+
+        assert!(!is_complete(&merge_ranges(vec![(2, 10)]), 10));
+        assert!(!is_complete(&merge_ranges(vec![(0, 8)]), 10));
+        assert!(!is_complete(&[], 10));
+    }
+
+    #[test]
+    fn a_chunk_sent_again_at_the_same_offset_replaces_the_first() {
+        let repo = SqliteRepository::open_in_memory().unwrap();
+        repo.insert_session(&UploadSession {
+            id: "s1".to_string(),
+            username: "ana".to_string(),
+            filename: "a.cr3".to_string(),
+            size: 10,
+            hash: "h".to_string(),
+            modified_at: None,
+            temp_path: std::path::PathBuf::from("/tmp/.s1.part"),
+        })
+        .unwrap();
+
+        repo.record_chunk("s1", 0, 10).unwrap();
+        repo.record_chunk("s1", 0, 4).unwrap();
+
+        let ranges = merge_ranges(repo.session_chunks("s1").unwrap());
+        assert_eq!(ranges, [[0, 4]]);
+        assert!(!is_complete(&ranges, 10));
+    }
+}
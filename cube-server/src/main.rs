@@ -3,45 +3,82 @@
 //! This is the entry point for the Cube server application.
 //!
 //! ## Features
-//! - Initializes the SQLite database and creates required tables if they do not exist.
-//! - Sets up the global application state, including upload directory, database connection, and WebSocket state.
+//! - Opens a pool of SQLite connections in WAL mode through `cube_db`, which applies pending schema migrations
+//!   and refuses to start on a database migrated by a newer version. Queries run on the blocking thread pool
+//!   (see `AppState::db`).
+//! - Starts a background sweeper for expired pairing codes and a worker that retries transfer jobs, and records
+//!   the thumbnails already in `.thumbs` for files stored before the database tracked them.
+//! - Checks the blob store against the per-user folder tree in the background, rebuilding whichever side is
+//!   missing (see `handlers::storage`).
+//! - Sets up the global application state, including upload directory, database connection pool, and WebSocket state.
+//! - Generates the desktop key of this launch and writes it where the desktop app reads it (see
+//!   `utils::desktop_key`).
 //! - Configures all HTTP and WebSocket routes using Axum, including file upload, authentication, configuration, and thumbnail management.
-//! - Serves static thumbnail files from the `.thumbs` directory.
-//! - Enables permissive CORS for development and cross-origin requests.
+//! - Serves thumbnail files from the `.thumbs` directory to their owners and to the desktop viewer.
+//! - Requires a session token on every route except `/ping`, `/generate_code`, `/set-config` and `/auth` (see `auth`
+//!   module). `/generate_code` only accepts the desktop app with the desktop key, so only it can issue pairing codes.
+//!   `/set-config` only accepts the desktop app too, since its settings apply to every user.
+//!   `/api/thumbs/list` and `GET /api/files/:hash` also accept the desktop app with the desktop key.
+//!   `/ws` and `/thumbs/*` also accept the desktop viewer of the server machine, with the desktop key instead of a
+//!   token (see `auth::DesktopViewer`).
+//! - Enables permissive CORS for development and cross-origin requests, except on `/admin/*`.
 //! - Prints the local IP address for easy access from other devices on the network.
 //!
 //! ## Endpoints
 //! - `/upload_raw`: Upload RAW files.
-//! - `/generate_code`: Generate authentication code.
-//! - `/set-config`: Set or update upload directory.
+//! - `/upload_raw/sessions/*`: Resumable, chunked RAW uploads.
+//! - `/generate_code`: Generate authentication code (desktop app only).
+//! - `/set-config`: Set the export directory and file name collision policy (desktop app only).
 //! - `/auth`: Authenticate and receive a session token.
+//! - `/auth/refresh`: Replace the caller's token with one that has a fresh expiry.
+//! - `/admin/devices`: List and revoke paired devices (desktop app only).
+//! - `/admin/fsck`: Check and repair the blob store and the per-user folder tree (desktop app only).
 //! - `/ping`: Health check endpoint.
 //! - `/api/thumbs`: Upload thumbnails.
-//! - `/api/thumbs/list`: List thumbnails.
-//! - `/thumbs/*`: Serve static thumbnail files.
+//! - `/api/thumbs/multipart`: Upload thumbnails as binary multipart parts.
+//! - `/api/thumbs/list`: List the caller's thumbnails (every user's for the desktop app), paginated with a cursor,
+//!   filtered and sorted.
+//! - `/api/files/:hash`: Download (`GET`, with `Range` support) or delete (`DELETE`) a file in the caller's library.
+//!   The desktop app can download any user's files.
+//! - `/thumbs/:file`: Serve a thumbnail file, with immutable cache headers.
 //! - WebSocket endpoint (see `ws` module).
 
+mod auth;
 mod state;
 mod handlers;
 mod utils;
 mod ws;
 mod tcp_server;
 
-use axum::{routing::{get, post}, Router};
-use handlers::auth::{generate_code_handler, auth_handler};
+use axum::{extract::DefaultBodyLimit, middleware, routing::{get, post}, Router};
+use auth::{require_auth, require_desktop};
+use handlers::auth::{generate_code_handler, auth_handler, refresh_handler, start_code_sweeper};
+use handlers::devices::{list_devices_handler, revoke_device_handler};
+use handlers::files::{delete_file_handler, download_file_handler};
+use handlers::storage::{fsck_handler, start_fsck};
 use handlers::upload_raw::upload_raw_handler;
-use handlers::thumbs::{upload_thumbs_handler, list_thumbs_handler};
+use handlers::upload_session::{
+    create_session_handler, finalize_session_handler, session_status_handler, start_session_sweeper,
+    upload_chunk_handler,
+};
+use handlers::thumbs::{
+    index_existing_thumbs, upload_thumbs_handler, upload_thumbs_multipart_handler, list_thumbs_handler, serve_thumb_handler,
+    MAX_MULTIPART_BYTES,
+};
 use handlers::config::set_config_handler;
 use state::AppState;
+use utils::{desktop_key::{new_desktop_key, write_desktop_key}, path::CollisionPolicy, throttle::AuthThrottle};
 use dirs::picture_dir;
 use local_ip_address::local_ip;
 use std::{sync::Arc, net::SocketAddr};
 use tokio::fs;
 use tower_http::cors::{CorsLayer, Any};
-use rusqlite::Connection;
-use ws::create_ws_router;
+use cube_db::open_pool;
+use ws::{create_ws_router, registry::Registry, transfers::start_transfer_worker};
 use tokio::sync::{Mutex, RwLock};
-use tower_http::services::ServeDir;
+
+/// Maximum number of open SQLite connections.
+const DB_POOL_SIZE: u32 = 8;
 
 #[tokio::main]
 async fn main() {
@@ -54,67 +91,84 @@ async fn main() {
 
     fs::create_dir_all(&default_dir).await.unwrap();
 
-    // Initialize SQLite database and tables
-    let conn = Connection::open("uploads.db").expect("Falha ao abrir DB");
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS uploads (
-            hash TEXT PRIMARY KEY,
-            filename TEXT,
-            size TEXT,
-            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-        )",
-        [],
-    ).unwrap();
-
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS tokens (
-            token TEXT PRIMARY KEY,
-            username TEXT,
-            ip TEXT,
-            created_at TIMESTAMP
-        )",
-        [],
-    ).unwrap();
-
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS auth_codes (
-            code TEXT PRIMARY KEY,
-            created_at TIMESTAMP,
-            ip TEXT
-        )",
-        [],
-    ).unwrap();
+    // Open the SQLite database, apply pending migrations and start the connection pool
+    let db_pool = match open_pool("uploads.db", DB_POOL_SIZE) {
+        Ok(pool) => pool,
+        Err(e) => {
+            eprintln!("❌ Falha ao abrir DB: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    // Secret the desktop app sends to prove it runs on this machine, new at each launch
+    let desktop_key = new_desktop_key();
+    match write_desktop_key(&desktop_key) {
+        Ok(path) => println!("🔑 Desktop key: {}", path.display()),
+        Err(e) => eprintln!("❌ Failed to write the desktop key: {e}"),
+    }
 
     // Build global application state
     let state = AppState {
         upload_dir: Arc::new(RwLock::new(default_dir)),
-        db: Arc::new(Mutex::new(conn)),
-        ws_state: Arc::new(Mutex::new(Vec::new())),
+        collision_policy: Arc::new(RwLock::new(CollisionPolicy::default())),
+        db_pool,
+        ws_state: Arc::new(Mutex::new(Registry::default())),
+        auth_throttle: Arc::new(Mutex::new(AuthThrottle::default())),
+        desktop_key,
     };
-    
-    // Serve static thumbnail files
-    let static_files = ServeDir::new(".thumbs").append_index_html_on_directories(false);
-    let thumbs_route = Router::new().nest_service("/thumbs", static_files);
+
 
     let shared_state = Arc::new(state);
     tokio::spawn(tcp_server::start_tcp_server(shared_state.clone()));
+    tokio::spawn(start_code_sweeper(shared_state.clone()));
+    tokio::spawn(start_session_sweeper(shared_state.clone()));
+    tokio::spawn(start_transfer_worker(shared_state.clone()));
+    tokio::spawn(index_existing_thumbs(shared_state.clone()));
+    tokio::spawn(start_fsck(shared_state.clone()));
 
     // Enable permissive CORS
     let cors = CorsLayer::new().allow_origin(Any).allow_methods(Any).allow_headers(Any);
 
-    // Build Axum application with all routes
-    let app = Router::new()
+    // Routes that require a valid session token
+    let protected = Router::new()
         .route("/upload_raw", post(upload_raw_handler))
+        .route("/upload_raw/sessions", post(create_session_handler))
+        .route("/upload_raw/sessions/:id", get(session_status_handler).put(upload_chunk_handler))
+        .route("/upload_raw/sessions/:id/finalize", post(finalize_session_handler))
+        .route("/api/thumbs", post(upload_thumbs_handler))
+        // Each part is size-checked by the handler; the batch is capped at `MAX_MULTIPART_BYTES`
+        .route("/api/thumbs/multipart", post(upload_thumbs_multipart_handler).layer(DefaultBodyLimit::max(MAX_MULTIPART_BYTES)))
+        .route("/auth/refresh", post(refresh_handler))
+        .route_layer(middleware::from_fn_with_state(shared_state.clone(), require_auth));
+
+    // Device administration, only reachable by the desktop app of the server machine
+    let admin = Router::new()
+        .route("/admin/devices", get(list_devices_handler))
+        .route("/admin/devices/:id", axum::routing::delete(revoke_device_handler))
+        .route("/admin/fsck", post(fsck_handler))
+        .route_layer(middleware::from_fn_with_state(shared_state.clone(), require_desktop));
+
+    // Pairing codes and server-wide settings, only reachable by the desktop app of the server machine; phones only
+    // call `/auth`
+    let desktop = Router::new()
         .route("/generate_code", get(generate_code_handler))
         .route("/set-config", post(set_config_handler))
+        .route_layer(middleware::from_fn_with_state(shared_state.clone(), require_desktop));
+
+    // Build Axum application with all routes
+    let app = Router::new()
+        .merge(desktop)
         .route("/auth", post(auth_handler))
         .route("/ping", get(|| async { "pong" }))
-        .route("/api/thumbs", post(upload_thumbs_handler))
+        .merge(protected)
+        // Check the token or desktop key themselves, see `auth::Caller`
         .route("/api/thumbs/list", get(list_thumbs_handler))
-        .merge(thumbs_route)
-        .merge(create_ws_router())
-        .with_state(shared_state.clone())
-        .layer(cors);
+        .route("/api/files/:hash", get(download_file_handler).delete(delete_file_handler))
+        .merge(create_ws_router()) // authenticates phones and desktop viewers itself
+        .route("/thumbs/:file", get(serve_thumb_handler)) // checks the token or desktop key itself, see `serve_thumb_handler`
+        .layer(cors)
+        .merge(admin) // added after the CORS layer, so browsers block pages on other sites from calling it
+        .with_state(shared_state.clone());
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
 
@@ -122,5 +176,8 @@ async fn main() {
         println!("📱 Scan: http://{}:8080", ip);
     }
 
-    axum::serve(tokio::net::TcpListener::bind(addr).await.unwrap(), app).await.unwrap();
+    axum::serve(
+        tokio::net::TcpListener::bind(addr).await.unwrap(),
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    ).await.unwrap();
 }
\ No newline at end of file
@@ -1,16 +1,42 @@
 use std::sync::Arc;
-use rusqlite::Connection;
+use cube_db::{Pool, PooledRepository};
 use tokio::sync::{Mutex, RwLock};
+use crate::utils::{path::CollisionPolicy, throttle::AuthThrottle};
 use crate::ws::Clients;
 
 /// Global application state shared across handlers.
 ///
 /// - `upload_dir`: The current upload directory, protected by an async RwLock.
-/// - `db`: The SQLite database connection, protected by an async Mutex.
+/// - `collision_policy`: How received files are named when their name is taken (see `utils::path`).
+/// - `db_pool`: Pool of SQLite connections (see `cube_db::pool`); use `AppState::db` to run queries.
 /// - `ws_state`: The list of connected WebSocket clients.
+/// - `auth_throttle`: Failed `/auth` attempts per client IP, used for lockouts.
+/// - `desktop_key`: Secret of this launch that the desktop app sends to prove itself (see `auth::DesktopViewer`).
 #[derive(Clone)]
 pub struct AppState {
     pub upload_dir: Arc<RwLock<String>>,
-    pub db: Arc<Mutex<Connection>>,
+    pub collision_policy: Arc<RwLock<CollisionPolicy>>,
+    pub db_pool: Pool,
     pub ws_state: Clients,
-}
\ No newline at end of file
+    pub auth_throttle: Arc<Mutex<AuthThrottle>>,
+    pub desktop_key: String,
+}
+
+impl AppState {
+    /// Runs `f` with a pooled connection on the blocking thread pool and returns its result.
+    ///
+    /// Panics in `f` are propagated to the caller.
+    pub async fn db<T, F>(&self, f: F) -> cube_db::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&PooledRepository) -> cube_db::Result<T> + Send + 'static,
+    {
+        let pool = self.db_pool.clone();
+        let task = tokio::task::spawn_blocking(move || f(&PooledRepository::from_pool(&pool)?));
+
+        match task.await {
+            Ok(result) => result,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+}
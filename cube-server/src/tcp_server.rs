@@ -1,6 +1,5 @@
 use std::path::PathBuf;
 use std::sync::Arc;
-use serde_json::json;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
 
@@ -13,7 +12,7 @@ pub async fn start_tcp_server(shared_state: Arc<crate::state::AppState>) {
             Ok((mut socket, addr)) => {
                 println!("📡 TCP connection from: {}", addr);
 
-                let state = shared_state.clone();
+                let _state = shared_state.clone();
                 tokio::spawn(async move {
                     if let Err(e) = handle_client(&mut socket).await {
                         eprintln!("TCP client error: {}", e);
@@ -50,10 +49,10 @@ async fn handle_config_command() -> Result<String, Box<dyn std::error::Error>> {
         .join("Cube");
     let user = "bruno";
 
-    let dcim_dir = base_dir.join(&user).join("dcim");
+    let dcim_dir = base_dir.join(user).join("dcim");
     let dcim_thumbs = dcim_dir.join("thumbs");
 
-    let downloads_dir = base_dir.join(&user).join("downloads");
+    let downloads_dir = base_dir.join(user).join("downloads");
     let downloads_thumbs = downloads_dir.join("thumbs");
 
     create_dir_if_not_exists(&dcim_thumbs).await?;
@@ -0,0 +1,286 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+use crate::utils::file::{discard_file, hash_file, persist_file, TempUpload};
+
+/// Directory inside the upload directory where each received content is stored once, named by its hash.
+pub const BLOB_DIR: &str = "blobs";
+
+/// Path of the blob holding the content with this hash: `blobs/ab/cdef…`, split after two characters so no
+/// directory holds too many files.
+///
+/// `hash` must have been checked with `is_valid_hash`.
+pub fn blob_path(base: &Path, hash: &str) -> PathBuf {
+    base.join(BLOB_DIR).join(&hash[..2]).join(&hash[2..])
+}
+
+/// Moves a fully received temporary file into the blob store.
+///
+/// If the content is already stored, at its recorded blob path or in `base`, the temporary file is discarded and
+/// the existing blob is kept. Blobs are not moved when the upload directory changes, so `recorded` takes
+/// precedence over `base`.
+///
+/// # Returns
+/// The blob path, and whether the content was already stored.
+///
+/// # Example
+/// ```
+/// let (blob, known) = store_blob(Path::new(&dir), recorded.as_deref(), &temp).await?;
+/// let path = link_output_path(&blob, &dir, "alice", "photo.raw", &temp.hash, None, CollisionPolicy::Counter).await?;
+/// ```
+pub async fn store_blob(base: &Path, recorded: Option<&Path>, temp: &TempUpload) -> io::Result<(PathBuf, bool)> {
+    if let Some(blob) = recorded {
+        if fs::try_exists(blob).await? {
+            discard_file(&temp.path).await;
+            return Ok((blob.to_path_buf(), true));
+        }
+    }
+
+    let blob = blob_path(base, &temp.hash);
+    if fs::try_exists(&blob).await? {
+        discard_file(&temp.path).await;
+        return Ok((blob, true));
+    }
+
+    if let Some(parent) = blob.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    persist_file(&temp.path, &blob).await?;
+    Ok((blob, false))
+}
+
+/// Creates `dest` with the content of `source`, without storing the bytes twice when possible.
+///
+/// Tries a reflink (a copy-on-write clone, on Btrfs, XFS, APFS or ReFS) first, then a hard link. If the
+/// filesystem supports neither or the files are on different devices, falls back to a copy. Either way `dest` is
+/// created exclusively: if something already exists there, nothing is changed and an `AlreadyExists` error is
+/// returned, so a file created at the same moment by another upload is never replaced.
+///
+/// # Notes
+/// - A reflinked or copied file is independent of `source`. A hard-linked one shares its inode: editing it in
+///   place changes the blob and every other user's copy of the content, and `check_copy` does not notice since
+///   both stay present. Its permissions and modification time are shared too. Editors that save by writing a new
+///   file and renaming it over the old one only replace that user's copy.
+pub async fn materialize(source: &Path, dest: &Path) -> io::Result<()> {
+    let (from, to) = (source.to_path_buf(), dest.to_path_buf());
+    match tokio::task::spawn_blocking(move || reflink_copy::reflink(from, to)).await {
+        Ok(Ok(())) => return Ok(()),
+        Ok(Err(e)) if e.kind() == io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+
+    match fs::hard_link(source, dest).await {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Err(e),
+        Err(_) => {}
+    }
+
+    let mut target = fs::OpenOptions::new().write(true).create_new(true).open(dest).await?;
+    let copied = async {
+        let mut source = fs::File::open(source).await?;
+        tokio::io::copy(&mut source, &mut target).await?;
+        target.sync_all().await
+    }
+    .await;
+
+    // The file was created here, so a partial copy can be removed
+    if copied.is_err() {
+        discard_file(dest).await;
+    }
+    copied
+}
+
+/// What [`check_copy`] found for one user's copy of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyCheck {
+    /// The blob and the copy are both present.
+    Healthy,
+    /// The copy was missing and was materialized again from the blob.
+    Restored,
+    /// The blob was missing and was rebuilt from the copy.
+    Rebuilt,
+    /// The blob is missing and the copy does not match the hash, so neither side can be rebuilt.
+    Corrupted,
+    /// The blob and the copy are both missing.
+    Lost,
+}
+
+/// Checks one user's copy of a file against the blob store and rebuilds whichever side is missing.
+///
+/// The copy is only hashed when the blob has to be rebuilt from it, so a check of a healthy store does not read
+/// file contents. Files stored before the blob store existed get their blob on the first check.
+///
+/// # Arguments
+/// * `blob` - The file's blob: its recorded path, or `blob_path` in the current upload directory.
+/// * `hash` - The file's hash.
+/// * `copy` - The user's copy, from `link_output_path`.
+pub async fn check_copy(blob: &Path, hash: &str, copy: &Path) -> io::Result<CopyCheck> {
+    match (fs::try_exists(blob).await?, fs::try_exists(copy).await?) {
+        (true, true) => Ok(CopyCheck::Healthy),
+        (true, false) => {
+            if let Some(parent) = copy.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            materialize(blob, copy).await?;
+            Ok(CopyCheck::Restored)
+        }
+        (false, true) => {
+            if hash_file(copy).await? != hash {
+                return Ok(CopyCheck::Corrupted);
+            }
+            if let Some(parent) = blob.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            materialize(copy, blob).await?;
+            Ok(CopyCheck::Rebuilt)
+        }
+        (false, false) => Ok(CopyCheck::Lost),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::path::tests::TempDir;
+
+    /// Writes `content` at `path` and returns its hash.
+    async fn write(path: &Path, content: &str) -> String {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await.unwrap();
+        }
+        fs::write(path, content).await.unwrap();
+        hash_file(path).await.unwrap()
+    }
+
+    async fn read(path: &Path) -> String {
+        fs::read_to_string(path).await.unwrap()
+    }
+
+    /// Writes `content` as a temporary upload in `dir`, as `stream_to_temp` would.
+    async fn temp_upload(dir: &Path, content: &str) -> TempUpload {
+        let path = dir.join(".upload.part");
+        let hash = write(&path, content).await;
+        TempUpload { path, hash, size: content.len() as u64 }
+    }
+
+    #[cfg(unix)]
+    fn inode(path: &Path) -> u64 {
+        use std::os::unix::fs::MetadataExt;
+        std::fs::metadata(path).unwrap().ino()
+    }
+
+    #[tokio::test]
+    async fn new_content_is_moved_into_the_blob_store() {
+        let dir = TempDir::new();
+        let temp = temp_upload(&dir.0, "a").await;
+
+        let (blob, known) = store_blob(&dir.0, None, &temp).await.unwrap();
+
+        assert!(!known);
+        assert_eq!(blob, blob_path(&dir.0, &temp.hash));
+        assert_eq!(read(&blob).await, "a");
+        assert!(!temp.path.exists());
+    }
+
+    #[tokio::test]
+    async fn known_content_keeps_the_recorded_blob() {
+        let dir = TempDir::new();
+        let recorded = dir.0.join("old/blobs/recorded");
+        write(&recorded, "a").await;
+        let temp = temp_upload(&dir.0, "a").await;
+
+        let (blob, known) = store_blob(&dir.0, Some(&recorded), &temp).await.unwrap();
+
+        assert!(known);
+        assert_eq!(blob, recorded);
+        assert!(!blob_path(&dir.0, &temp.hash).exists());
+        assert!(!temp.path.exists());
+    }
+
+    /// On one filesystem the copy is a reflink where supported, and a hard link otherwise.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn materialize_clones_or_hard_links_on_one_filesystem() {
+        let dir = TempDir::new();
+        let source = dir.0.join("blob");
+        write(&source, "a").await;
+        let reflinks = reflink_copy::reflink(&source, dir.0.join("probe")).is_ok();
+
+        let dest = dir.0.join("copy");
+        materialize(&source, &dest).await.unwrap();
+
+        assert_eq!(read(&dest).await, "a");
+        assert_eq!(inode(&dest) == inode(&source), !reflinks);
+    }
+
+    /// Neither reflinks nor hard links cross filesystems, so the content is copied. Needs a second filesystem,
+    /// `/dev/shm`, and passes trivially without one.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn materialize_copies_across_filesystems() {
+        use std::os::unix::fs::MetadataExt;
+
+        let shm = Path::new("/dev/shm");
+        let dir = TempDir::new();
+        let Ok(other) = std::fs::metadata(shm) else { return };
+        if other.dev() == std::fs::metadata(&dir.0).unwrap().dev() {
+            return;
+        }
+        let other = TempDir::new_in(shm);
+        let source = dir.0.join("blob");
+        write(&source, "a").await;
+
+        let dest = other.0.join("copy");
+        materialize(&source, &dest).await.unwrap();
+
+        assert_eq!(read(&dest).await, "a");
+        assert_eq!(std::fs::metadata(&dest).unwrap().nlink(), 1);
+    }
+
+    #[tokio::test]
+    async fn materialize_never_replaces_an_existing_file() {
+        let dir = TempDir::new();
+        let source = dir.0.join("blob");
+        write(&source, "a").await;
+        let dest = dir.0.join("copy");
+        write(&dest, "b").await;
+
+        let err = materialize(&source, &dest).await.unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(read(&dest).await, "b");
+    }
+
+    #[tokio::test]
+    async fn check_copy_repairs_whichever_side_is_missing() {
+        let dir = TempDir::new();
+        let (blob, copy) = (dir.0.join("blobs/blob"), dir.0.join("ana/2024/03/IMG_0001.CR2"));
+        let hash = write(&blob, "a").await;
+        write(&copy, "a").await;
+
+        assert_eq!(check_copy(&blob, &hash, &copy).await.unwrap(), CopyCheck::Healthy);
+
+        fs::remove_file(&copy).await.unwrap();
+        assert_eq!(check_copy(&blob, &hash, &copy).await.unwrap(), CopyCheck::Restored);
+        assert_eq!(read(&copy).await, "a");
+
+        fs::remove_file(&blob).await.unwrap();
+        assert_eq!(check_copy(&blob, &hash, &copy).await.unwrap(), CopyCheck::Rebuilt);
+        assert_eq!(read(&blob).await, "a");
+    }
+
+    #[tokio::test]
+    async fn check_copy_reports_what_cannot_be_repaired() {
+        let dir = TempDir::new();
+        let (blob, copy) = (dir.0.join("blobs/blob"), dir.0.join("ana/IMG_0001.CR2"));
+        let hash = write(&copy, "a").await;
+        write(&copy, "edited").await;
+
+        assert_eq!(check_copy(&blob, &hash, &copy).await.unwrap(), CopyCheck::Corrupted);
+        assert!(!blob.exists());
+
+        fs::remove_file(&copy).await.unwrap();
+        assert_eq!(check_copy(&blob, &hash, &copy).await.unwrap(), CopyCheck::Lost);
+    }
+}
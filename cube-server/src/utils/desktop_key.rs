@@ -0,0 +1,51 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// Name of the file holding the desktop key, inside the `Cube` folder of the user's local data directory.
+pub const DESKTOP_KEY_FILE: &str = "desktop.key";
+
+/// Generates the desktop key of this launch. Keys of previous launches stop working when the server restarts.
+pub fn new_desktop_key() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
+/// Where the desktop app reads the key: `~/.local/share/Cube/desktop.key` on Linux,
+/// `~/Library/Application Support/Cube/desktop.key` on macOS and `%LOCALAPPDATA%\Cube\desktop.key` on Windows.
+pub fn desktop_key_path() -> PathBuf {
+    dirs::data_local_dir()
+        .unwrap_or_else(|| std::env::current_dir().unwrap_or_default())
+        .join("Cube")
+        .join(DESKTOP_KEY_FILE)
+}
+
+/// Writes the desktop key for the desktop app, readable only by the current user on Unix.
+///
+/// The previous file is removed first, so a file created with wider permissions is not reused.
+///
+/// # Example
+/// ```
+/// let key = new_desktop_key();
+/// let path = write_desktop_key(&key)?;
+/// ```
+pub fn write_desktop_key(key: &str) -> io::Result<PathBuf> {
+    let path = desktop_key_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::remove_file(&path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(&path)?.write_all(key.as_bytes())?;
+    Ok(path)
+}
@@ -0,0 +1,60 @@
+use axum::http::HeaderValue;
+
+/// `ETag` of content identified by a hash: the hash in quotes.
+pub fn etag(hash: &str) -> String {
+    format!("\"{hash}\"")
+}
+
+/// Returns true if an `If-None-Match` value is `*` or lists `etag`, compared weakly.
+///
+/// # Example
+/// ```
+/// if headers.get(header::IF_NONE_MATCH).is_some_and(|value| etag_listed(value, &etag)) {
+///     return StatusCode::NOT_MODIFIED.into_response();
+/// }
+/// ```
+pub fn etag_listed(value: &HeaderValue, etag: &str) -> bool {
+    value
+        .to_str()
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Returns true if an `If-Range` value names `etag`. `If-Range` needs a strong match, so weak tags and dates never
+/// match and the whole file is sent instead of the range.
+pub fn if_range_matches(value: &HeaderValue, etag: &str) -> bool {
+    value.as_bytes() == etag.as_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "4e55a7af";
+
+    #[test]
+    fn etag_is_the_quoted_hash() {
+        assert_eq!(etag(HASH), "\"4e55a7af\"");
+    }
+
+    #[test]
+    fn if_none_match_lists() {
+        let etag = etag(HASH);
+        assert!(etag_listed(&HeaderValue::from_static("\"4e55a7af\""), &etag));
+        assert!(etag_listed(&HeaderValue::from_static("\"other\", W/\"4e55a7af\""), &etag));
+        assert!(etag_listed(&HeaderValue::from_static("*"), &etag));
+        assert!(!etag_listed(&HeaderValue::from_static("\"other\""), &etag));
+        assert!(!etag_listed(&HeaderValue::from_static("4e55a7af"), &etag));
+    }
+
+    #[test]
+    fn if_range_needs_a_strong_match() {
+        let etag = etag(HASH);
+        assert!(if_range_matches(&HeaderValue::from_static("\"4e55a7af\""), &etag));
+        assert!(!if_range_matches(&HeaderValue::from_static("W/\"4e55a7af\""), &etag));
+        assert!(!if_range_matches(&HeaderValue::from_static("\"other\""), &etag));
+        assert!(!if_range_matches(&HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"), &etag));
+    }
+}
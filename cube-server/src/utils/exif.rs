@@ -0,0 +1,189 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use chrono::{NaiveDate, NaiveDateTime};
+use cube_db::PhotoMetadata;
+use exif::{Exif, In, Tag, Value};
+
+use crate::utils::xmp::{fill_missing, read_xmp};
+
+/// Bytes read from the start of TIFF-based files. Their IFD0 and EXIF IFD are written before the image data,
+/// so the RAW data and previews (tens of MB) are never read.
+const MAX_TIFF_EXIF_BYTES: u64 = 1024 * 1024;
+
+/// Reads the EXIF metadata of a received file, completed with its embedded XMP metadata.
+///
+/// Supports JPEG, HEIF, PNG, WebP and TIFF-based RAW formats (CR2, NEF, ARW, DNG...). Only the first
+/// `MAX_TIFF_EXIF_BYTES` of TIFF-based files are read. Fields missing from EXIF are taken from the XMP packet,
+/// if any (see `utils::xmp`). XMP sidecar files are not read: phones upload each file on its own, so a sidecar
+/// arrives as a separate upload. It reads from disk, so this should run on a blocking thread.
+///
+/// # Arguments
+/// * `path` - The file to read.
+/// * `hash` - The upload hash, stored in the returned metadata.
+///
+/// # Returns
+/// `None` if the file has neither readable EXIF data nor XMP metadata.
+///
+/// # Example
+/// ```
+/// if let Some(metadata) = read_exif(&temp.path, &temp.hash) {
+///     println!("{:?}", metadata.camera_model);
+/// }
+/// ```
+pub fn read_exif(path: &Path, hash: &str) -> Option<PhotoMetadata> {
+    match (read_exif_fields(path, hash), read_xmp(path, hash)) {
+        (Some(mut metadata), Some(xmp)) => {
+            fill_missing(&mut metadata, xmp);
+            Some(metadata)
+        }
+        (metadata, xmp) => metadata.or(xmp),
+    }
+}
+
+/// Reads the EXIF metadata of a file, without XMP.
+fn read_exif_fields(path: &Path, hash: &str) -> Option<PhotoMetadata> {
+    let mut file = BufReader::new(File::open(path).ok()?);
+    let header = file.fill_buf().ok()?;
+    let is_tiff = header.starts_with(b"II*\0") || header.starts_with(b"MM\0*");
+    // `read_from_container` reads TIFF files whole, so they are parsed from a bounded read instead
+    let exif = if is_tiff {
+        read_tiff_exif(file)?
+    } else {
+        exif::Reader::new().read_from_container(&mut file).ok()?
+    };
+
+    Some(PhotoMetadata {
+        hash: hash.to_string(),
+        camera_make: text(&exif, Tag::Make),
+        camera_model: text(&exif, Tag::Model),
+        lens_model: text(&exif, Tag::LensModel),
+        exposure_time: exposure_time(&exif),
+        f_number: rational(&exif, Tag::FNumber),
+        iso: exif.get_field(Tag::PhotographicSensitivity, In::PRIMARY).and_then(|f| f.value.get_uint(0)),
+        focal_length: rational(&exif, Tag::FocalLength),
+        gps_latitude: coordinate(&exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, b'S'),
+        gps_longitude: coordinate(&exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, b'W'),
+        taken_at: taken_at(&exif),
+    })
+}
+
+/// Parses the EXIF data of a TIFF file from its first `MAX_TIFF_EXIF_BYTES`.
+///
+/// Fields and IFDs past that point are skipped, along with any other malformed entry.
+fn read_tiff_exif<R: Read>(reader: R) -> Option<Exif> {
+    let mut data = Vec::new();
+    reader.take(MAX_TIFF_EXIF_BYTES).read_to_end(&mut data).ok()?;
+
+    let exif = match exif::Reader::new().continue_on_error(true).read_raw(data) {
+        Ok(exif) => exif,
+        Err(exif::Error::PartialResult(partial)) => partial.into_inner().0,
+        Err(_) => return None,
+    };
+    // Not even one field was readable
+    exif.fields().next()?;
+    Some(exif)
+}
+
+/// First string of an ASCII field, without padding; `None` if missing or blank.
+fn text(exif: &Exif, tag: Tag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => {
+            let value = String::from_utf8_lossy(values.first()?);
+            let value = value.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+            (!value.is_empty()).then(|| value.to_string())
+        }
+        _ => None,
+    }
+}
+
+fn rational(exif: &Exif, tag: Tag) -> Option<f64> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(values) if values.first()?.denom != 0 => Some(values[0].to_f64()),
+        _ => None,
+    }
+}
+
+fn exposure_time(exif: &Exif) -> Option<String> {
+    match &exif.get_field(Tag::ExposureTime, In::PRIMARY)?.value {
+        Value::Rational(values) => format_exposure(values.first()?.num, values.first()?.denom),
+        _ => None,
+    }
+}
+
+/// Exposure time of `num/denom` seconds as photographers write it: `1/250` below one second, `2.5` above.
+pub fn format_exposure(num: u32, denom: u32) -> Option<String> {
+    if num == 0 || denom == 0 {
+        None
+    } else if num < denom {
+        Some(format!("1/{}", (denom as f64 / num as f64).round()))
+    } else {
+        Some(format!("{}", num as f64 / denom as f64))
+    }
+}
+
+/// GPS coordinate in decimal degrees; negative when the reference is `negative_ref` (south or west).
+fn coordinate(exif: &Exif, tag: Tag, ref_tag: Tag, negative_ref: u8) -> Option<f64> {
+    let degrees = match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(values) if values.len() >= 3 && values.iter().all(|v| v.denom != 0) => {
+            values[0].to_f64() + values[1].to_f64() / 60.0 + values[2].to_f64() / 3600.0
+        }
+        _ => return None,
+    };
+
+    let negative = matches!(
+        &exif.get_field(ref_tag, In::PRIMARY).map(|f| &f.value),
+        Some(Value::Ascii(values)) if values.first().and_then(|v| v.first()) == Some(&negative_ref)
+    );
+
+    Some(if negative { -degrees } else { degrees })
+}
+
+/// `DateTimeOriginal`, the camera's local time when the photo was taken.
+fn taken_at(exif: &Exif) -> Option<NaiveDateTime> {
+    let value = match &exif.get_field(Tag::DateTimeOriginal, In::PRIMARY)?.value {
+        Value::Ascii(values) => exif::DateTime::from_ascii(values.first()?).ok()?,
+        _ => return None,
+    };
+
+    NaiveDate::from_ymd_opt(value.year as i32, value.month as u32, value.day as u32)?
+        .and_hms_opt(value.hour as u32, value.minute as u32, value.second as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A little-endian TIFF whose IFD0 has a `Model` stored near the start and a `Make` stored past
+    /// `MAX_TIFF_EXIF_BYTES`, like a RAW file with its image data in between.
+    fn tiff() -> Vec<u8> {
+        let far = MAX_TIFF_EXIF_BYTES as u32 + 1024;
+        let mut data = b"II*\0".to_vec();
+        data.extend(8u32.to_le_bytes());
+        data.extend(2u16.to_le_bytes());
+        for (tag, offset) in [(0x010F_u16, far), (0x0110, 38)] {
+            data.extend(tag.to_le_bytes());
+            data.extend(2u16.to_le_bytes()); // ASCII
+            data.extend(5u32.to_le_bytes());
+            data.extend(offset.to_le_bytes());
+        }
+        data.extend(0u32.to_le_bytes());
+        data.extend(b"EOS\0\0");
+        data.resize(far as usize, 0);
+        data.extend(b"Cube\0");
+        data
+    }
+
+    #[test]
+    fn tiff_exif_is_read_from_the_start_of_the_file() {
+        let exif = read_tiff_exif(Cursor::new(tiff())).unwrap();
+        assert_eq!(text(&exif, Tag::Model).as_deref(), Some("EOS"));
+        assert_eq!(text(&exif, Tag::Make), None);
+    }
+
+    #[test]
+    fn tiff_without_readable_fields_has_no_exif() {
+        assert!(read_tiff_exif(Cursor::new(b"II*\0\xFF\xFF\xFF\x7F".to_vec())).is_none());
+    }
+}
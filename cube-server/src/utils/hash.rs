@@ -1,20 +1,42 @@
 use sha2::{Sha256, Digest};
 
-/// Computes the SHA-256 hash of the given binary data and returns it as a hexadecimal string.
+/// Incrementally computes a SHA-256 hash for data that arrives in chunks.
 ///
-/// # Arguments
-/// * `data` - A byte slice containing the data to hash.
-///
-/// # Returns
-/// A `String` containing the hexadecimal representation of the SHA-256 hash.
+/// Used when a file is streamed to disk, so the hash is known as soon as the
+/// last chunk is written without reading the file back.
 ///
 /// # Example
 /// ```
-/// let hash = compute_hash(b"hello world");
-/// println!("{}", hash);
+/// let mut hasher = StreamHasher::new();
+/// hasher.update(b"hello ");
+/// hasher.update(b"world");
+/// println!("{}", hasher.finalize());
 /// ```
-pub fn compute_hash(data: &[u8]) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(data);
-    format!("{:x}", hasher.finalize())
-}
\ No newline at end of file
+#[derive(Default)]
+pub struct StreamHasher {
+    inner: Sha256,
+}
+
+impl StreamHasher {
+    /// Creates an empty hasher.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds the next chunk of data into the hash.
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.inner.update(chunk);
+    }
+
+    /// Consumes the hasher and returns the hexadecimal representation of the SHA-256 hash.
+    pub fn finalize(self) -> String {
+        format!("{:x}", self.inner.finalize())
+    }
+}
+
+/// Returns true if `value` looks like a hash produced by [`StreamHasher`]: 64 lowercase hexadecimal digits.
+///
+/// Hashes sent by clients are used in file names, so they must be checked with this first.
+pub fn is_valid_hash(value: &str) -> bool {
+    value.len() == 64 && value.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
@@ -1,3 +1,10 @@
 pub mod hash;
+pub mod blob;
+pub mod desktop_key;
+pub mod etag;
+pub mod exif;
 pub mod file;
-pub mod path;
\ No newline at end of file
+pub mod path;
+pub mod thumbnail;
+pub mod throttle;
+pub mod xmp;
\ No newline at end of file
@@ -1,39 +1,222 @@
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
 use chrono::{DateTime, Datelike, Utc};
+use serde::Deserialize;
 use tokio::fs;
 
-/// Builds and ensures the output path for a file, organizing by username and optionally by year/month.
+use crate::utils::{blob::materialize, file::hash_file};
+
+/// Number of hash characters used by `CollisionPolicy::ShortHash` and `CollisionPolicy::HashFolder`.
+const SHORT_HASH_LEN: usize = 8;
+
+/// What `link_output_path` does when a different file already has the requested name.
+///
+/// - `Counter`: Adds the first free counter, `IMG_0001_1.CR2`, `IMG_0001_2.CR2`...
+/// - `ShortHash`: Adds the start of the new file's hash, `IMG_0001_4e55a7af.CR2`.
+/// - `HashFolder`: Keeps the name in a subfolder named after the start of the hash, `4e55a7af/IMG_0001.CR2`.
+///
+/// If the hash-based name is taken as well, a counter is added to it (see `candidate_path`).
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionPolicy {
+    #[default]
+    Counter,
+    ShortHash,
+    HashFolder,
+}
+
+/// Links a stored blob into the user's folder, organized by username and optionally by year/month, and returns
+/// the path it was linked at.
+///
+/// Only the last component of `filename` is used, so names sent by clients cannot point outside the user's
+/// folder. Each candidate path is created exclusively (see `materialize`), so an existing file is never replaced,
+/// even one created at the same moment by another upload. If a file with the same content is already there, its
+/// path is kept; otherwise `policy` picks the next candidate until one is free.
 ///
 /// # Arguments
+/// * `blob` - The blob holding the content, from `store_blob`.
 /// * `base` - The base directory as a string.
 /// * `username` - The username to include in the path.
 /// * `filename` - The name of the file to be saved.
-/// * `modified_at` - Optional modification date to organize files by year and month.
+/// * `hash` - The SHA-256 of the file, used to detect collisions and by the hash-based policies.
+/// * `modified_at` - Optional capture or modification date to organize files by year and month.
+/// * `policy` - How to name the file when the name is taken.
 ///
 /// # Returns
-/// A `PathBuf` representing the full output path where the file should be saved. The function also ensures
-/// that the directory exists, creating it if necessary.
+/// The full path where the file was linked. Missing directories are created.
 ///
 /// # Example
 /// ```
-/// let path = get_output_path("AppData/cube", "alice", "photo.raw", Some(Utc::now())).await;
+/// let path = link_output_path(&blob, "AppData/cube", "alice", "photo.raw", &hash, Some(Utc::now()), CollisionPolicy::Counter).await?;
 /// ```
-pub async fn get_output_path(
+pub async fn link_output_path(
+    blob: &Path,
     base: &str,
     username: &str,
     filename: &str,
+    hash: &str,
     modified_at: Option<DateTime<Utc>>,
-) -> PathBuf {
+    policy: CollisionPolicy,
+) -> io::Result<PathBuf> {
     let base_path = PathBuf::from(base);
-    let path = if let Some(modified) = modified_at {
-        let dir = base_path.join(username).join(modified.year().to_string()).join(format!("{:02}", modified.month()));
-        fs::create_dir_all(&dir).await.expect("Failed to create directory");
-        dir.join(filename)
+    let dir = if let Some(modified) = modified_at {
+        base_path.join(username).join(modified.year().to_string()).join(format!("{:02}", modified.month()))
     } else {
-        let dir = base_path.join(username);
-        fs::create_dir_all(&dir).await.expect("Failed to create directory");
-        dir.join(filename)
+        base_path.join(username)
     };
 
-    path
+    let filename = Path::new(filename)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| hash.to_string());
+
+    let mut attempt = 0;
+    loop {
+        let path = candidate_path(&dir, &filename, hash, policy, attempt);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        match materialize(blob, &path).await {
+            Ok(()) => return Ok(path),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if hash_file(&path).await.is_ok_and(|existing| existing == hash) {
+                    return Ok(path);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+        attempt += 1;
+    }
+}
+
+/// The path tried at `attempt` (0 for the name as received) when naming a file with `policy`.
+///
+/// - `Counter`: `IMG_0001.CR2`, `IMG_0001_1.CR2`, `IMG_0001_2.CR2`...
+/// - `ShortHash`: `IMG_0001.CR2`, `IMG_0001_4e55a7af.CR2`, `IMG_0001_4e55a7af_1.CR2`...
+/// - `HashFolder`: `IMG_0001.CR2`, `4e55a7af/IMG_0001.CR2`, `4e55a7af/IMG_0001_1.CR2`...
+fn candidate_path(dir: &Path, filename: &str, hash: &str, policy: CollisionPolicy, attempt: u32) -> PathBuf {
+    let short_hash = &hash[..SHORT_HASH_LEN.min(hash.len())];
+    match (policy, attempt) {
+        (_, 0) => dir.join(filename),
+        (CollisionPolicy::Counter, n) => dir.join(with_suffix(filename, &n.to_string())),
+        (CollisionPolicy::ShortHash, 1) => dir.join(with_suffix(filename, short_hash)),
+        (CollisionPolicy::ShortHash, n) => dir.join(with_suffix(filename, &format!("{short_hash}_{}", n - 1))),
+        (CollisionPolicy::HashFolder, 1) => dir.join(short_hash).join(filename),
+        (CollisionPolicy::HashFolder, n) => dir.join(short_hash).join(with_suffix(filename, &(n - 1).to_string())),
+    }
+}
+
+/// `IMG_0001.CR2` with suffix `1` gives `IMG_0001_1.CR2`.
+fn with_suffix(filename: &str, suffix: &str) -> String {
+    match filename.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => format!("{stem}_{suffix}.{extension}"),
+        _ => format!("{filename}_{suffix}"),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use uuid::Uuid;
+
+    const HASH: &str = "4e55a7af0123456789abcdef0123456789abcdef0123456789abcdef01234567";
+
+    /// A new directory under the system temp directory, removed when dropped. Shared with the other file tests.
+    pub(crate) struct TempDir(pub(crate) PathBuf);
+
+    impl TempDir {
+        pub(crate) fn new() -> Self {
+            Self::new_in(&std::env::temp_dir())
+        }
+
+        /// A new directory under `root`, e.g. on another filesystem.
+        pub(crate) fn new_in(root: &Path) -> Self {
+            let dir = root.join(format!("cube-path-{}", Uuid::new_v4()));
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Writes `content` as a blob and returns its path and hash.
+    async fn blob(dir: &Path, content: &str) -> (PathBuf, String) {
+        let path = dir.join(format!("blob-{}", Uuid::new_v4()));
+        fs::write(&path, content).await.unwrap();
+        let hash = hash_file(&path).await.unwrap();
+        (path, hash)
+    }
+
+    fn candidates(policy: CollisionPolicy) -> Vec<PathBuf> {
+        (0..3).map(|attempt| candidate_path(Path::new("ana"), "IMG_0001.CR2", HASH, policy, attempt)).collect()
+    }
+
+    #[test]
+    fn suffix_goes_before_the_extension() {
+        assert_eq!(with_suffix("IMG_0001.CR2", "1"), "IMG_0001_1.CR2");
+        assert_eq!(with_suffix("archive.tar.gz", "1"), "archive.tar_1.gz");
+        assert_eq!(with_suffix("README", "1"), "README_1");
+        assert_eq!(with_suffix(".hidden", "1"), ".hidden_1");
+    }
+
+    #[test]
+    fn candidates_follow_the_policy() {
+        assert_eq!(
+            candidates(CollisionPolicy::Counter),
+            ["ana/IMG_0001.CR2", "ana/IMG_0001_1.CR2", "ana/IMG_0001_2.CR2"].map(PathBuf::from)
+        );
+        assert_eq!(
+            candidates(CollisionPolicy::ShortHash),
+            ["ana/IMG_0001.CR2", "ana/IMG_0001_4e55a7af.CR2", "ana/IMG_0001_4e55a7af_1.CR2"].map(PathBuf::from)
+        );
+        assert_eq!(
+            candidates(CollisionPolicy::HashFolder),
+            ["ana/IMG_0001.CR2", "ana/4e55a7af/IMG_0001.CR2", "ana/4e55a7af/IMG_0001_1.CR2"].map(PathBuf::from)
+        );
+    }
+
+    #[tokio::test]
+    async fn links_into_the_user_and_month_folder() {
+        let temp = TempDir::new();
+        let base = temp.0.to_string_lossy().to_string();
+        let (blob, hash) = blob(&temp.0, "a").await;
+        let modified = Utc.with_ymd_and_hms(2024, 3, 9, 12, 0, 0).unwrap();
+
+        let policy = CollisionPolicy::Counter;
+        let path = link_output_path(&blob, &base, "ana", "../../IMG_0001.CR2", &hash, Some(modified), policy).await.unwrap();
+
+        assert_eq!(path, temp.0.join("ana/2024/03/IMG_0001.CR2"));
+        assert_eq!(fs::read_to_string(&path).await.unwrap(), "a");
+    }
+
+    #[tokio::test]
+    async fn keeps_the_same_content_and_renames_other_content() {
+        let temp = TempDir::new();
+        let base = temp.0.to_string_lossy().to_string();
+        let (first, first_hash) = blob(&temp.0, "a").await;
+        let (second, second_hash) = blob(&temp.0, "b").await;
+
+        let link = |blob: PathBuf, hash: String, policy| {
+            let base = base.clone();
+            async move { link_output_path(&blob, &base, "ana", "IMG.CR2", &hash, None, policy).await.unwrap() }
+        };
+
+        let original = link(first.clone(), first_hash.clone(), CollisionPolicy::Counter).await;
+        assert_eq!(link(first, first_hash, CollisionPolicy::Counter).await, original);
+
+        let renamed = link(second.clone(), second_hash.clone(), CollisionPolicy::Counter).await;
+        assert_eq!(renamed, temp.0.join("ana/IMG_1.CR2"));
+        assert_eq!(fs::read_to_string(&original).await.unwrap(), "a");
+        assert_eq!(fs::read_to_string(&renamed).await.unwrap(), "b");
+
+        let short = &second_hash[..SHORT_HASH_LEN];
+        let hashed = link(second, second_hash.clone(), CollisionPolicy::HashFolder).await;
+        assert_eq!(hashed, temp.0.join("ana").join(short).join("IMG.CR2"));
+    }
 }
@@ -1,4 +1,8 @@
+#[allow(clippy::module_inception)]
 mod ws; // já existente
+pub mod protocol;
+pub mod registry;
+pub mod transfers;
 
 pub use ws::*;
 
@@ -3,98 +3,177 @@
 //! This module provides WebSocket support for real-time communication between the server and clients.
 //!
 //! ## Features
-//! - Accepts WebSocket connections and manages a list of connected clients.
-//! - Allows broadcasting JSON messages to all connected clients.
-//! - Handles incoming messages and dispatches actions based on their content (e.g., "copy_files").
+//! - Accepts WebSocket connections from paired phones (with their session token) and from desktop viewers
+//!   running on the server machine (`?role=desktop`, with the desktop key instead of a token).
+//! - Registers every connection in the client `Registry` with its role, username and device, and removes it
+//!   when the socket closes.
+//! - Pings every client periodically and drops the ones that stop answering (see `CLIENT_TIMEOUT`).
+//! - Sends a `hello` frame on connect, then decodes incoming frames as `ClientMessage`s (see `protocol`)
+//!   and dispatches them, replying with an `error` message to anything it cannot handle.
+//! - Turns `copy_files` requests from desktop viewers into transfer jobs for the phone that announced each hash,
+//!   and re-dispatches a phone's unfinished jobs when it connects (see `transfers`).
 //!
 //! ## Main Functions
 //! - `ws_handler`: Axum handler to upgrade HTTP requests to WebSocket connections.
 //! - `handle_socket`: Manages the lifecycle of a WebSocket connection, including receiving and sending messages.
-//! - `broadcast_json`: Broadcasts a JSON message to all connected clients.
+//! - `disconnect_token`: Closes and removes the connections opened with a given token.
 
+use crate::auth::{AuthUser, DesktopViewer};
 use crate::state::AppState;
+use super::protocol::{parse_client_message, ClientMessage, ErrorCode, ServerMessage};
+use super::registry::{ClientRole, Registry, WsClient, CLIENT_QUEUE_SIZE};
+use super::transfers;
 use axum::extract::ws::{Message, WebSocketUpgrade, WebSocket};
-use axum::{extract::State, response::IntoResponse};
-use std::sync::Arc;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use std::{sync::Arc, time::{Duration, Instant}};
 use futures_util::{StreamExt, SinkExt};
-use tokio::sync::{Mutex, mpsc::UnboundedSender};
-use serde_json::Value;
+use tokio::sync::Mutex;
 
-/// Type alias for the list of connected WebSocket clients.
-pub type Clients = Arc<Mutex<Vec<UnboundedSender<Message>>>>;
+/// Interval between two pings sent to each client.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// A client that sent no frame (not even a pong) for this long is disconnected.
+pub const CLIENT_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// Type alias for the registry of connected WebSocket clients.
+pub type Clients = Arc<Mutex<Registry>>;
+
+/// Query string of `/ws`.
+#[derive(Deserialize)]
+pub struct WsParams {
+    #[serde(default)]
+    role: ClientRole,
+}
 
 /// Axum handler to upgrade HTTP requests to WebSocket connections.
+///
+/// # Flow
+/// - Desktop viewers must connect from the server machine itself with the desktop key.
+/// - Phones must present a valid session token.
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
     State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
+    Query(params): Query<WsParams>,
+    user: Result<AuthUser, (StatusCode, &'static str)>,
+    desktop: Result<DesktopViewer, (StatusCode, &'static str)>,
+) -> Response {
+    let user = match params.role {
+        ClientRole::Desktop => match desktop {
+            Ok(DesktopViewer) => None,
+            Err(rejection) => return rejection.into_response(),
+        },
+        ClientRole::Phone => match user {
+            Ok(user) => Some(user),
+            Err(rejection) => return rejection.into_response(),
+        },
+    };
+
+    ws.on_upgrade(move |socket| handle_socket(socket, state, params.role, user))
 }
 
 /// Handles the lifecycle of a WebSocket connection.
 ///
-/// - Adds the client to the global client list.
-/// - Spawns a task to send messages to the client.
-/// - Listens for incoming messages and dispatches actions.
-/// - Removes the client on disconnect.
-async fn handle_socket(stream: WebSocket, state: Arc<AppState>) {
+/// - Adds the client to the registry with a bounded message queue.
+/// - Re-dispatches the unfinished transfer jobs of a connecting phone.
+/// - Forwards queued messages to the socket and dispatches incoming text frames.
+/// - Pings the client every `HEARTBEAT_INTERVAL` and drops it after `CLIENT_TIMEOUT` without any frame.
+/// - Removes the client from the registry when the socket closes, times out or falls behind.
+async fn handle_socket(stream: WebSocket, state: Arc<AppState>, role: ClientRole, user: Option<AuthUser>) {
     let (mut sender, mut receiver) = stream.split();
-    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<Message>();
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Message>(CLIENT_QUEUE_SIZE);
 
-    // Add this client to the list
-    state.ws_state.lock().await.push(tx.clone());
+    // Greet the client so it can check the protocol version
+    if sender.send(ServerMessage::hello().to_ws()).await.is_err() {
+        return;
+    }
 
-    // Task to send messages to this socket
-    tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            let _ = sender.send(msg).await;
-        }
+    // Add this client to the registry; the registry holds the only sender of the queue
+    let id = state.ws_state.lock().await.register(WsClient {
+        role,
+        token: user.as_ref().map(|u| u.token.clone()),
+        username: user.as_ref().map(|u| u.username.clone()),
+        device_id: user.as_ref().map(|u| u.device_id.clone()),
+        tx,
     });
 
-    // Listen for incoming messages from this socket
-    while let Some(Ok(Message::Text(text))) = receiver.next().await {
-        println!("📩 WS received: {}", text);
-
-        match serde_json::from_str::<Value>(&text) {
-            Ok(payload) => {
-                if let Some(action) = payload.get("name").and_then(|a| a.as_str()) {
-                    match action {
-                        "copy_files" => {
-                            if let Some(hashes) = payload.get("payload").and_then(|h| h.get("hashes")).and_then(|h| h.as_array()) {
-                                let clients = state.ws_state.lock().await;
-
-                                for hash in hashes {
-                                    if let Some(h) = hash.as_str() {
-                                        let request = serde_json::json!({
-                                            "action": "send_raw",
-                                            "hash": h
-                                        });
-
-                                        println!("⬇️ Sending download for {}", h);
-                                        broadcast_json(&clients, &request);
-                                    }
+    // Ask a reconnecting phone for the files it still owes
+    if let Some(user) = &user {
+        transfers::dispatch_device(&state, &user.device_id).await;
+    }
+
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    let mut last_seen = Instant::now();
+
+    loop {
+        tokio::select! {
+            // Messages queued for this client; `None` once it was removed from the registry
+            outgoing = rx.recv() => {
+                let Some(msg) = outgoing else { break };
+                let closing = matches!(msg, Message::Close(_));
+                if sender.send(msg).await.is_err() || closing {
+                    break;
+                }
+            }
+
+            incoming = receiver.next() => {
+                last_seen = Instant::now();
+                match incoming {
+                    Some(Ok(Message::Text(text))) => {
+                        println!("📩 WS received: {}", text);
+
+                        match parse_client_message(&text) {
+                            Ok(ClientMessage::CopyFiles { hashes }) if role == ClientRole::Desktop => {
+                                transfers::request_copies(&state, id, hashes).await
+                            }
+                            Ok(ClientMessage::CopyFiles { .. }) => {
+                                let reply = ServerMessage::error(ErrorCode::Forbidden, "Only desktop viewers can request copies");
+                                if sender.send(reply.to_ws()).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Err(reply) => {
+                                println!("❌ Invalid WS message: {:?}", reply);
+                                if sender.send(reply.to_ws()).await.is_err() {
+                                    break;
                                 }
                             }
                         }
-
-                        _ => println!("⚠️ Unknown action: {}", action),
                     }
+                    Some(Ok(Message::Binary(_))) => {
+                        let reply = ServerMessage::error(ErrorCode::Malformed, "Binary frames are not supported");
+                        if sender.send(reply.to_ws()).await.is_err() {
+                            break;
+                        }
+                    }
+                    // Pings are answered by axum, pongs only refresh `last_seen`
+                    Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => {}
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
                 }
-
             }
-            Err(err) => {
-                println!("❌ Error decoding WS JSON: {err}");
+
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > CLIENT_TIMEOUT {
+                    println!("⏱️ WS client {} timed out", id);
+                    break;
+                }
+                if sender.send(Message::Ping(Vec::new())).await.is_err() {
+                    break;
+                }
             }
         }
     }
 
+    state.ws_state.lock().await.unregister(id);
+    let _ = sender.close().await;
     println!("🔌 WS disconnected");
 }
 
-/// Broadcasts a JSON message to all connected clients.
-fn broadcast_json(clients: &[UnboundedSender<Message>], json: &Value) {
-    let msg = Message::Text(json.to_string());
-    for client in clients.iter() {
-        let _ = client.send(msg.clone());
-    }
-}
\ No newline at end of file
+/// Closes and removes every connection opened with `token`.
+pub async fn disconnect_token(clients: &Clients, token: &str) {
+    clients.lock().await.disconnect_token(token);
+}
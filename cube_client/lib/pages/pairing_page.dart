@@ -1,8 +1,7 @@
-import 'dart:convert';
 import 'package:flutter/material.dart';
-import 'package:http/http.dart' as http;
 import 'package:mobile_scanner/mobile_scanner.dart';
-import 'package:shared_preferences/shared_preferences.dart';
+
+import '../services/auth_service.dart';
 
 class PairingPage extends StatefulWidget {
   const PairingPage({super.key});
@@ -19,34 +18,22 @@ class _PairingPageState extends State<PairingPage> {
   bool _scanned = false;
 
   Future<void> _authenticate(String ip, String code, String username) async {
-    try {
-      final res = await http.post(
-        Uri.parse("http://$ip:8080/auth"),
-        headers: {"Content-Type": "application/json"},
-        body: jsonEncode({"code": code, "username": username}),
-      );
+    final status = await AuthService.authenticate(ip, code, username);
 
-      if (res.statusCode == 200) {
-        final token = jsonDecode(res.body)['token'];
-        final prefs = await SharedPreferences.getInstance();
-        await prefs.setString("ip", ip);
-        await prefs.setString("token", token);
-        await prefs.setString("username", username);
-
-        ScaffoldMessenger.of(context).showSnackBar(
-          const SnackBar(content: Text("✅ Pareado com sucesso!")),
-        );
+    if (status == 200) {
+      ScaffoldMessenger.of(context).showSnackBar(
+        const SnackBar(content: Text("✅ Pareado com sucesso!")),
+      );
 
-        Navigator.pushReplacementNamed(context, '/gallery');
-      } else {
-        ScaffoldMessenger.of(context).showSnackBar(
-          const SnackBar(content: Text("❌ Código inválido")),
-        );
-        setState(() => _scanned = false);
-      }
-    } catch (e) {
+      Navigator.pushReplacementNamed(context, '/gallery');
+    } else if (status == null) {
+      ScaffoldMessenger.of(context).showSnackBar(
+        const SnackBar(content: Text("❌ Erro na conexão")),
+      );
+      setState(() => _scanned = false);
+    } else {
       ScaffoldMessenger.of(context).showSnackBar(
-        SnackBar(content: Text("❌ Erro na conexão: $e")),
+        const SnackBar(content: Text("❌ Código inválido")),
       );
       setState(() => _scanned = false);
     }
@@ -1,7 +1,7 @@
 import 'package:flutter/material.dart';
 import 'package:shared_preferences/shared_preferences.dart';
-import 'package:http/http.dart' as http;
 import 'package:provider/provider.dart';
+import '../services/auth_service.dart';
 import '../services/ws_service.dart';
 
 class SplashPage extends StatefulWidget {
@@ -28,20 +28,16 @@ class _SplashPageState extends State<SplashPage> {
     }
 
     final ip = prefs.getString("ip");
-    final token = prefs.getString("token");
 
+    // Renova o token se estiver perto de expirar; sem token válido é preciso parear de novo
+    final token = await AuthService.ensureFreshToken();
     if (ip != null && token != null) {
-      try {
-        final res = await http.get(Uri.parse("http://$ip:8080/poll_token?token=$token"));
-        if (res.statusCode == 200) {
-          // ✅ Conecta ao WebSocket ANTES de ir pra gallery
-          final ws = Provider.of<WebSocketService>(context, listen: false);
-          ws.connect(ip);
-
-          Navigator.pushReplacementNamed(context, '/gallery');
-          return;
-        }
-      } catch (_) {}
+      // ✅ Conecta ao WebSocket ANTES de ir pra gallery
+      final ws = Provider.of<WebSocketService>(context, listen: false);
+      ws.connect(ip);
+
+      Navigator.pushReplacementNamed(context, '/gallery');
+      return;
     }
 
     // Falhou ou sem dados → voltar para pareamento
@@ -30,9 +30,9 @@ class _UploadPageState extends State<UploadPage> {
     final ws = Provider.of<WebSocketService>(context, listen: false);
 
     ws.addListenerCallback((msg) async {
-      if (msg['action'] == 'send_raw' && msg['hash'] != null) {
-        print('📥 Pedido de envio do hash: ${msg['hash']}');
-        final hash = msg['hash'];
+      if (msg['name'] == 'send_raw' && msg['payload']?['hash'] != null) {
+        print('📥 Pedido de envio do hash: ${msg['payload']['hash']}');
+        final hash = msg['payload']['hash'];
         final paths = await PhotoManager.getAssetPathList(onlyAll: true);
         final allAssets = await paths.first.getAssetListRange(start: 0, end: 9999);
 
@@ -2,47 +2,119 @@ import 'dart:convert';
 import 'package:http/http.dart' as http;
 import 'package:shared_preferences/shared_preferences.dart';
 
+/// Sessão com o servidor: IP, usuário e o token devolvido por `/auth`.
+///
+/// Todas as rotas do servidor, exceto `/ping` e `/auth`, exigem o token: no cabeçalho
+/// `Authorization: Bearer` das requisições HTTP e em `?token=` na URL do WebSocket.
 class AuthService {
+  static const _ipKey = 'ip';
   static const _usernameKey = 'username';
-  static const _tokenKey = 'auth_token';
+  static const _tokenKey = 'token';
+  static const _expiresAtKey = 'token_expires_at';
 
-  static Future<void> saveCredentials(String username, String token) async {
+  /// O token é renovado em `/auth/refresh` quando falta menos que isso para expirar.
+  static const refreshBefore = Duration(days: 7);
+
+  static Future<void> saveSession({
+    required String ip,
+    required String username,
+    required String token,
+    required String expiresAt,
+  }) async {
     final prefs = await SharedPreferences.getInstance();
+    await prefs.setString(_ipKey, ip);
     await prefs.setString(_usernameKey, username);
     await prefs.setString(_tokenKey, token);
+    await prefs.setString(_expiresAtKey, expiresAt);
   }
 
-  static Future<Map<String, String>?> getCredentials() async {
+  static Future<String?> serverIp() async {
     final prefs = await SharedPreferences.getInstance();
-    final username = prefs.getString(_usernameKey);
-    final token = prefs.getString(_tokenKey);
-    if (username != null && token != null) {
-      return {'username': username, 'token': token};
-    }
-    return null;
+    return prefs.getString(_ipKey);
   }
 
-  static Future<void> clearCredentials() async {
+  static Future<String?> token() async {
+    final prefs = await SharedPreferences.getInstance();
+    return prefs.getString(_tokenKey);
+  }
+
+  /// Cabeçalhos de autenticação para as requisições HTTP ao servidor.
+  static Future<Map<String, String>> authHeaders() async {
+    final token = await ensureFreshToken();
+    return token == null ? {} : {'Authorization': 'Bearer $token'};
+  }
+
+  static Future<void> clearSession() async {
     final prefs = await SharedPreferences.getInstance();
-    await prefs.remove(_usernameKey);
     await prefs.remove(_tokenKey);
+    await prefs.remove(_expiresAtKey);
   }
 
-  static Future<String?> authenticate(String serverUrl, String code) async {
+  /// Pareia com o servidor usando o código do QR e guarda a sessão.
+  ///
+  /// Retorna o código HTTP da resposta, ou `null` se o servidor não respondeu.
+  static Future<int?> authenticate(String ip, String code, String username) async {
     try {
       final res = await http.post(
-        Uri.parse("http://$serverUrl/auth"),
+        Uri.parse("http://$ip:8080/auth"),
         headers: {"Content-Type": "application/json"},
-        body: jsonEncode({"code": code}),
+        body: jsonEncode({"code": code, "username": username}),
       );
 
       if (res.statusCode == 200) {
         final json = jsonDecode(res.body);
-        return json['token'] as String;
+        await saveSession(
+          ip: ip,
+          username: username,
+          token: json['token'] as String,
+          expiresAt: json['expires_at'] as String,
+        );
       }
+      return res.statusCode;
     } catch (e) {
       print("❌ Erro na autenticação: $e");
+      return null;
+    }
+  }
+
+  /// Retorna o token atual, renovando-o antes que expire.
+  ///
+  /// Retorna `null` se não há sessão ou se o servidor recusou o token (expirado ou revogado);
+  /// nesse caso a sessão é apagada e o celular precisa parear de novo. Se o servidor não
+  /// responder, o token atual é mantido.
+  static Future<String?> ensureFreshToken() async {
+    final prefs = await SharedPreferences.getInstance();
+    final ip = prefs.getString(_ipKey);
+    final token = prefs.getString(_tokenKey);
+    if (ip == null || token == null) return null;
+
+    final expiresAt = DateTime.tryParse(prefs.getString(_expiresAtKey) ?? '');
+    if (expiresAt != null && expiresAt.difference(DateTime.now()) > refreshBefore) {
+      return token;
+    }
+
+    try {
+      final res = await http.post(
+        Uri.parse("http://$ip:8080/auth/refresh"),
+        headers: {'Authorization': 'Bearer $token'},
+      );
+
+      if (res.statusCode == 200) {
+        final json = jsonDecode(res.body);
+        final refreshed = json['token'] as String;
+        await prefs.setString(_tokenKey, refreshed);
+        await prefs.setString(_expiresAtKey, json['expires_at'] as String);
+        print("🔑 Token renovado");
+        return refreshed;
+      }
+      if (res.statusCode == 401) {
+        print("🚫 Token recusado pelo servidor");
+        await clearSession();
+        return null;
+      }
+    } catch (e) {
+      print("❌ Falha ao renovar o token: $e");
     }
-    return null;
+    return token;
   }
 }
@@ -4,6 +4,8 @@ import 'package:photo_manager/photo_manager.dart';
 import 'package:http/http.dart' as http;
 import 'package:crypto/crypto.dart';
 
+import 'auth_service.dart';
+
 Future<void> sendThumbnailsToRust(List<AssetEntity> photos) async {
   final List<Map<String, dynamic>> payload = [];
 
@@ -31,8 +33,8 @@ Future<void> sendThumbnailsToRust(List<AssetEntity> photos) async {
 
   try {
     final response = await http.post(
-      Uri.parse("http://bruno-linux:8080/api/thumbs"),
-      headers: {"Content-Type": "application/json"},
+      Uri.parse("http://${await AuthService.serverIp()}:8080/api/thumbs"),
+      headers: {...await AuthService.authHeaders(), "Content-Type": "application/json"},
       body: jsonEncode(payload),
     );
 
@@ -3,6 +3,7 @@ import 'package:http/http.dart' as http;
 import 'package:crypto/crypto.dart';
 import 'dart:io';
 
+import 'auth_service.dart';
 import 'db_service.dart';
 
 class UploadService {
@@ -28,15 +29,16 @@ class UploadService {
         final fileBytes = await file.readAsBytes();
         final hash = sha256.convert(fileBytes).toString();
 
-        final uri = Uri.parse('http://bruno-linux:8080/upload_raw');
+        final uri = Uri.parse('http://${await AuthService.serverIp()}:8080/upload_raw');
 
+        // O servidor identifica o usuário pelo token
         final response = await http.post(
           uri,
           headers: {
+            ...await AuthService.authHeaders(),
             'Content-Type': 'application/octet-stream',
             'X-Filename': file.path.split('/').last,
             'X-Modified-At': asset.modifiedDateTime.toUtc().toIso8601String(),
-            'X-Username': "bruno",
           },
           body: fileBytes,
         );
@@ -61,10 +63,7 @@ class UploadService {
 
     onDone();
   }
-  static Future<void> uploadSingle(
-    AssetEntity asset, {
-    String username = "bruno",
-  }) async {
+  static Future<void> uploadSingle(AssetEntity asset) async {
     final file = await asset.originFile;
     if (file == null) {
       print("⚠️ Arquivo nulo para ${asset.id}");
@@ -74,15 +73,15 @@ class UploadService {
     final fileBytes = await file.readAsBytes();
     final hash = sha256.convert(fileBytes).toString();
 
-    final uri = Uri.parse('http://bruno-linux:8080/upload_raw');
+    final uri = Uri.parse('http://${await AuthService.serverIp()}:8080/upload_raw');
 
     final response = await http.post(
       uri,
       headers: {
+        ...await AuthService.authHeaders(),
         'Content-Type': 'application/octet-stream',
         'X-Filename': file.path.split('/').last,
         'X-Modified-At': asset.modifiedDateTime.toUtc().toIso8601String(),
-        'X-Username': username,
       },
       body: fileBytes,
     );
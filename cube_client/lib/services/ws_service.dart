@@ -3,6 +3,11 @@ import 'dart:convert';
 import 'package:flutter/foundation.dart';
 import 'package:web_socket_channel/web_socket_channel.dart';
 
+import 'auth_service.dart';
+
+/// Versão do protocolo WS falada por este cliente (ver cube-server/src/ws/protocol.rs).
+const int protocolVersion = 1;
+
 class WebSocketService with ChangeNotifier {
   WebSocketChannel? _channel;
   bool _connected = false;
@@ -19,17 +24,27 @@ class WebSocketService with ChangeNotifier {
     _onSessionLost = callback;
   }
 
-  void connect(String ip) {
+  Future<void> connect(String ip) async {
     _ip = ip;
 
     if (_connecting || _connected) return;
     _connecting = true;
     notifyListeners();
 
-    final url = 'ws://$ip:8080/ws';
-    print("📲 Conectando ao WebSocket: $url - $ip");
+    // Navegadores e o WebSocketChannel não enviam cabeçalhos no upgrade: o token vai na URL
+    final token = await AuthService.ensureFreshToken();
+    if (token == null) {
+      print('🚫 Sem token válido, é preciso parear de novo');
+      _connecting = false;
+      notifyListeners();
+      _onSessionLost?.call();
+      return;
+    }
+
+    final url = Uri.parse('ws://$ip:8080/ws').replace(queryParameters: {'token': token});
+    print("📲 Conectando ao WebSocket: $ip");
     try {
-      _channel = WebSocketChannel.connect(Uri.parse(url));
+      _channel = WebSocketChannel.connect(url);
     } catch (e) {
       print('❌ Falha ao conectar: $e');
       _scheduleReconnect();
@@ -49,6 +64,15 @@ class WebSocketService with ChangeNotifier {
             notifyListeners();
           }
 
+          if (data['name'] == 'hello' &&
+              data['payload']?['version'] != protocolVersion) {
+            print('⚠️ Versão do protocolo diferente: servidor '
+                '${data['payload']?['version']}, cliente $protocolVersion');
+          }
+          if (data['name'] == 'error') {
+            print('❌ Erro do servidor WS: ${data['payload']}');
+          }
+
           for (final listener in _listeners) {
             listener(data);
           }
@@ -100,7 +124,7 @@ class WebSocketService with ChangeNotifier {
 
   void send(Map<String, dynamic> message) {
     if (_connected && _channel != null) {
-      _channel!.sink.add(jsonEncode(message));
+      _channel!.sink.add(jsonEncode({'v': protocolVersion, ...message}));
     } else {
       print("❌ Tentando enviar WS sem conexão.");
     }
@@ -0,0 +1,40 @@
+//! # Database Worker
+//!
+//! This module owns the SQLite connection used by the embedded HTTP server.
+//!
+//! ## Features
+//! - Opens `uploads.db` in the app config directory on a dedicated thread and applies the pending migrations on
+//!   startup. The first release's tauri-plugin-sql kept `sqlite:uploads.db` at the same path, so its data is
+//!   adopted (see `cube_db::schema`).
+//! - Runs every `DbJob` received on the channel with a `cube_db::SqliteRepository`, the same typed
+//!   queries used by `cube-server`.
+//!
+//! ## Notes
+//! - The schema and its migrations are the ones of `cube-server` (see `cube_db::schema`).
+//! - Jobs are queued with `AppState::db`, which sends the query result back to the caller.
+//! - The worker does not depend on a webview, so the HTTP server works while the window is closed.
+
+use cube_db::{schema, SqliteRepository};
+use rusqlite::Connection;
+use std::{path::PathBuf, thread::JoinHandle};
+use tokio::sync::mpsc::Receiver;
+
+use crate::state::DbJob;
+
+/// Starts the database worker on its own thread.
+///
+/// # Flow
+/// - Opens the database at `path` and applies pending migrations.
+/// - Runs jobs until every `db_tx` sender is dropped.
+pub fn start_db_worker(path: PathBuf, mut db_rx: Receiver<DbJob>) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let conn = Connection::open(&path).expect("Falha ao abrir DB");
+        schema::migrate(&conn).expect("Falha ao aplicar migrações");
+        println!("🗄️ DB pronto em {}", path.display());
+
+        let repo = SqliteRepository::new(conn);
+        while let Some(job) = db_rx.blocking_recv() {
+            job(&repo);
+        }
+    })
+}
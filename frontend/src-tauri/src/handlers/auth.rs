@@ -1,47 +1,67 @@
-use axum::extract::ws::Message;
 use axum::{
-    extract::{Json, State},
+    extract::{ConnectInfo, Json, State},
     http::StatusCode,
     response::IntoResponse,
     Json as AxumJson,
 };
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use cube_db::{NewToken, Repository};
 use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use uuid::Uuid;
 
-use tauri::Emitter;
-
-use crate::state::{AppState, DbRequest};
-use serde_json::json;
+use crate::auth::{AuthUser, TokenBinding};
+use crate::state::AppState;
+use crate::utils::blob::BLOB_DIR;
+use crate::ws::{protocol::ServerMessage, registry::ClientRole};
 
 /// # Authentication Module
 ///
 /// This module implements a simple code-based authentication flow for opening a session on the server.
 /// The flow consists of two main endpoints:
 ///
-/// - **Code Generation (`generate_code_handler`)**: Generates a random 6-character code, saves it in the database along with the server's IP, and returns it to the client. The code expires in 60 seconds.
-/// - **Authentication (`auth_handler`)**: Receives a code and username, validates the code in the database, generates a UUID token for the session, and notifies all connected WebSocket clients with the new token.
+/// - **Code Generation (`get_qr_code` command, in `lib.rs`)**: Generates a random 6-character code with `generate_code`, saves it in the database along with the server's IP, and shows it as a QR code. The code expires in 60 seconds.
+/// - **Authentication (`auth_handler`)**: Receives a code and username, validates and consumes the code, generates a UUID token for the session bound to the phone's IP, and notifies desktop viewers that a phone was paired.
+/// - **Refresh (`refresh_handler`)**: Replaces the caller's token with a new one that has a fresh expiry, keeping the same device.
+///
+/// A background task (`start_code_sweeper`) periodically deletes expired codes and forgets old failed attempts.
 ///
 /// ## Structures
 /// - `CodeResponse`: Response when generating a code (code, ip, expires_in).
-/// - `AuthRequest`: Payload for authentication (code, username).
-/// - `AuthResponse`: Response when authenticating (token).
+/// - `AuthRequest`: Payload for authentication (code, username, optional device name and token binding).
+/// - `AuthResponse`: Response when authenticating or refreshing (token, expires_at).
 ///
 /// ## Authentication Flow
 /// 1. The client requests an authentication code.
 /// 2. The server generates and returns the code, IP, and expiration time.
 /// 3. The client sends the code and username for authentication.
-/// 4. If the code is valid, the server generates a token, saves it in the database, and notifies via WebSocket.
-/// 5. The client receives the token for use in subsequent requests.
+/// 4. If the code is valid, the server generates a token, saves it in the database, and notifies desktop viewers via WebSocket.
+/// 5. The client receives the token and sends it as `Authorization: Bearer <token>` on subsequent requests.
 ///
 /// ## Notes
-/// - The code does not check for expiration, only existence.
-/// - The returned IP is always the server's, not the client's.
+/// - Codes expire after `CODE_TTL_SECS` seconds and can only be used once.
+/// - Tokens expire after `TOKEN_TTL_DAYS` days unless refreshed.
+/// - After `MAX_FAILED_ATTEMPTS` failed attempts (see `utils::throttle`), the client IP is locked out of `/auth` for a while.
+/// - The IP returned by `get_qr_code` is the server's, so the phone knows where to connect.
+///   The IP stored in `tokens` is the phone's real address, taken from the `/auth` connection.
+/// - A token issued with `binding: "ip"` or `binding: "subnet"` is rejected when used from another IP or
+///   subnet (see `auth::TokenBinding`).
 /// - All tokens and codes are stored in SQLite.
 /// - Real-time notifications are sent via WebSocket.
 
+/// Number of seconds a pairing code stays valid.
+pub const CODE_TTL_SECS: u64 = 60;
+
+/// Number of days a session token stays valid.
+pub const TOKEN_TTL_DAYS: i64 = 30;
+
+/// Interval between two runs of the expired code sweeper.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Longest accepted username, in bytes.
+const MAX_USERNAME_LEN: usize = 64;
+
 /// Response when generating an authentication code.
 #[derive(Serialize)]
 pub struct CodeResponse {
@@ -55,86 +75,62 @@ pub struct CodeResponse {
 pub struct AuthRequest {
     pub code: String,
     pub username: String,
+    pub device_name: Option<String>,
+    #[serde(default)]
+    pub binding: TokenBinding,
 }
 
-/// Response when authenticating.
+/// Response when authenticating or refreshing a token.
 #[derive(Serialize)]
 pub struct AuthResponse {
     pub token: String,
-}
-
-/// Generates a 6-character code, saves it in the database, and returns it to the client.
-///
-/// # Flow
-/// - Generates a random code.
-/// - Saves it in the database with timestamp and IP.
-/// - Returns JSON with code, IP, and expiration time.
-pub async fn generate_code_handler(window: tauri::Window) -> Result<CodeResponse, String> {
-    let now = Utc::now().to_rfc3339();
-    let ip = local_ip_address::local_ip()
-        .map_err(|e| e.to_string())?
-        .to_string();
-    let code = generate_code(6);
-
-    let payload = json!({
-        "db": "sqlite:uploads.db",
-        "sql": "INSERT INTO auth_codes (code, ip, created_at) VALUES (?, ?, ?)
-                ON CONFLICT(code) DO UPDATE SET ip = excluded.ip, created_at = excluded.created_at",
-        "values": [code, ip, now]
-    });
-
-    window
-        .emit("plugin:sql|execute", payload)
-        .map_err(|e| format!("Erro ao executar comando SQL: {}", e))?;
-
-    Ok(CodeResponse {
-        code,
-        ip,
-        expires_in: 60,
-    })
+    pub expires_at: String,
 }
 
 /// Authenticates the user using code and username, returns a session token.
 ///
 /// # Flow
-/// - Validates code in the database.
-/// - Generates a UUID token.
-/// - Saves the token in the database.
-/// - Notifies WebSocket clients.
-/// - Returns token in JSON.
+/// - Rejects the request if the client IP is locked out.
+/// - Rejects usernames that are not a plain directory name (see `valid_username`).
+/// - Looks up the code and deletes it, so it cannot be used twice.
+/// - Rejects unknown or expired codes and records the failure for the client IP.
+/// - Generates a UUID token with an expiry date.
+/// - Saves the token in the database along with the client IP, the requested binding, a new device ID and the device name.
+/// - Notifies desktop viewers with a `paired` message.
+/// - Returns token and expiry in JSON.
 pub async fn auth_handler(
     State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Json(payload): Json<AuthRequest>,
 ) -> impl IntoResponse {
     let code = payload.code.clone();
     let username = payload.username.clone();
+    let client_ip = addr.ip();
 
-    println!("⚠️ Autenticando com o código {}", code);
-
-    // Canal para resposta do Tauri (IP buscado por código)
-    let (tx, rx) = tokio::sync::oneshot::channel();
-
-    let sql = "SELECT ip FROM auth_codes WHERE code = ?".to_string();
-    let values = vec![json!(code)];
-
-    if let Err(e) = state
-        .db_tx
-        .send(DbRequest {
-            sql,
-            values,
-            respond_to: tx,
-        })
-        .await
-    {
+    if let Some(remaining) = state.auth_throttle.lock().await.locked_for(client_ip) {
         return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Erro ao enviar consulta ao Tauri: {}", e),
+            StatusCode::TOO_MANY_REQUESTS,
+            format!(
+                "Too many failed attempts, try again in {} seconds",
+                remaining.as_secs()
+            ),
         )
             .into_response();
     }
 
-    let response = match tokio::time::timeout(std::time::Duration::from_secs(2), rx).await {
-        Ok(Ok(json_data)) => json_data,
+    if !valid_username(&payload.username) {
+        return (StatusCode::BAD_REQUEST, "Invalid username").into_response();
+    }
+
+    // Códigos são de uso único: consome o código qualquer que seja o resultado
+    let taken = code.clone();
+    let row = match tokio::time::timeout(
+        std::time::Duration::from_secs(2),
+        state.db(move |repo| repo.take_auth_code(&taken)),
+    )
+    .await
+    {
+        Ok(Ok(row)) => row,
         _ => {
             return (
                 StatusCode::UNAUTHORIZED,
@@ -144,58 +140,145 @@ pub async fn auth_handler(
         }
     };
 
-    // Parse do IP
-    let ip = response
-        .get(0)
-        .and_then(|row| row.get("ip"))
-        .and_then(|ip| ip.as_str())
-        .map(|s| s.to_string());
+    match row.map(|row| row.created_at) {
+        Some(Some(created_at)) if !is_expired(created_at) => {}
+        Some(_) => {
+            state.auth_throttle.lock().await.record_failure(client_ip);
+            return (StatusCode::UNAUTHORIZED, "Código expirado".to_string()).into_response();
+        }
+        None => {
+            state.auth_throttle.lock().await.record_failure(client_ip);
+            println!("🚫 Invalid code from {}", client_ip);
+            return (StatusCode::UNAUTHORIZED, "Código inválido".to_string()).into_response();
+        }
+    }
 
-    let ip = match ip {
-        Some(ip) => ip,
-        None => return (StatusCode::UNAUTHORIZED, "Código inválido".to_string()).into_response(),
-    };
+    state.auth_throttle.lock().await.record_success(client_ip);
 
     // Gera token
     let token = Uuid::new_v4().to_string();
-    let now = Utc::now().to_rfc3339();
+    let now = Utc::now();
+    let expires_at = now + chrono::Duration::days(TOKEN_TTL_DAYS);
+    let device_id = Uuid::new_v4().to_string();
+    let device_name = payload
+        .device_name
+        .clone()
+        .unwrap_or_else(|| username.clone());
 
-    // Envia comando de insert para Tauri via canal
-    let (tx, rx) = tokio::sync::oneshot::channel();
-    let insert_sql =
-        "INSERT INTO tokens (token, username, ip, created_at) VALUES (?, ?, ?, ?)".to_string();
-    let insert_values = vec![json!(token), json!(username), json!(ip), json!(now)];
+    let new_token = NewToken {
+        token: token.clone(),
+        username: username.clone(),
+        ip: client_ip.to_canonical().to_string(),
+        device_id: device_id.clone(),
+        device_name: device_name.clone(),
+        binding: payload.binding.as_str().to_string(),
+        created_at: now,
+        expires_at,
+    };
 
+    if let Err(e) = state.db(move |repo| repo.insert_token(&new_token)).await {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Erro ao inserir token: {}", e),
+        )
+            .into_response();
+    }
+
+    // Avisa os visualizadores desktop; o token em si só volta para o celular
+    let paired = ServerMessage::Paired {
+        username,
+        device_id,
+        device_name,
+    };
+    state
+        .ws_state
+        .lock()
+        .await
+        .send_to_role(ClientRole::Desktop, &paired);
+
+    let expires_at = expires_at.to_rfc3339();
+    (StatusCode::OK, AxumJson(AuthResponse { token, expires_at })).into_response()
+}
+
+/// Replaces the caller's token with a new one, extending the session.
+///
+/// # Flow
+/// - Generates a new UUID token with a fresh expiry date.
+/// - Replaces the old token in the database, keeping device ID, name and IP.
+/// - Moves open WebSocket connections of the old token to the new one.
+/// - Returns the new token and expiry in JSON.
+pub async fn refresh_handler(
+    State(state): State<Arc<AppState>>,
+    user: AuthUser,
+) -> impl IntoResponse {
+    let token = Uuid::new_v4().to_string();
+    let now = Utc::now();
+    let expires_at = now + chrono::Duration::days(TOKEN_TTL_DAYS);
+
+    let (old, new) = (user.token.clone(), token.clone());
     if let Err(e) = state
-        .db_tx
-        .send(DbRequest {
-            sql: insert_sql,
-            values: insert_values,
-            respond_to: tx,
-        })
+        .db(move |repo| repo.replace_token(&old, &new, expires_at, now))
         .await
     {
         return (
             StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Erro ao inserir token: {}", e),
+            format!("Erro ao renovar token: {}", e),
         )
             .into_response();
     }
 
-    let _ = rx.await; // ignorar resposta
+    state
+        .ws_state
+        .lock()
+        .await
+        .replace_token(&user.token, &token);
+
+    let expires_at = expires_at.to_rfc3339();
+    (StatusCode::OK, AxumJson(AuthResponse { token, expires_at })).into_response()
+}
+
+/// Periodically deletes expired pairing codes and forgets old failed attempts.
+///
+/// Runs forever; spawn it once at startup.
+pub async fn start_code_sweeper(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
 
-    // WebSocket
-    let msg = json!({ "token": token }).to_string();
-    let clients = state.ws_state.lock().await;
-    for tx in clients.iter() {
-        let _ = tx.send(Message::Text(msg.clone()));
+    loop {
+        interval.tick().await;
+
+        let cutoff = Utc::now() - chrono::Duration::seconds(CODE_TTL_SECS as i64);
+        if let Err(e) = state
+            .db(move |repo| repo.delete_auth_codes_before(cutoff))
+            .await
+        {
+            eprintln!("Erro ao limpar códigos: {e}");
+        }
+
+        state.auth_throttle.lock().await.purge();
     }
+}
+
+/// Returns true if a code created at `created_at` is older than `CODE_TTL_SECS`.
+/// Usernames become a directory under the upload folder, so only plain names are accepted:
+/// 1 to `MAX_USERNAME_LEN` ASCII letters, digits, `.`, `_` or `-`, excluding `.`, `..` and
+/// the blob store directory.
+fn valid_username(username: &str) -> bool {
+    !username.is_empty()
+        && username.len() <= MAX_USERNAME_LEN
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        && username != "."
+        && username != ".."
+        && username != BLOB_DIR
+}
 
-    (StatusCode::OK, AxumJson(AuthResponse { token })).into_response()
+fn is_expired(created_at: DateTime<Utc>) -> bool {
+    Utc::now().signed_duration_since(created_at).num_seconds() >= CODE_TTL_SECS as i64
 }
 
 /// Generates an alphanumeric code of `len` characters.
-fn generate_code(len: usize) -> String {
+pub fn generate_code(len: usize) -> String {
     rand::thread_rng()
         .sample_iter(&Alphanumeric)
         .take(len)
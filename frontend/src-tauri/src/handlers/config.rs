@@ -1,31 +1,36 @@
 /// # Configuration Handler
 ///
-/// This module provides an endpoint to set or update the upload directory used by the server.
+/// This module provides an endpoint to set the export directory and how received files are named when their name is
+/// already taken. Received files always go to the fixed internal directory.
 ///
 /// ## Endpoint
-/// - **set_config_handler**: Receives an optional upload directory path. If not provided, generates a default path based on the current year and month. Ensures the directory exists and updates the global application state.
+/// - **set_config_handler**: Receives an optional export directory path and collision policy. Ensures the internal and
+///   export directories exist and points the global application state at the internal directory.
 ///
 /// ## Structures
-/// - `ConfigPayload`: Payload for configuration (optional `upload_dir`).
+/// - `ConfigPayload`: Payload for configuration (optional `upload_dir` and `collision_policy`).
 use serde::Deserialize;
 use std::sync::Arc;
 use whoami;
 
 use crate::state::AppState;
+use crate::utils::path::CollisionPolicy;
 
 /// Payload for configuration requests.
-/// If `upload_dir` is not provided, a default directory is generated.
+/// `upload_dir` is the export directory; if it is not provided, `C:\Export` is used.
+/// If `collision_policy` (`counter`, `short_hash` or `hash_folder`) is not provided, the current one is kept.
 #[derive(Deserialize, Debug)]
 pub struct ConfigPayload {
     upload_dir: Option<String>,
+    collision_policy: Option<CollisionPolicy>,
 }
 
-/// Sets the upload directory for the server.
+/// Sets the export directory and the collision policy of the server.
 ///
 /// # Flow
-/// - Uses the provided directory or generates a default one based on the current year and month.
-/// - Creates the directory if it does not exist.
-/// - Updates the global application state with the new directory.
+/// - Creates the fixed internal directory, where received files are stored, if it does not exist.
+/// - Creates the provided export directory, or `C:\Export`, if it does not exist.
+/// - Updates the global application state with the internal directory and the new collision policy.
 /// - Returns a message indicating the result.
 ///
 /// # Returns
@@ -60,18 +65,17 @@ pub async fn set_config_handler(
     }
 
     // Atualiza o estado global
-    {
-        let mut dir = state.upload_dir.write().await;
-        *dir = internal_dir.to_string_lossy().to_string();
-    }
-    {
-        let mut export = state.upload_dir.write().await;
-        *export = export_dir.clone();
+    *state.upload_dir.write().await = internal_dir.to_string_lossy().to_string();
+
+    if let Some(policy) = payload.collision_policy {
+        *state.collision_policy.write().await = policy;
     }
+    let policy = *state.collision_policy.read().await;
 
     Ok(format!(
-        "📂 Internal directory: {}\n📤 Export directory: {}",
+        "📂 Internal directory: {}\n📤 Export directory: {}\n🏷️ Collision policy: {:?}",
         internal_dir.to_string_lossy(),
-        export_dir
+        export_dir,
+        policy
     ))
 }
@@ -0,0 +1,78 @@
+//! # Paired Devices Handler
+//!
+//! This module provides administration endpoints and commands for the devices paired with the server.
+//!
+//! ## Endpoints
+//! - **list_devices_handler** (`GET /admin/devices`): Lists every paired device with username, IP and last-seen time.
+//! - **revoke_device_handler** (`DELETE /admin/devices/:id`): Deletes the device's token and closes its WebSocket connections.
+//!
+//! ## Notes
+//! - Devices are rows of the `tokens` table, identified by `device_id` so tokens are never exposed.
+//! - Both endpoints only accept the desktop app of the server machine, with the desktop key (see
+//!   `auth::require_desktop`), and send no CORS headers, so pages on other sites cannot call them.
+//! - `list_devices` and `revoke_device` are also exposed to the desktop UI as Tauri commands.
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use cube_db::{Device, Repository};
+use std::sync::Arc;
+
+use crate::state::AppState;
+use crate::ws::disconnect_token;
+
+/// Lists every paired device, most recently seen first.
+pub async fn list_devices_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    match list_devices(&state).await {
+        Ok(devices) => (StatusCode::OK, Json(devices)).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error listing devices: {e}"),
+        )
+            .into_response(),
+    }
+}
+
+/// Revokes a paired device.
+///
+/// # Flow
+/// - Deletes the device's token, so further requests with it are rejected.
+/// - Closes the WebSocket connections opened with that token.
+pub async fn revoke_device_handler(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match revoke_device(&state, &id).await {
+        Ok(true) => (StatusCode::OK, "Device revoked").into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, "Device not found").into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error revoking device: {e}"),
+        )
+            .into_response(),
+    }
+}
+
+/// Reads every paired device from the `tokens` table.
+pub async fn list_devices(state: &AppState) -> Result<Vec<Device>, String> {
+    state.db(|repo| repo.list_devices()).await
+}
+
+/// Deletes the token of device `id` and closes its WebSocket connections.
+///
+/// # Returns
+/// `false` if no device has this ID.
+pub async fn revoke_device(state: &AppState, id: &str) -> Result<bool, String> {
+    let device_id = id.to_string();
+    let token = match state.db(move |repo| repo.revoke_device(&device_id)).await? {
+        Some(token) => token,
+        None => return Ok(false),
+    };
+
+    disconnect_token(&state.ws_state, &token).await;
+    println!("🔒 Device {} revoked", id);
+    Ok(true)
+}
@@ -1,4 +1,8 @@
 pub mod auth;
 pub mod config;
+pub mod devices;
+pub mod files;
+pub mod storage;
 pub mod thumbs;
 pub mod upload_raw;
+pub mod upload_session;
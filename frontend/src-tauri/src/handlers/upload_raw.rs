@@ -6,50 +6,66 @@
 //! - **upload_raw_handler**: Receives a file upload (with metadata in headers), saves it to disk, updates the database, and notifies connected WebSocket clients.
 //!
 //! ## Flow
-//! 1. Extracts username, filename, and modification date from HTTP headers.
-//! 2. Reads the file body and computes its hash.
-//! 3. Checks if the file (by hash) already exists in the database; if so, ignores the upload.
-//! 4. Determines the output path based on user and date, and saves the file.
-//! 5. Inserts the file metadata into the database.
-//! 6. Notifies all connected WebSocket clients about the new upload.
-//! 7. Returns a success message.
+//! 1. Takes the username from the caller's session token, and filename and modification date from HTTP headers.
+//! 2. Streams the file body to a temporary file in the upload directory, computing its hash on the way.
+//! 3. Checks if the user already uploaded a file with the same hash; if so, discards the temporary file.
+//! 4. Reads the file's EXIF metadata, moves the content into the blob store (`blobs/ab/cdef…`, see
+//!    `utils::blob`) unless another user already stored it, and reflinks, hard links or copies it at the output
+//!    path based on user and capture date (EXIF `DateTimeOriginal`, or `X-Modified-At`). A different file with
+//!    the same name is never overwritten: the configured `CollisionPolicy` picks another name.
+//! 5. Records the saved path, size, owner, capture time, upload time and EXIF metadata in the database.
+//! 6. Generates the file's thumbnails, so files uploaded without `/api/thumbs` still show in the grid. Content
+//!    already stored by another user keeps its thumbnails.
+//! 7. Notifies all connected WebSocket clients about the new upload, with its final path, and completes its
+//!    transfer jobs.
+//! 8. Returns a success message.
+//!
+//! Steps 3 to 7 live in [`store_upload`] so that resumable uploads (see `upload_session`) finish
+//! exactly like single-request uploads.
 
 use axum::{
-    body::{to_bytes, Body},
-    extract::{ws::Message, State},
+    body::Body,
+    extract::State,
     http::{HeaderMap, StatusCode},
     response::IntoResponse,
 };
 
 use chrono::{DateTime, Utc};
-use serde_json::json;
-use std::sync::Arc;
+use cube_db::{Repository, StoredFile};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use uuid::Uuid;
 
-use crate::state::{AppState, DbRequest};
-use crate::utils::{file::save_file, hash::compute_hash, path::get_output_path};
+use crate::auth::AuthUser;
+use crate::state::AppState;
+use crate::utils::{
+    blob::store_blob,
+    exif::read_exif,
+    file::{discard_file, stream_to_temp, TempUpload},
+    path::link_output_path,
+    thumbnail::generate_thumbnails,
+};
+use crate::ws::{protocol::ServerMessage, registry::ClientRole, transfers};
 
 /// Handles RAW file uploads.
 ///
 /// # Flow
-/// - Extracts metadata from headers.
-/// - Reads and saves the file.
-/// - Checks for duplicates by hash.
+/// - Extracts metadata from headers; the username comes from the session token.
+/// - Streams the body to a temporary file while hashing it.
+/// - Checks for duplicates by hash in the user's library.
+/// - Moves the file to its final path.
 /// - Updates the database.
 /// - Notifies WebSocket clients.
 /// - Returns a status message.
 
 pub async fn upload_raw_handler(
     State(state): State<Arc<AppState>>,
+    AuthUser { username, .. }: AuthUser,
     headers: HeaderMap,
     body: Body,
 ) -> impl IntoResponse {
-    let username = headers
-        .get("X-Username")
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("default")
-        .to_string();
-
     let filename = headers
         .get("X-Filename")
         .and_then(|v| v.to_str().ok())
@@ -62,92 +78,203 @@ pub async fn upload_raw_handler(
         .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
         .map(|dt| dt.with_timezone(&Utc));
 
-    let data = match to_bytes(body, usize::MAX).await {
-        Ok(bytes) => bytes.to_vec(),
+    let dir = state.upload_dir.read().await.clone();
+
+    let temp = match stream_to_temp(&PathBuf::from(&dir), body.into_data_stream()).await {
+        Ok(temp) => temp,
         Err(_) => return (StatusCode::BAD_REQUEST, "Erro ao ler o arquivo".to_string()),
     };
 
-    let hash = compute_hash(&data);
+    match store_upload(&state, temp, &dir, &username, &filename, modified_at).await {
+        Ok(StoreOutcome::Stored(_)) => (StatusCode::OK, "Upload Ended!".to_string()),
+        Ok(StoreOutcome::Duplicate) => (StatusCode::OK, "The file already exists".to_string()),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+/// Result of [`store_upload`].
+pub enum StoreOutcome {
+    /// The file was moved to the given path.
+    Stored(PathBuf),
+    /// The user already uploaded a file with the same hash; the temporary file was discarded.
+    Duplicate,
+}
+
+/// Moves a fully received temporary file into the upload directory.
+///
+/// # Flow
+/// - Checks for duplicates by hash in the user's library; duplicates are discarded. Files only announced with a
+///   thumbnail are not duplicates.
+/// - Reads the EXIF metadata of the file.
+/// - Atomically moves the file into the blob store, or discards it if the content is already stored.
+/// - Links the blob into the user's folder, by date; the EXIF capture date is preferred over `modified_at`. Taken
+///   names are resolved with the configured `CollisionPolicy`, and existing files are never replaced (see
+///   `utils::path::link_output_path`).
+/// - Records the file in the database: path, size, owner, capture and upload times, and its EXIF metadata. If the
+///   file cannot be recorded, the copy and a blob stored by this upload are removed and the error is returned, so
+///   the client retries; a failure to record the metadata is only logged.
+/// - Generates its thumbnails in `.thumbs` (see `utils::thumbnail`) and records the grid thumbnail, unless the
+///   content was already stored.
+/// - Notifies WebSocket clients with a `copied` event carrying the final path.
+/// - Marks the transfer jobs waiting for this hash on the user's devices as `done`, for stored files and duplicates alike.
+pub async fn store_upload(
+    state: &AppState,
+    temp: TempUpload,
+    dir: &str,
+    username: &str,
+    filename: &str,
+    modified_at: Option<DateTime<Utc>>,
+) -> Result<StoreOutcome, String> {
+    let hash = temp.hash.clone();
 
     // Consulta se já existe
-    let (tx, rx) = tokio::sync::oneshot::channel();
-    let sql = "SELECT EXISTS(SELECT 1 FROM uploads WHERE hash = ?)".to_string();
-    let values = vec![json!(hash)];
-
-    if let Err(e) = state
-        .db_tx
-        .send(DbRequest {
-            sql,
-            values,
-            respond_to: tx,
-        })
-        .await
+    let lookup = hash.clone();
+    let owner = username.to_string();
+    let owned = match tokio::time::timeout(
+        std::time::Duration::from_secs(2),
+        state.db(move |repo| {
+            Ok(repo
+                .find_owned_upload(&owner, &lookup)?
+                .is_some_and(|u| u.path.is_some()))
+        }),
+    )
+    .await
     {
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Erro ao consultar DB: {e}"),
-        );
-    }
-
-    let exists = match tokio::time::timeout(std::time::Duration::from_secs(2), rx).await {
-        Ok(Ok(payload)) => {
-            payload
-                .get(0)
-                .and_then(|row| row.as_object())
-                .and_then(|row| row.values().next()) // Pega a primeira coluna da row
-                .and_then(|v| v.as_i64())
-                .unwrap_or(0)
-                == 1
+        Ok(Ok(owned)) => owned,
+        Ok(Err(e)) => {
+            discard_file(&temp.path).await;
+            return Err(format!("Erro ao consultar DB: {e}"));
         }
-        _ => false,
+        Err(_) => false,
     };
 
-    if exists {
+    if owned {
+        discard_file(&temp.path).await;
         println!("📦 File {} already exists", hash);
-        return (StatusCode::OK, "The file already exists".to_string());
+        transfers::mark_done(state, &hash, username).await;
+        return Ok(StoreOutcome::Duplicate);
     }
 
-    // Salva o arquivo
-    let dir = state.upload_dir.read().await;
-    let path = get_output_path(&dir, &username, &filename, modified_at).await;
-    if let Err(e) = save_file(&path, &data).await {
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Erro ao salvar arquivo: {e}"),
-        );
-    }
+    // A data de captura do EXIF tem prioridade sobre a data de modificação enviada pelo celular
+    let exif_path = temp.path.clone();
+    let exif_hash = hash.clone();
+    let metadata = tokio::task::spawn_blocking(move || read_exif(&exif_path, &exif_hash))
+        .await
+        .ok()
+        .flatten();
+    let taken_at = metadata
+        .as_ref()
+        .and_then(|m| m.taken_at)
+        .map(|t| t.and_utc())
+        .or(modified_at);
 
-    // Insere no banco
-    let (_tx, _rx) = tokio::sync::oneshot::channel(); // Fire-and-forget
-    let insert_sql = "INSERT INTO uploads (hash, filename) VALUES (?, ?)".to_string();
-    let insert_values = vec![json!(hash), json!(filename)];
-
-    if let Err(e) = state
-        .db_tx
-        .send(DbRequest {
-            sql: insert_sql,
-            values: insert_values,
-            respond_to: _tx,
-        })
+    // O conteúdo fica uma única vez no repositório de blobs; a pasta do usuário recebe um link para ele
+    let lookup = hash.clone();
+    let recorded = state
+        .db(move |repo| repo.find_upload(&lookup))
         .await
+        .ok()
+        .flatten()
+        .and_then(|u| u.blob)
+        .map(PathBuf::from);
+    let (blob, known) = match store_blob(Path::new(dir), recorded.as_deref(), &temp).await {
+        Ok(stored) => stored,
+        Err(e) => {
+            discard_file(&temp.path).await;
+            return Err(format!("Erro ao salvar arquivo: {e}"));
+        }
+    };
+
+    // Cria o arquivo no destino final
+    let policy = *state.collision_policy.read().await;
+    let path = match link_output_path(&blob, dir, username, filename, &hash, taken_at, policy).await
     {
-        eprintln!("Erro ao inserir no DB: {e}");
+        Ok(path) => path,
+        Err(e) => {
+            // Um blob gravado por este upload ainda não está registrado; um que já existia fica
+            if !known {
+                discard_file(&blob).await;
+            }
+            return Err(format!("Erro ao salvar arquivo: {e}"));
+        }
+    };
+
+    // Insere no banco
+    let file = StoredFile {
+        hash: hash.clone(),
+        filename: filename.to_string(),
+        size: temp.size,
+        owner: username.to_string(),
+        path: path.clone(),
+        modified_at,
+        uploaded_at: Utc::now(),
+        blob: blob.clone(),
+    };
+    if let Err(e) = state.db(move |repo| repo.save_stored_file(&file)).await {
+        // Nada aponta para a cópia, nem para um blob gravado por este upload: uma nova tentativa recomeça do zero
+        discard_file(&path).await;
+        if !known {
+            discard_file(&blob).await;
+        }
+        return Err(format!("Erro ao inserir no DB: {e}"));
     }
+    if let Some(metadata) = metadata {
+        if let Err(e) = state
+            .db(move |repo| repo.save_photo_metadata(&metadata))
+            .await
+        {
+            eprintln!("Erro ao salvar metadados de {hash}: {e}");
+        }
+    }
+
+    println!(
+        "✅ Received and Saved: {} ({} bytes)",
+        path.to_string_lossy(),
+        temp.size
+    );
 
-    println!("✅ Received and Saved: {}", path.to_string_lossy());
+    // Gera as miniaturas antes de notificar, para que os visualizadores as encontrem ao atualizar a grade
+    if !known {
+        let thumb_source = path.clone();
+        let thumb_hash = hash.clone();
+        let generated = tokio::task::spawn_blocking(move || {
+            generate_thumbnails(&thumb_source, Path::new(".thumbs"), &thumb_hash)
+        })
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+        match generated {
+            Ok(thumb) => {
+                let thumb_hash = hash.clone();
+                let thumb = thumb
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string();
+                if let Err(e) = state
+                    .db(move |repo| repo.set_thumb(&thumb_hash, &thumb))
+                    .await
+                {
+                    eprintln!("Erro ao salvar miniatura de {hash}: {e}");
+                }
+            }
+            Err(e) => eprintln!("⚠️ Sem miniatura para {hash}: {e}"),
+        }
+    }
 
-    // Notifica via WebSocket
-    let confirmation = json!({
-        "event": "copied",
-        "hash": hash,
-        "status": "success",
-        "path": path.to_string_lossy(),
-    });
+    // Notifica os aparelhos do usuário e os visualizadores desktop
+    let confirmation = ServerMessage::Copied {
+        hash: hash.to_string(),
+        status: "success".to_string(),
+        path: path.to_string_lossy().to_string(),
+    };
 
-    let clients = state.ws_state.lock().await;
-    for client in clients.iter() {
-        let _ = client.send(Message::Text(confirmation.to_string()));
+    {
+        let mut clients = state.ws_state.lock().await;
+        clients.send_to_user(username, &confirmation);
+        clients.send_to_role(ClientRole::Desktop, &confirmation);
     }
 
-    (StatusCode::OK, "Upload Ended!".to_string())
+    transfers::mark_done(state, &hash, username).await;
+
+    Ok(StoreOutcome::Stored(path))
 }
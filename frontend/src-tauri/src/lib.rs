@@ -1,19 +1,28 @@
+mod auth;
+mod db;
 mod handlers;
 mod state;
 mod tcp_server;
 mod utils;
 mod ws;
 
-use crate::handlers::auth::CodeResponse;
+use crate::db::start_db_worker;
+use crate::handlers::auth::{generate_code, start_code_sweeper, CodeResponse, CODE_TTL_SECS};
 use crate::handlers::config::{set_config_handler, ConfigPayload};
-use crate::handlers::thumbs::list_thumbs_handler;
-use crate::state::{AppState, DbRequest};
+use crate::handlers::devices;
+use crate::handlers::storage::start_fsck;
+use crate::handlers::thumbs::{index_existing_thumbs, list_thumbs, ListParams, PhotoPage};
+use crate::handlers::upload_session::start_session_sweeper;
+use crate::state::{AppState, DbJob};
+use crate::utils::{desktop_key::new_desktop_key, path::CollisionPolicy, throttle::AuthThrottle};
+use crate::ws::registry::Registry;
+use crate::ws::transfers::start_transfer_worker;
 
 use anyhow::Result;
 use chrono::Utc;
+use cube_db::{Device, Repository};
 use dirs::picture_dir;
-use rand::{distributions::Alphanumeric, Rng};
-use tauri_plugin_sql::{Migration, MigrationKind};
+use tauri::Manager;
 use tokio::{fs, sync::{mpsc, Mutex, RwLock}};
 
 use std::{
@@ -22,9 +31,15 @@ use std::{
     sync::Arc
 };
 
+/// One page of every user's thumbnails, as served to the desktop viewer by `/api/thumbs/list`.
 #[tauri::command]
-async fn thumbs_list(window: tauri::Window) {
-    list_thumbs_handler(window).await;
+async fn thumbs_list(
+    state: tauri::State<'_, Arc<AppState>>,
+    params: Option<ListParams>,
+) -> Result<PhotoPage, String> {
+    list_thumbs(state.inner(), None, params.unwrap_or_default())
+        .await
+        .map_err(|(_, e)| e)
 }
 
 #[tauri::command]
@@ -35,6 +50,25 @@ async fn set_config(
     set_config_handler(state.inner().clone(), payload).await
 }
 
+/// Desktop key of this launch, sent by the webview to the HTTP and WebSocket server (see `auth::DesktopViewer`).
+#[tauri::command]
+fn desktop_key(state: tauri::State<'_, Arc<AppState>>) -> String {
+    state.desktop_key.clone()
+}
+
+#[tauri::command]
+async fn list_devices(state: tauri::State<'_, Arc<AppState>>) -> Result<Vec<Device>, String> {
+    devices::list_devices(state.inner()).await
+}
+
+#[tauri::command]
+async fn revoke_device(
+    state: tauri::State<'_, Arc<AppState>>,
+    id: String,
+) -> Result<bool, String> {
+    devices::revoke_device(state.inner(), &id).await
+}
+
 fn test_tcp() {
     match TcpStream::connect("127.0.0.1:7878") {
         Ok(mut stream) => {
@@ -61,17 +95,23 @@ fn test_tcp() {
 }
 
 #[tauri::command]
-async fn get_qr_code(window: tauri::Window) -> Result<CodeResponse, String> {
-    let now = Utc::now().to_rfc3339();
+async fn get_qr_code(state: tauri::State<'_, Arc<AppState>>) -> Result<CodeResponse, String> {
     let ip = local_ip_address::local_ip()
         .map_err(|e| e.to_string())?
         .to_string();
     let code = generate_code(6);
 
+    // Guarda o código no banco do servidor HTTP, onde o `/auth` vai procurá-lo
+    let (saved_code, saved_ip) = (code.clone(), ip.clone());
+    state
+        .db(move |repo| repo.save_auth_code(&saved_code, &saved_ip, Utc::now()))
+        .await
+        .map_err(|e| format!("Erro ao salvar código: {e}"))?;
+
     Ok(CodeResponse {
         code,
         ip,
-        expires_in: 60,
+        expires_in: CODE_TTL_SECS,
     })
 }
 
@@ -83,11 +123,16 @@ pub async fn run() {
     
     test_tcp();
     let default_dir = prepare_upload_dir().await;
-    let (db_tx, db_rx) = mpsc::channel::<DbRequest>(32);
+    // O banco é aberto no `setup`, quando o diretório do app é conhecido; até lá as consultas esperam na fila
+    let (db_tx, db_rx) = mpsc::channel::<DbJob>(32);
+
     let app_state = AppState {
         upload_dir: Arc::new(RwLock::new(default_dir.clone())),
-        ws_state: Arc::new(Mutex::new(Vec::new())),
+        collision_policy: Arc::new(RwLock::new(CollisionPolicy::default())),
+        ws_state: Arc::new(Mutex::new(Registry::default())),
         db_tx,
+        auth_throttle: Arc::new(Mutex::new(AuthThrottle::default())),
+        desktop_key: new_desktop_key(),
     };
 
     let shared_state = Arc::new(app_state);
@@ -98,38 +143,43 @@ pub async fn run() {
         start_axum_server(axum_state).await;
     });
 
+    // Remove códigos de pareamento expirados
+    tokio::spawn(start_code_sweeper(shared_state.clone()));
+
+    // Remove sessões de upload expiradas e seus arquivos parciais
+    tokio::spawn(start_session_sweeper(shared_state.clone()));
+
+    // Reenvia pedidos de cópia que não chegaram
+    tokio::spawn(start_transfer_worker(shared_state.clone()));
+
+    // Registra as thumbs que já estavam em `.thumbs`, para que apareçam na lista
+    tokio::spawn(index_existing_thumbs(shared_state.clone()));
+
+    // Confere os blobs com as pastas dos usuários e refaz o lado que faltar
+    tokio::spawn(start_fsck(shared_state.clone()));
+
     //let tcp_server = start_tcp_server(shared_state.clone()).await;
 
     // Opcional: TCP server
     //tokio::spawn(tcp_server);
 
-    let db_path = std::env::current_dir().unwrap().join("uploads.db");
-    let db_url = format!("sqlite:{}", "uploads.db");
-
-    let sql = include_str!("./migrations/create_tables.sql");
-    println!("📄 SQL da migração:\n{}", db_url);
-
-    let sql_plugin = tauri_plugin_sql::Builder::default()
-        .add_migrations(
-            &db_url,
-            vec![Migration {
-                version: 1,
-                description: "create tables",
-                sql: include_str!("./migrations/create_tables.sql"),
-                kind: MigrationKind::Up,
-            }],
-        )
-        .build();
-
-    println!("✅ Migração registrada");
-
     tauri::Builder::default()
         .plugin(tauri_plugin_dialog::init())
-        .plugin(sql_plugin)
+        .setup(move |app| {
+            // Banco do servidor HTTP, independente da webview. Fica no mesmo lugar que o `sqlite:uploads.db` do
+            // tauri-plugin-sql da primeira versão, para que as migrações adotem os dados dela
+            let dir = app.path().app_config_dir()?;
+            std::fs::create_dir_all(&dir)?;
+            start_db_worker(dir.join("uploads.db"), db_rx);
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             get_qr_code,
             set_config,
-            thumbs_list
+            desktop_key,
+            thumbs_list,
+            list_devices,
+            revoke_device
         ])
         .manage(shared_state)
         .run(tauri::generate_context!())
@@ -151,36 +201,79 @@ async fn prepare_upload_dir() -> String {
 
 async fn start_axum_server(state: Arc<AppState>) {
     use crate::handlers::{
-        auth::auth_handler, thumbs::upload_thumbs_handler, upload_raw::upload_raw_handler,
+        auth::{auth_handler, refresh_handler},
+        devices::{list_devices_handler, revoke_device_handler},
+        files::{delete_file_handler, download_file_handler},
+        storage::fsck_handler,
+        thumbs::{
+            list_thumbs_handler, serve_thumb_handler, upload_thumbs_handler,
+            upload_thumbs_multipart_handler, MAX_MULTIPART_BYTES,
+        },
+        upload_raw::upload_raw_handler,
+        upload_session::{
+            create_session_handler, finalize_session_handler, session_status_handler,
+            upload_chunk_handler,
+        },
     };
+    use crate::auth::{require_auth, require_desktop};
     use crate::ws::create_ws_router;
     use axum::{
-        routing::{get, post},
+        extract::DefaultBodyLimit,
+        middleware,
+        routing::{delete, get, post},
         Router,
     };
     use local_ip_address::local_ip;
-    use tower_http::{
-        cors::{Any, CorsLayer},
-        services::ServeDir,
-    };
+    use tower_http::cors::{Any, CorsLayer};
 
     let cors = CorsLayer::new()
         .allow_origin(Any)
         .allow_methods(Any)
         .allow_headers(Any);
 
-    let static_files = ServeDir::new(".thumbs").append_index_html_on_directories(false);
-    let thumbs_route = Router::new().nest_service("/thumbs", static_files);
+    // Routes that require a valid session token
+    let protected = Router::new()
+        .route("/upload_raw", post(upload_raw_handler)) // stay
+        .route("/upload_raw/sessions", post(create_session_handler))
+        .route(
+            "/upload_raw/sessions/:id",
+            get(session_status_handler).put(upload_chunk_handler),
+        )
+        .route(
+            "/upload_raw/sessions/:id/finalize",
+            post(finalize_session_handler),
+        )
+        .route("/api/thumbs", post(upload_thumbs_handler)) // stay
+        // Cada parte é limitada pelo handler; o lote é limitado a `MAX_MULTIPART_BYTES`
+        .route(
+            "/api/thumbs/multipart",
+            post(upload_thumbs_multipart_handler).layer(DefaultBodyLimit::max(MAX_MULTIPART_BYTES)),
+        )
+        .route("/auth/refresh", post(refresh_handler))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_auth));
+
+    // Device administration, only reachable by the desktop app of the server machine
+    let admin = Router::new()
+        .route("/admin/devices", get(list_devices_handler))
+        .route("/admin/devices/:id", delete(revoke_device_handler))
+        .route("/admin/fsck", post(fsck_handler))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_desktop));
 
     let app = Router::new()
         .route("/ping", get(|| async { "pong" }))
-        .route("/upload_raw", post(upload_raw_handler)) // stay
         .route("/auth", post(auth_handler)) // stay
-        .route("/api/thumbs", post(upload_thumbs_handler)) // stay
-        .merge(thumbs_route)
-        .merge(create_ws_router())
-        .with_state(state)
-        .layer(cors);
+        .merge(protected)
+        // Verifica o token ou a chave desktop por conta própria, ver `auth::Caller`
+        .route("/api/thumbs/list", get(list_thumbs_handler))
+        .route(
+            "/api/files/:hash",
+            get(download_file_handler).delete(delete_file_handler),
+        )
+        .merge(create_ws_router()) // autentica celulares e visualizadores desktop por conta própria
+        .route("/thumbs/:file", get(serve_thumb_handler)) // verifica o token ou a chave desktop por conta própria
+        .layer(cors)
+        .merge(admin) // depois da camada de CORS, para que navegadores impeçam páginas de outros sites de chamá-la
+        .with_state(state);
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
 
@@ -190,15 +283,10 @@ async fn start_axum_server(state: Arc<AppState>) {
 
     println!("🌐 Servidor HTTP ativo em: http://{}", addr);
 
-    axum::serve(tokio::net::TcpListener::bind(addr).await.unwrap(), app)
-        .await
-        .unwrap();
-}
-
-fn generate_code(len: usize) -> String {
-    rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(len)
-        .map(char::from)
-        .collect()
+    axum::serve(
+        tokio::net::TcpListener::bind(addr).await.unwrap(),
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
@@ -1,3 +1,5 @@
+mod auth;
+mod db;
 mod handlers;
 mod state;
 mod tcp_server;
@@ -1,22 +1,45 @@
+use crate::utils::{path::CollisionPolicy, throttle::AuthThrottle};
 use crate::ws::Clients;
-use serde_json::Value;
+use cube_db::SqliteRepository;
 use std::sync::Arc;
-use tokio::sync::{oneshot, RwLock};
+use tokio::sync::{oneshot, Mutex, RwLock};
 
 /// Global application state shared across handlers.
 ///
 /// - `upload_dir`: The current upload directory, protected by an async RwLock.
-/// - `db`: The SQLite database connection, protected by an async Mutex.
+/// - `collision_policy`: How received files are named when their name is taken (see `utils::path`).
+/// - `db_tx`: Queue of the database worker; use `AppState::db` to run queries.
 /// - `ws_state`: The list of connected WebSocket clients.
+/// - `auth_throttle`: Failed `/auth` attempts per client IP, used for lockouts.
+/// - `desktop_key`: Secret of this launch that the webview sends to prove itself (see `auth::DesktopViewer`).
 #[derive(Clone)]
 pub struct AppState {
     pub upload_dir: Arc<RwLock<String>>,
+    pub collision_policy: Arc<RwLock<CollisionPolicy>>,
     pub ws_state: Clients,
-    pub db_tx: tokio::sync::mpsc::Sender<DbRequest>,
+    pub db_tx: tokio::sync::mpsc::Sender<DbJob>,
+    pub auth_throttle: Arc<Mutex<AuthThrottle>>,
+    pub desktop_key: String,
 }
 
-pub struct DbRequest {
-    pub sql: String,
-    pub values: Vec<Value>,
-    pub respond_to: oneshot::Sender<Value>,
+/// A closure run by the database worker with the repository.
+pub type DbJob = Box<dyn FnOnce(&SqliteRepository) + Send>;
+
+impl AppState {
+    /// Runs `f` on the database worker and waits for its result.
+    pub async fn db<T, F>(&self, f: F) -> Result<T, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&SqliteRepository) -> cube_db::Result<T> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job: DbJob = Box::new(move |repo| {
+            let _ = tx.send(f(repo));
+        });
+
+        self.db_tx.send(job).await.map_err(|e| e.to_string())?;
+        rx.await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| e.to_string())
+    }
 }
@@ -0,0 +1,7 @@
+use uuid::Uuid;
+
+/// Generates the desktop key of this launch, which the webview gets through the `desktop_key` command. Keys of
+/// previous launches stop working when the app restarts.
+pub fn new_desktop_key() -> String {
+    Uuid::new_v4().simple().to_string()
+}
@@ -0,0 +1,29 @@
+use axum::http::HeaderValue;
+
+/// `ETag` of content identified by a hash: the hash in quotes.
+pub fn etag(hash: &str) -> String {
+    format!("\"{hash}\"")
+}
+
+/// Returns true if an `If-None-Match` value is `*` or lists `etag`, compared weakly.
+///
+/// # Example
+/// ```
+/// if headers.get(header::IF_NONE_MATCH).is_some_and(|value| etag_listed(value, &etag)) {
+///     return StatusCode::NOT_MODIFIED.into_response();
+/// }
+/// ```
+pub fn etag_listed(value: &HeaderValue, etag: &str) -> bool {
+    value
+        .to_str()
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Returns true if an `If-Range` value names `etag`. `If-Range` needs a strong match, so weak tags and dates never
+/// match and the whole file is sent instead of the range.
+pub fn if_range_matches(value: &HeaderValue, etag: &str) -> bool {
+    value.as_bytes() == etag.as_bytes()
+}
@@ -1,21 +1,153 @@
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
 use std::io;
-use std::path::PathBuf;
-use tokio::fs;
+use std::path::{Path, PathBuf};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter},
+};
+use uuid::Uuid;
 
-/// Saves binary data to the specified file path asynchronously.
+use crate::utils::hash::StreamHasher;
+
+/// A file that has been fully streamed to a temporary location but not yet moved to its final path.
 ///
-/// # Arguments
-/// * `path` - The path where the file will be saved.
-/// * `data` - The binary data to write.
+/// - `path`: Location of the temporary file.
+/// - `hash`: SHA-256 of the written content.
+/// - `size`: Number of bytes written.
+pub struct TempUpload {
+    pub path: PathBuf,
+    pub hash: String,
+    pub size: u64,
+}
+
+/// Streams a body into a temporary file inside `dir`, hashing it as it is written.
 ///
-/// # Panics
-/// Panics if the file cannot be written.
+/// The temporary file lives in the same directory tree as the final destination so it can later be
+/// moved into place with [`persist_file`] using an atomic rename. If reading or writing fails, the
+/// partial file is removed.
+///
+/// # Arguments
+/// * `dir` - The directory where the temporary file is created.
+/// * `stream` - The chunks of the body, as produced by `Body::into_data_stream`.
 ///
 /// # Example
 /// ```
-/// let path = PathBuf::from("output.raw");
-/// save_file(&path, b"file content").await;
+/// let temp = stream_to_temp(Path::new("uploads"), body.into_data_stream()).await?;
+/// println!("{} ({} bytes)", temp.hash, temp.size);
 /// ```
-pub async fn save_file(path: &PathBuf, data: &[u8]) -> io::Result<()> {
-    fs::write(path, data).await
+pub async fn stream_to_temp<S, E>(dir: &Path, stream: S) -> io::Result<TempUpload>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    fs::create_dir_all(dir).await?;
+    let path = dir.join(format!(".{}.part", Uuid::new_v4()));
+
+    match write_stream(&path, stream).await {
+        Ok((hash, size)) => Ok(TempUpload { path, hash, size }),
+        Err(e) => {
+            discard_file(&path).await;
+            Err(e)
+        }
+    }
+}
+
+async fn write_stream<S, E>(path: &Path, mut stream: S) -> io::Result<(String, u64)>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let mut file = BufWriter::new(fs::File::create(path).await?);
+    let mut hasher = StreamHasher::new();
+    let mut size = 0u64;
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(io::Error::other)?;
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
+        size += chunk.len() as u64;
+    }
+
+    file.flush().await?;
+    file.get_ref().sync_all().await?;
+
+    Ok((hasher.finalize(), size))
+}
+
+/// Writes a body into an existing or new file starting at `offset`, without truncating it.
+///
+/// Used by resumable uploads, where each chunk lands at its own position in the partial file.
+/// Fails without writing past `limit` if the chunk would extend beyond it.
+///
+/// # Returns
+/// The number of bytes written.
+pub async fn write_stream_at<S, E>(
+    path: &Path,
+    offset: u64,
+    limit: u64,
+    mut stream: S,
+) -> io::Result<u64>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .await?;
+    file.seek(io::SeekFrom::Start(offset)).await?;
+
+    let mut file = BufWriter::new(file);
+    let mut written = 0u64;
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(io::Error::other)?;
+        if offset + written + chunk.len() as u64 > limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "chunk exceeds the declared file size",
+            ));
+        }
+        file.write_all(&chunk).await?;
+        written += chunk.len() as u64;
+    }
+
+    file.flush().await?;
+    file.get_ref().sync_all().await?;
+
+    Ok(written)
+}
+
+/// Computes the SHA-256 hash of a file on disk, reading it in chunks.
+pub async fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path).await?;
+    let mut hasher = StreamHasher::new();
+    let mut buf = vec![0u8; 1024 * 1024];
+
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(hasher.finalize())
+}
+
+/// Atomically moves a temporary file to its final destination.
+///
+/// # Arguments
+/// * `temp` - The temporary file produced by [`stream_to_temp`].
+/// * `dest` - The final path, usually from `blob_path`.
+pub async fn persist_file(temp: &Path, dest: &Path) -> io::Result<()> {
+    fs::rename(temp, dest).await
+}
+
+/// Removes a temporary file, ignoring errors if it no longer exists.
+pub async fn discard_file(path: &Path) {
+    let _ = fs::remove_file(path).await;
 }
@@ -1,3 +1,10 @@
+pub mod blob;
+pub mod desktop_key;
+pub mod etag;
+pub mod exif;
 pub mod file;
 pub mod hash;
 pub mod path;
+pub mod throttle;
+pub mod thumbnail;
+pub mod xmp;
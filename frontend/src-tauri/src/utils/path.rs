@@ -1,46 +1,131 @@
+use crate::utils::{blob::materialize, file::hash_file};
 use chrono::{DateTime, Datelike, Utc};
-use std::path::PathBuf;
+use serde::Deserialize;
+use std::io;
+use std::path::{Path, PathBuf};
 use tokio::fs;
 
-/// Builds and ensures the output path for a file, organizing by username and optionally by year/month.
+/// Number of hash characters used by `CollisionPolicy::ShortHash` and `CollisionPolicy::HashFolder`.
+const SHORT_HASH_LEN: usize = 8;
+
+/// What `link_output_path` does when a different file already has the requested name.
+///
+/// - `Counter`: Adds the first free counter, `IMG_0001_1.CR2`, `IMG_0001_2.CR2`...
+/// - `ShortHash`: Adds the start of the new file's hash, `IMG_0001_4e55a7af.CR2`.
+/// - `HashFolder`: Keeps the name in a subfolder named after the start of the hash, `4e55a7af/IMG_0001.CR2`.
+///
+/// If the hash-based name is taken as well, a counter is added to it (see `candidate_path`).
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionPolicy {
+    #[default]
+    Counter,
+    ShortHash,
+    HashFolder,
+}
+
+/// Links a stored blob into the user's folder, organized by username and optionally by year/month, and returns
+/// the path it was linked at.
+///
+/// Only the last component of `filename` is used, so names sent by clients cannot point outside the user's
+/// folder. Each candidate path is created exclusively (see `materialize`), so an existing file is never replaced,
+/// even one created at the same moment by another upload. If a file with the same content is already there, its
+/// path is kept; otherwise `policy` picks the next candidate until one is free.
 ///
 /// # Arguments
+/// * `blob` - The blob holding the content, from `store_blob`.
 /// * `base` - The base directory as a string.
 /// * `username` - The username to include in the path.
 /// * `filename` - The name of the file to be saved.
-/// * `modified_at` - Optional modification date to organize files by year and month.
+/// * `hash` - The SHA-256 of the file, used to detect collisions and by the hash-based policies.
+/// * `modified_at` - Optional capture or modification date to organize files by year and month.
+/// * `policy` - How to name the file when the name is taken.
 ///
 /// # Returns
-/// A `PathBuf` representing the full output path where the file should be saved. The function also ensures
-/// that the directory exists, creating it if necessary.
+/// The full path where the file was linked. Missing directories are created.
 ///
 /// # Example
 /// ```
-/// let path = get_output_path("AppData/cube", "alice", "photo.raw", Some(Utc::now())).await;
+/// let path = link_output_path(&blob, "AppData/cube", "alice", "photo.raw", &hash, Some(Utc::now()), CollisionPolicy::Counter).await?;
 /// ```
-pub async fn get_output_path(
+pub async fn link_output_path(
+    blob: &Path,
     base: &str,
     username: &str,
     filename: &str,
+    hash: &str,
     modified_at: Option<DateTime<Utc>>,
-) -> PathBuf {
+    policy: CollisionPolicy,
+) -> io::Result<PathBuf> {
     let base_path = PathBuf::from(base);
-    let path = if let Some(modified) = modified_at {
-        let dir = base_path
+    let dir = if let Some(modified) = modified_at {
+        base_path
             .join(username)
             .join(modified.year().to_string())
-            .join(format!("{:02}", modified.month()));
-        fs::create_dir_all(&dir)
-            .await
-            .expect("Failed to create directory");
-        dir.join(filename)
+            .join(format!("{:02}", modified.month()))
     } else {
-        let dir = base_path.join(username);
-        fs::create_dir_all(&dir)
-            .await
-            .expect("Failed to create directory");
-        dir.join(filename)
+        base_path.join(username)
     };
 
-    path
+    let filename = Path::new(filename)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| hash.to_string());
+
+    let mut attempt = 0;
+    loop {
+        let path = candidate_path(&dir, &filename, hash, policy, attempt);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        match materialize(blob, &path).await {
+            Ok(()) => return Ok(path),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if hash_file(&path)
+                    .await
+                    .is_ok_and(|existing| existing == hash)
+                {
+                    return Ok(path);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+        attempt += 1;
+    }
+}
+
+/// The path tried at `attempt` (0 for the name as received) when naming a file with `policy`.
+///
+/// - `Counter`: `IMG_0001.CR2`, `IMG_0001_1.CR2`, `IMG_0001_2.CR2`...
+/// - `ShortHash`: `IMG_0001.CR2`, `IMG_0001_4e55a7af.CR2`, `IMG_0001_4e55a7af_1.CR2`...
+/// - `HashFolder`: `IMG_0001.CR2`, `4e55a7af/IMG_0001.CR2`, `4e55a7af/IMG_0001_1.CR2`...
+fn candidate_path(
+    dir: &Path,
+    filename: &str,
+    hash: &str,
+    policy: CollisionPolicy,
+    attempt: u32,
+) -> PathBuf {
+    let short_hash = &hash[..SHORT_HASH_LEN.min(hash.len())];
+    match (policy, attempt) {
+        (_, 0) => dir.join(filename),
+        (CollisionPolicy::Counter, n) => dir.join(with_suffix(filename, &n.to_string())),
+        (CollisionPolicy::ShortHash, 1) => dir.join(with_suffix(filename, short_hash)),
+        (CollisionPolicy::ShortHash, n) => {
+            dir.join(with_suffix(filename, &format!("{short_hash}_{}", n - 1)))
+        }
+        (CollisionPolicy::HashFolder, 1) => dir.join(short_hash).join(filename),
+        (CollisionPolicy::HashFolder, n) => dir
+            .join(short_hash)
+            .join(with_suffix(filename, &(n - 1).to_string())),
+    }
+}
+
+/// `IMG_0001.CR2` with suffix `1` gives `IMG_0001_1.CR2`.
+fn with_suffix(filename: &str, suffix: &str) -> String {
+    match filename.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => format!("{stem}_{suffix}.{extension}"),
+        _ => format!("{filename}_{suffix}"),
+    }
 }
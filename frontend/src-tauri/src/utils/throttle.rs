@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Number of failed attempts allowed from one IP within `FAILURE_WINDOW` before it is locked out.
+pub const MAX_FAILED_ATTEMPTS: u32 = 5;
+
+/// Period over which failed attempts are counted.
+pub const FAILURE_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// How long an IP stays locked out after reaching `MAX_FAILED_ATTEMPTS`.
+pub const LOCKOUT_DURATION: Duration = Duration::from_secs(15 * 60);
+
+/// Failed attempts recorded for a single IP.
+struct Attempts {
+    failures: u32,
+    first_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Tracks failed authentication attempts per client IP and locks out IPs that fail too often.
+///
+/// # Example
+/// ```
+/// let mut throttle = AuthThrottle::default();
+/// if throttle.locked_for(ip).is_none() {
+///     throttle.record_failure(ip);
+/// }
+/// ```
+#[derive(Default)]
+pub struct AuthThrottle {
+    attempts: HashMap<IpAddr, Attempts>,
+}
+
+impl AuthThrottle {
+    /// Returns the remaining lockout time for `ip`, or `None` if it may try again.
+    pub fn locked_for(&self, ip: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        self.attempts
+            .get(&ip)
+            .and_then(|a| a.locked_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    /// Records a failed attempt, locking `ip` out once it reaches `MAX_FAILED_ATTEMPTS` within `FAILURE_WINDOW`.
+    pub fn record_failure(&mut self, ip: IpAddr) {
+        let now = Instant::now();
+        let attempts = self.attempts.entry(ip).or_insert(Attempts {
+            failures: 0,
+            first_failure: now,
+            locked_until: None,
+        });
+
+        if now.duration_since(attempts.first_failure) > FAILURE_WINDOW {
+            attempts.failures = 0;
+            attempts.first_failure = now;
+            attempts.locked_until = None;
+        }
+
+        attempts.failures += 1;
+        if attempts.failures >= MAX_FAILED_ATTEMPTS {
+            attempts.locked_until = Some(now + LOCKOUT_DURATION);
+        }
+    }
+
+    /// Clears the failed attempts of `ip` after a successful authentication.
+    pub fn record_success(&mut self, ip: IpAddr) {
+        self.attempts.remove(&ip);
+    }
+
+    /// Drops entries whose failure window and lockout have both ended.
+    pub fn purge(&mut self) {
+        let now = Instant::now();
+        self.attempts.retain(|_, a| {
+            let locked = a.locked_until.is_some_and(|until| until > now);
+            locked || now.duration_since(a.first_failure) <= FAILURE_WINDOW
+        });
+    }
+}
@@ -0,0 +1,448 @@
+use exif::{Exif, In, Tag};
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder},
+    metadata::Orientation,
+    DynamicImage, ImageFormat, ImageReader, Limits,
+};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::utils::hash::is_valid_hash;
+
+/// Longest edge, in pixels, of each generated thumbnail. The first one is the grid thumbnail.
+pub const THUMB_SIZES: [u32; 2] = [400, 1600];
+
+/// JPEG quality of stored thumbnails.
+const THUMB_QUALITY: u8 = 80;
+
+/// Formats thumbnails are stored in, with their file extension.
+const THUMB_FORMATS: [(ImageFormat, &str); 2] =
+    [(ImageFormat::Jpeg, "jpg"), (ImageFormat::Png, "png")];
+
+/// Largest thumbnail accepted from phones, in bytes.
+pub const MAX_UPLOADED_THUMB_BYTES: usize = 2 * 1024 * 1024;
+
+/// Largest width or height of a thumbnail accepted from phones, in pixels.
+const MAX_UPLOADED_THUMB_DIMENSION: u32 = 4096;
+
+/// Largest embedded preview of a RAW file that is read, in bytes. Larger JPEG images are RAW data, such as the
+/// main image of CR2 files, which is a single JPEG strip without `NewSubfileType`.
+const MAX_PREVIEW_BYTES: u64 = 8 * 1024 * 1024;
+
+/// Largest width or height of a received image or preview that is decoded, in pixels.
+const MAX_SOURCE_DIMENSION: u32 = 16384;
+
+/// Most memory the decoder may allocate for a received image or preview, in bytes.
+const MAX_SOURCE_ALLOC: u64 = 256 * 1024 * 1024;
+
+/// Path of a thumbnail inside `dir`: `<hash>.<extension>` for the grid size and
+/// `<hash>_<size>.<extension>` for the larger ones.
+pub fn thumb_path(dir: &Path, hash: &str, size: u32, extension: &str) -> PathBuf {
+    if size == THUMB_SIZES[0] {
+        dir.join(format!("{hash}.{extension}"))
+    } else {
+        dir.join(format!("{hash}_{size}.{extension}"))
+    }
+}
+
+/// Reverse of `thumb_path`: the hash of a thumbnail file name, or `None` if it is not one.
+pub fn thumb_hash(file_name: &str) -> Option<&str> {
+    let (stem, extension) = file_name.rsplit_once('.')?;
+    if !THUMB_FORMATS.iter().any(|(_, e)| *e == extension) {
+        return None;
+    }
+
+    let hash = match stem.split_once('_') {
+        Some((hash, size))
+            if size
+                .parse()
+                .is_ok_and(|size: u32| THUMB_SIZES[1..].contains(&size)) =>
+        {
+            hash
+        }
+        Some(_) => return None,
+        None => stem,
+    };
+    is_valid_hash(hash).then_some(hash)
+}
+
+/// Returns the grid thumbnail of a file, in whichever format it was stored.
+pub fn find_thumb(dir: &Path, hash: &str) -> Option<PathBuf> {
+    THUMB_FORMATS
+        .iter()
+        .map(|(_, extension)| thumb_path(dir, hash, THUMB_SIZES[0], extension))
+        .find(|path| path.exists())
+}
+
+/// Deletes every thumbnail of a file, in all sizes and formats. Missing thumbnails are ignored.
+pub fn remove_thumbnails(dir: &Path, hash: &str) {
+    for size in THUMB_SIZES {
+        for (_, extension) in THUMB_FORMATS {
+            let _ = std::fs::remove_file(thumb_path(dir, hash, size, extension));
+        }
+    }
+}
+
+/// Generates the thumbnails of a received file, one per entry of `THUMB_SIZES`.
+///
+/// JPEG and PNG files are decoded directly. For TIFF-based RAW files (DNG, CR2, NEF, ARW) the largest
+/// embedded JPEG preview is used, and for other formats (e.g. HEIC) the EXIF thumbnail. Images larger than
+/// `MAX_SOURCE_DIMENSION` or `MAX_SOURCE_ALLOC` are not decoded. The EXIF orientation is applied, images are
+/// never upscaled, and each thumbnail is written atomically as JPEG.
+///
+/// Only what is needed is read: the image itself for JPEG and PNG files, and the IFDs and the previews for RAW
+/// files. Decoding is still slow, so this should run on a blocking thread.
+///
+/// # Arguments
+/// * `source` - The received file.
+/// * `dir` - The thumbnail directory (`.thumbs`).
+/// * `hash` - The upload hash, used to name the thumbnails.
+///
+/// # Returns
+/// The path of the grid thumbnail.
+///
+/// # Example
+/// ```
+/// let thumb = generate_thumbnails(&path, Path::new(".thumbs"), &hash)?;
+/// ```
+pub fn generate_thumbnails(source: &Path, dir: &Path, hash: &str) -> io::Result<PathBuf> {
+    let mut file = BufReader::new(File::open(source)?);
+    let mut magic = [0; 4];
+    let is_tiff = file.read_exact(&mut magic).is_ok() && (&magic == b"II*\0" || &magic == b"MM\0*");
+    file.rewind()?;
+
+    let (image, orientation) = if is_tiff {
+        let tiff = read_tiff(&mut file)?;
+        let orientation = tiff
+            .orientation
+            .and_then(|value| Orientation::from_exif(u8::try_from(value).ok()?));
+        (largest_preview(&mut file, tiff.previews), orientation)
+    } else {
+        let exif = exif::Reader::new().read_from_container(&mut file).ok();
+        file.rewind()?;
+        let mut reader = ImageReader::new(&mut file).with_guessed_format()?;
+        reader.limits(source_limits());
+        let image = reader.decode().ok();
+        (
+            image.or_else(|| exif.as_ref().and_then(exif_thumbnail)),
+            exif.as_ref().and_then(orientation),
+        )
+    };
+
+    let mut image = image.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no decodable image or embedded preview",
+        )
+    })?;
+    if let Some(orientation) = orientation {
+        image.apply_orientation(orientation);
+    }
+
+    std::fs::create_dir_all(dir)?;
+    for size in THUMB_SIZES {
+        let thumb = if image.width() > size || image.height() > size {
+            image.thumbnail(size, size)
+        } else {
+            image.clone()
+        };
+        write_thumbnail(&thumb, ImageFormat::Jpeg, dir, hash, size)?;
+    }
+
+    Ok(thumb_path(dir, hash, THUMB_SIZES[0], "jpg"))
+}
+
+/// Validates a thumbnail sent by a phone and stores it as the grid thumbnail of `hash`.
+///
+/// # Flow
+/// - Rejects hashes that are not a SHA-256, as the hash names the stored file.
+/// - Rejects data larger than `MAX_UPLOADED_THUMB_BYTES` and anything that is not a JPEG or PNG image.
+/// - Decodes it, rejecting images wider or taller than `MAX_UPLOADED_THUMB_DIMENSION`.
+/// - Scales it down to the grid size and re-encodes it in its own format.
+/// - Stores it with the extension of that format, replacing a grid thumbnail in another format.
+///
+/// # Returns
+/// The path of the stored thumbnail, or why it was rejected.
+///
+/// # Example
+/// ```
+/// match save_uploaded_thumbnail(&bytes, Path::new(".thumbs"), &hash) {
+///     Ok(path) => println!("{}", path.display()),
+///     Err(reason) => println!("rejected: {reason}"),
+/// }
+/// ```
+pub fn save_uploaded_thumbnail(bytes: &[u8], dir: &Path, hash: &str) -> Result<PathBuf, String> {
+    if !is_valid_hash(hash) {
+        return Err("invalid hash".to_string());
+    }
+    if bytes.len() > MAX_UPLOADED_THUMB_BYTES {
+        return Err(format!(
+            "thumbnail is larger than {MAX_UPLOADED_THUMB_BYTES} bytes"
+        ));
+    }
+
+    let format = image::guess_format(bytes)
+        .ok()
+        .filter(|format| THUMB_FORMATS.iter().any(|(f, _)| f == format))
+        .ok_or("not a JPEG or PNG image")?;
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_UPLOADED_THUMB_DIMENSION);
+    limits.max_image_height = Some(MAX_UPLOADED_THUMB_DIMENSION);
+
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+    reader.limits(limits);
+    let image = reader.decode().map_err(|e| format!("invalid image: {e}"))?;
+
+    let size = THUMB_SIZES[0];
+    let image = if image.width() > size || image.height() > size {
+        image.thumbnail(size, size)
+    } else {
+        image
+    };
+
+    std::fs::create_dir_all(dir).map_err(|e| format!("could not save thumbnail: {e}"))?;
+    write_thumbnail(&image, format, dir, hash, size)
+        .map_err(|e| format!("could not save thumbnail: {e}"))
+}
+
+/// Decodes the largest JPEG preview of a TIFF file that can be decoded.
+fn largest_preview<R: Read + Seek>(
+    reader: &mut R,
+    mut previews: Vec<Range<u64>>,
+) -> Option<DynamicImage> {
+    previews.sort_by_key(|range| std::cmp::Reverse(range.end - range.start));
+    previews.into_iter().find_map(|range| {
+        let mut data = vec![0; usize::try_from(range.end - range.start).ok()?];
+        reader.seek(SeekFrom::Start(range.start)).ok()?;
+        reader.read_exact(&mut data).ok()?;
+        decode_jpeg(&data)
+    })
+}
+
+/// Decodes an embedded JPEG preview within the limits of received images.
+fn decode_jpeg(data: &[u8]) -> Option<DynamicImage> {
+    let mut reader = ImageReader::with_format(Cursor::new(data), ImageFormat::Jpeg);
+    reader.limits(source_limits());
+    reader.decode().ok()
+}
+
+/// Decoding limits of received images and their previews.
+fn source_limits() -> Limits {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_SOURCE_DIMENSION);
+    limits.max_image_height = Some(MAX_SOURCE_DIMENSION);
+    limits.max_alloc = Some(MAX_SOURCE_ALLOC);
+    limits
+}
+
+/// What `generate_thumbnails` needs from a TIFF file.
+struct TiffInfo {
+    /// Byte ranges of the JPEG images referenced by the IFD chain and its SubIFDs.
+    previews: Vec<Range<u64>>,
+    /// Orientation of the main image (IFD0), as stored in EXIF.
+    orientation: Option<u32>,
+}
+
+/// Reads the IFDs of a TIFF file, seeking to each one instead of loading the file.
+///
+/// Both `JPEGInterchangeFormat` previews and single-strip JPEG images are collected. IFDs of the full-resolution
+/// image (`NewSubfileType` 0) and lossless JPEG RAW data (compression 7) are skipped, and so are ranges larger than
+/// `MAX_PREVIEW_BYTES`, that go past the end of the file or that do not start with a JPEG marker.
+fn read_tiff<R: Read + Seek>(reader: &mut R) -> io::Result<TiffInfo> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    let mut header = [0; 8];
+    reader.rewind()?;
+    reader.read_exact(&mut header)?;
+
+    let mut tiff = Tiff {
+        reader,
+        little_endian: header.starts_with(b"II"),
+        len,
+    };
+    let first = u64::from(tiff.u32(&header[4..]));
+
+    let mut info = TiffInfo {
+        previews: Vec::new(),
+        orientation: None,
+    };
+    let mut pending = vec![first];
+    let mut visited = HashSet::new();
+
+    while let Some(ifd) = pending.pop() {
+        if ifd == 0 || visited.len() >= 32 || !visited.insert(ifd) {
+            continue;
+        }
+        let Some(count) = tiff.bytes_at(ifd, 2).map(|b| tiff.u16(&b) as u64) else {
+            continue;
+        };
+        let Some(entries) = tiff.bytes_at(ifd + 2, count as usize * 12) else {
+            continue;
+        };
+
+        let (mut compression, mut strips, mut strip_lengths) = (None, Vec::new(), Vec::new());
+        let (mut jpeg_offset, mut jpeg_length, mut subfile_type) = (None, None, None);
+
+        for entry in entries.chunks_exact(12) {
+            match tiff.u16(entry) {
+                0x00FE => subfile_type = tiff.values(entry).first().copied(),
+                0x0103 => compression = tiff.values(entry).first().copied(),
+                0x0111 => strips = tiff.values(entry),
+                0x0117 => strip_lengths = tiff.values(entry),
+                0x0201 => jpeg_offset = tiff.values(entry).first().copied(),
+                0x0202 => jpeg_length = tiff.values(entry).first().copied(),
+                0x0112 if ifd == first => info.orientation = tiff.values(entry).first().copied(),
+                0x014A => pending.extend(tiff.values(entry).into_iter().map(u64::from)),
+                _ => {}
+            }
+        }
+
+        // Skip the RAW image itself, only previews are wanted
+        if subfile_type != Some(0) && compression != Some(7) {
+            if let (Some(offset), Some(length)) = (jpeg_offset, jpeg_length) {
+                info.previews
+                    .push(u64::from(offset)..u64::from(offset) + u64::from(length));
+            }
+            if compression == Some(6) && strips.len() == 1 && strip_lengths.len() == 1 {
+                info.previews
+                    .push(u64::from(strips[0])..u64::from(strips[0]) + u64::from(strip_lengths[0]));
+            }
+        }
+
+        if let Some(next) = tiff.bytes_at(ifd + 2 + count * 12, 4) {
+            pending.push(u64::from(tiff.u32(&next)));
+        }
+    }
+
+    info.previews.retain(|range| {
+        range.end - range.start <= MAX_PREVIEW_BYTES
+            && range.end <= len
+            && tiff
+                .bytes_at(range.start, 2)
+                .is_some_and(|b| b == [0xFF, 0xD8])
+    });
+    Ok(info)
+}
+
+/// Random access to the values of a TIFF file, in its byte order.
+struct Tiff<'a, R> {
+    reader: &'a mut R,
+    little_endian: bool,
+    len: u64,
+}
+
+impl<R: Read + Seek> Tiff<'_, R> {
+    /// `len` bytes at `pos`; `None` if they go past the end of the file.
+    fn bytes_at(&mut self, pos: u64, len: usize) -> Option<Vec<u8>> {
+        if pos.checked_add(len as u64)? > self.len {
+            return None;
+        }
+        let mut bytes = vec![0; len];
+        self.reader.seek(SeekFrom::Start(pos)).ok()?;
+        self.reader.read_exact(&mut bytes).ok()?;
+        Some(bytes)
+    }
+
+    fn u16(&self, b: &[u8]) -> u16 {
+        if self.little_endian {
+            u16::from_le_bytes([b[0], b[1]])
+        } else {
+            u16::from_be_bytes([b[0], b[1]])
+        }
+    }
+
+    fn u32(&self, b: &[u8]) -> u32 {
+        let b = [b[0], b[1], b[2], b[3]];
+        if self.little_endian {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        }
+    }
+
+    /// SHORT, LONG and IFD values of a 12-byte IFD entry, inline when they fit in 4 bytes.
+    fn values(&mut self, entry: &[u8]) -> Vec<u32> {
+        let width = match self.u16(&entry[2..]) {
+            3 => 2,
+            4 | 13 => 4,
+            _ => return Vec::new(),
+        };
+        let size = width * self.u32(&entry[4..]).min(64) as usize;
+        let data = if size <= 4 {
+            entry[8..8 + size].to_vec()
+        } else {
+            let offset = u64::from(self.u32(&entry[8..]));
+            match self.bytes_at(offset, size) {
+                Some(data) => data,
+                None => return Vec::new(),
+            }
+        };
+        data.chunks_exact(width)
+            .map(|b| {
+                if width == 2 {
+                    u32::from(self.u16(b))
+                } else {
+                    self.u32(b)
+                }
+            })
+            .collect()
+    }
+}
+
+/// The JPEG thumbnail stored in the EXIF data (IFD1), if any.
+fn exif_thumbnail(exif: &Exif) -> Option<DynamicImage> {
+    let offset = exif
+        .get_field(Tag::JPEGInterchangeFormat, In::THUMBNAIL)?
+        .value
+        .get_uint(0)? as usize;
+    let length = exif
+        .get_field(Tag::JPEGInterchangeFormatLength, In::THUMBNAIL)?
+        .value
+        .get_uint(0)? as usize;
+    let data = exif.buf().get(offset..offset.checked_add(length)?)?;
+    decode_jpeg(data)
+}
+
+fn orientation(exif: &Exif) -> Option<Orientation> {
+    let value = exif
+        .get_field(Tag::Orientation, In::PRIMARY)?
+        .value
+        .get_uint(0)?;
+    Orientation::from_exif(u8::try_from(value).ok()?)
+}
+
+/// Encodes `image` as a `size` thumbnail of `hash`, writing it next to its final path and renaming it into
+/// place. Thumbnails of the same size in other formats are removed.
+fn write_thumbnail(
+    image: &DynamicImage,
+    format: ImageFormat,
+    dir: &Path,
+    hash: &str,
+    size: u32,
+) -> io::Result<PathBuf> {
+    let mut buffer = Vec::new();
+    let encoded = match format {
+        ImageFormat::Png => image.write_with_encoder(PngEncoder::new(&mut buffer)),
+        _ => DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut buffer, THUMB_QUALITY)),
+    };
+    encoded.map_err(io::Error::other)?;
+
+    let extension = THUMB_FORMATS
+        .iter()
+        .find(|(f, _)| *f == format)
+        .map_or("jpg", |(_, extension)| extension);
+    let path = thumb_path(dir, hash, size, extension);
+    let temp = path.with_extension(format!("{extension}.tmp"));
+    std::fs::write(&temp, buffer)?;
+    std::fs::rename(&temp, &path)?;
+
+    for (_, other) in THUMB_FORMATS.iter().filter(|(f, _)| *f != format) {
+        let _ = std::fs::remove_file(thumb_path(dir, hash, size, other));
+    }
+
+    Ok(path)
+}
@@ -0,0 +1,209 @@
+use chrono::{NaiveDate, NaiveDateTime};
+use cube_db::PhotoMetadata;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use crate::utils::exif::format_exposure;
+
+/// Bytes searched for an XMP packet from the start of a file. JPEG keeps it in an APP1 segment before the image
+/// data, and TIFF-based RAW files and HEIF usually write it before their image data too.
+const MAX_XMP_SCAN_BYTES: u64 = 1024 * 1024;
+
+const PACKET_START: &[u8] = b"<x:xmpmeta";
+const PACKET_END: &[u8] = b"</x:xmpmeta>";
+
+/// Reads the XMP metadata embedded in a received file.
+///
+/// Only the first `MAX_XMP_SCAN_BYTES` are searched for an `<x:xmpmeta>` packet. It reads from disk, so this
+/// should run on a blocking thread.
+///
+/// # Arguments
+/// * `path` - The file to read.
+/// * `hash` - The upload hash, stored in the returned metadata.
+///
+/// # Returns
+/// `None` if the file has no XMP packet, or none of the fields `PhotoMetadata` stores.
+pub fn read_xmp(path: &Path, hash: &str) -> Option<PhotoMetadata> {
+    let mut data = Vec::new();
+    File::open(path)
+        .ok()?
+        .take(MAX_XMP_SCAN_BYTES)
+        .read_to_end(&mut data)
+        .ok()?;
+    parse_xmp(&data, hash)
+}
+
+/// Fills the fields `metadata` lacks with those of `other`.
+pub fn fill_missing(metadata: &mut PhotoMetadata, other: PhotoMetadata) {
+    metadata.camera_make = metadata.camera_make.take().or(other.camera_make);
+    metadata.camera_model = metadata.camera_model.take().or(other.camera_model);
+    metadata.lens_model = metadata.lens_model.take().or(other.lens_model);
+    metadata.exposure_time = metadata.exposure_time.take().or(other.exposure_time);
+    metadata.f_number = metadata.f_number.or(other.f_number);
+    metadata.iso = metadata.iso.or(other.iso);
+    metadata.focal_length = metadata.focal_length.or(other.focal_length);
+    metadata.gps_latitude = metadata.gps_latitude.or(other.gps_latitude);
+    metadata.gps_longitude = metadata.gps_longitude.or(other.gps_longitude);
+    metadata.taken_at = metadata.taken_at.or(other.taken_at);
+}
+
+/// Parses the first XMP packet found in `data`.
+fn parse_xmp(data: &[u8], hash: &str) -> Option<PhotoMetadata> {
+    let start = find(data, PACKET_START)?;
+    let end = start + find(&data[start..], PACKET_END)? + PACKET_END.len();
+    let xmp = String::from_utf8_lossy(&data[start..end]);
+
+    let metadata = PhotoMetadata {
+        hash: hash.to_string(),
+        camera_make: property(&xmp, "tiff:Make"),
+        camera_model: property(&xmp, "tiff:Model"),
+        lens_model: property(&xmp, "exifEX:LensModel").or_else(|| property(&xmp, "aux:Lens")),
+        exposure_time: property(&xmp, "exif:ExposureTime")
+            .and_then(|v| rational(&v))
+            .and_then(|(n, d)| format_exposure(n, d)),
+        f_number: property(&xmp, "exif:FNumber")
+            .and_then(|v| rational(&v))
+            .map(|(n, d)| n as f64 / d as f64),
+        iso: property(&xmp, "exifEX:PhotographicSensitivity")
+            .or_else(|| property(&xmp, "exif:ISOSpeedRatings"))
+            .and_then(|v| v.parse().ok()),
+        focal_length: property(&xmp, "exif:FocalLength")
+            .and_then(|v| rational(&v))
+            .map(|(n, d)| n as f64 / d as f64),
+        gps_latitude: property(&xmp, "exif:GPSLatitude").and_then(|v| coordinate(&v)),
+        gps_longitude: property(&xmp, "exif:GPSLongitude").and_then(|v| coordinate(&v)),
+        taken_at: [
+            "exif:DateTimeOriginal",
+            "photoshop:DateCreated",
+            "xmp:CreateDate",
+        ]
+        .iter()
+        .find_map(|name| property(&xmp, name).and_then(|v| date(&v))),
+    };
+
+    let empty = PhotoMetadata {
+        hash: hash.to_string(),
+        ..Default::default()
+    };
+    (metadata != empty).then_some(metadata)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Value of an XMP property, written either as an attribute (`tiff:Make="Canon"`) or as an element
+/// (`<tiff:Make>Canon</tiff:Make>`). For arrays (`rdf:Seq`, `rdf:Alt`), the first item. `None` if missing or blank.
+fn property(xmp: &str, name: &str) -> Option<String> {
+    let value = attribute(xmp, name).or_else(|| element(xmp, name))?;
+    let value = unescape(value.trim());
+    (!value.is_empty()).then_some(value)
+}
+
+fn attribute<'a>(xmp: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = xmp;
+    while let Some(found) = rest.find(name) {
+        let before = rest[..found].chars().next_back();
+        let after = rest[found + name.len()..].trim_start();
+        rest = &rest[found + name.len()..];
+
+        if !before.is_some_and(char::is_whitespace) {
+            continue;
+        }
+        let Some(after) = after.strip_prefix('=') else {
+            continue;
+        };
+        let after = after.trim_start();
+        let quote = after.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let value = &after[1..];
+        return value.find(quote).map(|end| &value[..end]);
+    }
+    None
+}
+
+fn element<'a>(xmp: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{name}");
+    let close = format!("</{name}>");
+
+    let mut rest = xmp;
+    while let Some(found) = rest.find(&open) {
+        rest = &rest[found + open.len()..];
+        // `<tiff:Make>` or `<tiff:Make rdf:parseType=…>`, not `<tiff:MakeNote>`
+        if !rest.starts_with(|c: char| c == '>' || c.is_whitespace()) {
+            continue;
+        }
+        let content = &rest[rest.find('>')? + 1..];
+        let content = &content[..content.find(&close)?];
+        return Some(match content.find("<rdf:li") {
+            Some(item) => {
+                let item = &content[item..];
+                let item = &item[item.find('>')? + 1..];
+                &item[..item.find("</rdf:li>")?]
+            }
+            None => content,
+        });
+    }
+    None
+}
+
+fn unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// `1/250` or `28/10`; a plain number counts as a rational over 1.
+fn rational(value: &str) -> Option<(u32, u32)> {
+    let (num, denom) = value.split_once('/').unwrap_or((value, "1"));
+    let (num, denom) = (num.trim().parse().ok()?, denom.trim().parse().ok()?);
+    (denom != 0).then_some((num, denom))
+}
+
+/// XMP GPS coordinate, `DDD,MM,SSk` or `DDD,MM.mmk` with `k` one of `N`, `S`, `E`, `W`, in decimal degrees;
+/// negative south and west.
+fn coordinate(value: &str) -> Option<f64> {
+    let direction = value.chars().next_back()?;
+    let negative = match direction.to_ascii_uppercase() {
+        'N' | 'E' => false,
+        'S' | 'W' => true,
+        _ => return None,
+    };
+
+    let parts = value[..value.len() - 1]
+        .split(',')
+        .map(|part| part.trim().parse::<f64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let degrees = match parts[..] {
+        [degrees, minutes] => degrees + minutes / 60.0,
+        [degrees, minutes, seconds] => degrees + minutes / 60.0 + seconds / 3600.0,
+        _ => return None,
+    };
+
+    Some(if negative { -degrees } else { degrees })
+}
+
+/// XMP date, `YYYY-MM-DDThh:mm:ss` with optional fraction and time zone, or a shorter form. The local time is
+/// kept and the time zone dropped, like EXIF `DateTimeOriginal`.
+fn date(value: &str) -> Option<NaiveDateTime> {
+    let value = value.trim();
+    value
+        .get(..19)
+        .and_then(|v| NaiveDateTime::parse_from_str(v, "%Y-%m-%dT%H:%M:%S").ok())
+        .or_else(|| {
+            value
+                .get(..16)
+                .and_then(|v| NaiveDateTime::parse_from_str(v, "%Y-%m-%dT%H:%M").ok())
+        })
+        .or_else(|| {
+            value
+                .get(..10)
+                .and_then(|v| NaiveDate::parse_from_str(v, "%Y-%m-%d").ok())?
+                .and_hms_opt(0, 0, 0)
+        })
+}
@@ -1,3 +1,6 @@
+pub mod protocol;
+pub mod registry;
+pub mod transfers;
 mod ws; // já existente
 
 pub use ws::*;
@@ -0,0 +1,156 @@
+//! # WebSocket Protocol
+//!
+//! This module defines every message exchanged over `/ws`, in both directions.
+//!
+//! ## Format
+//! Every frame is a JSON object with the protocol version, the message name and its payload:
+//! ```json
+//! { "v": 1, "name": "copy_files", "payload": { "hashes": ["..."] } }
+//! ```
+//!
+//! ## Messages
+//! - `ClientMessage`: Messages sent by clients (phone or desktop) to the server.
+//! - `ServerMessage`: Messages sent by the server to clients.
+//!
+//! ## Notes
+//! - The server sends a `hello` message as soon as a connection opens, so clients can check `version`
+//!   and warn the user about a mismatch.
+//! - Frames with another version, an unknown name or an invalid payload get an `error` reply.
+
+use axum::extract::ws::Message;
+use cube_db::TransferState;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Version of the message protocol spoken by this server.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Messages sent by clients to the server.
+///
+/// - `CopyFiles`: Asks the phones holding `hashes` to upload the original files. Only accepted from desktop viewers.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "name", content = "payload", rename_all = "snake_case")]
+pub enum ClientMessage {
+    CopyFiles { hashes: Vec<String> },
+}
+
+/// Messages sent by the server to clients.
+///
+/// - `Hello`: First frame of every connection, with the server's protocol version.
+/// - `Paired`: A phone was paired with `/auth` (sent to desktop viewers only).
+/// - `Copied`: A file was received and saved at `path`, after resolving name collisions.
+/// - `SendRaw`: Asks a phone to upload the original file with this hash.
+/// - `Transfer`: A transfer job requested with `copy_files` changed state (sent to desktop viewers only).
+/// - `Error`: The last client frame could not be handled.
+#[derive(Debug, Serialize)]
+#[serde(tag = "name", content = "payload", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Hello {
+        version: u32,
+        server: String,
+    },
+    Paired {
+        username: String,
+        device_id: String,
+        device_name: String,
+    },
+    Copied {
+        hash: String,
+        status: String,
+        path: String,
+    },
+    SendRaw {
+        hash: String,
+    },
+    Transfer {
+        id: String,
+        hash: String,
+        device_id: String,
+        state: TransferState,
+        attempts: u32,
+        error: Option<String>,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+/// Reason sent in an `Error` message.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The frame is not valid JSON, or not a text frame.
+    Malformed,
+    /// The frame's `v` field is missing or differs from `PROTOCOL_VERSION`.
+    UnsupportedVersion,
+    /// The message name is unknown or its payload does not match.
+    InvalidMessage,
+    /// No connected client can handle the request.
+    Unavailable,
+    /// The connection's role may not send this message.
+    Forbidden,
+}
+
+/// A message together with the protocol version, as sent on the wire.
+#[derive(Serialize)]
+struct Envelope<'a, T> {
+    v: u32,
+    #[serde(flatten)]
+    message: &'a T,
+}
+
+impl ServerMessage {
+    /// Builds the `hello` message sent when a connection opens.
+    pub fn hello() -> Self {
+        ServerMessage::Hello {
+            version: PROTOCOL_VERSION,
+            server: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        }
+    }
+
+    /// Builds an `error` message.
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        ServerMessage::Error {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Serializes the message with the protocol version into a WebSocket text frame.
+    pub fn to_ws(&self) -> Message {
+        let envelope = Envelope {
+            v: PROTOCOL_VERSION,
+            message: self,
+        };
+        Message::Text(serde_json::to_string(&envelope).unwrap_or_default())
+    }
+}
+
+/// Parses a text frame sent by a client.
+///
+/// # Returns
+/// The decoded message, or the `error` message to send back to the client.
+pub fn parse_client_message(text: &str) -> Result<ClientMessage, ServerMessage> {
+    let value: Value = serde_json::from_str(text)
+        .map_err(|e| ServerMessage::error(ErrorCode::Malformed, format!("Invalid JSON: {e}")))?;
+
+    match value.get("v").and_then(|v| v.as_u64()) {
+        Some(v) if v == PROTOCOL_VERSION as u64 => {}
+        Some(v) => {
+            return Err(ServerMessage::error(
+                ErrorCode::UnsupportedVersion,
+                format!("Protocol version {v} is not supported, server speaks {PROTOCOL_VERSION}"),
+            ))
+        }
+        None => {
+            return Err(ServerMessage::error(
+                ErrorCode::UnsupportedVersion,
+                format!("Missing protocol version, server speaks {PROTOCOL_VERSION}"),
+            ))
+        }
+    }
+
+    serde_json::from_value(value)
+        .map_err(|e| ServerMessage::error(ErrorCode::InvalidMessage, e.to_string()))
+}
@@ -0,0 +1,151 @@
+//! # WebSocket Client Registry
+//!
+//! This module keeps track of the open WebSocket connections and who is behind each of them.
+//!
+//! ## Features
+//! - Each connection gets a `ConnectionId` and is stored with its role, username and device.
+//! - Messages are sent to a single device, to every device of a user, or to every client of a role,
+//!   so phones never receive messages meant for other users.
+//! - Each client has a bounded queue of `CLIENT_QUEUE_SIZE` messages. Clients whose queue is full (too slow
+//!   to keep up) or closed are removed, which also closes their socket.
+//!
+//! ## Roles
+//! - `Phone`: A paired phone, authenticated with its session token.
+//! - `Desktop`: A photo viewer running on the server machine itself, which receives events about every user.
+
+use axum::extract::ws::Message;
+use serde::Deserialize;
+use std::collections::HashMap;
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+use uuid::Uuid;
+
+use super::protocol::ServerMessage;
+
+/// Number of messages that may wait in a client's queue before it is considered too slow and dropped.
+pub const CLIENT_QUEUE_SIZE: usize = 64;
+
+/// Identifier of a single WebSocket connection.
+pub type ConnectionId = Uuid;
+
+/// Kind of client behind a connection, chosen with `/ws?role=`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientRole {
+    #[default]
+    Phone,
+    Desktop,
+}
+
+/// A connected WebSocket client.
+///
+/// - `role`: Whether the client is a phone or a desktop viewer.
+/// - `token`: The session token the connection was opened with (phones only).
+/// - `username`: The user the token belongs to (phones only).
+/// - `device_id`: The paired device the token belongs to (phones only).
+/// - `tx`: Channel used to send messages to the socket.
+pub struct WsClient {
+    pub role: ClientRole,
+    pub token: Option<String>,
+    pub username: Option<String>,
+    pub device_id: Option<String>,
+    pub tx: Sender<Message>,
+}
+
+/// Open WebSocket connections, keyed by connection ID.
+#[derive(Default)]
+pub struct Registry {
+    clients: HashMap<ConnectionId, WsClient>,
+}
+
+impl Registry {
+    /// Adds a client and returns its new connection ID.
+    pub fn register(&mut self, client: WsClient) -> ConnectionId {
+        let id = Uuid::new_v4();
+        self.clients.insert(id, client);
+        id
+    }
+
+    /// Removes a client, usually because its socket closed.
+    pub fn unregister(&mut self, id: ConnectionId) {
+        self.clients.remove(&id);
+    }
+
+    /// Sends a message to a single connection.
+    pub fn send_to(&mut self, id: ConnectionId, message: &ServerMessage) -> bool {
+        self.send_where(|cid, _| *cid == id, message) > 0
+    }
+
+    /// Sends a message to every connection of a paired device. Returns the number of connections reached.
+    pub fn send_to_device(&mut self, device_id: &str, message: &ServerMessage) -> usize {
+        self.send_where(|_, c| c.device_id.as_deref() == Some(device_id), message)
+    }
+
+    /// Sends a message to every device of a user. Returns the number of connections reached.
+    pub fn send_to_user(&mut self, username: &str, message: &ServerMessage) -> usize {
+        self.send_where(|_, c| c.username.as_deref() == Some(username), message)
+    }
+
+    /// Sends a message to every client with the given role. Returns the number of connections reached.
+    pub fn send_to_role(&mut self, role: ClientRole, message: &ServerMessage) -> usize {
+        self.send_where(|_, c| c.role == role, message)
+    }
+
+    /// Returns true if the device has at least one open connection.
+    pub fn is_device_connected(&self, device_id: &str) -> bool {
+        self.clients
+            .values()
+            .any(|c| c.device_id.as_deref() == Some(device_id))
+    }
+
+    /// Points the connections opened with `old` to the token that replaced it.
+    pub fn replace_token(&mut self, old: &str, new: &str) {
+        for client in self
+            .clients
+            .values_mut()
+            .filter(|c| c.token.as_deref() == Some(old))
+        {
+            client.token = Some(new.to_string());
+        }
+    }
+
+    /// Closes and removes every connection opened with `token`.
+    pub fn disconnect_token(&mut self, token: &str) {
+        self.clients.retain(|_, client| {
+            if client.token.as_deref() == Some(token) {
+                let _ = client.tx.try_send(Message::Close(None));
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Queues a message for every matching client, dropping clients that are too slow or gone.
+    fn send_where(
+        &mut self,
+        filter: impl Fn(&ConnectionId, &WsClient) -> bool,
+        message: &ServerMessage,
+    ) -> usize {
+        let msg = message.to_ws();
+        let mut reached = 0;
+        let mut dropped = Vec::new();
+
+        for (id, client) in self.clients.iter().filter(|(id, c)| filter(id, c)) {
+            match client.tx.try_send(msg.clone()) {
+                Ok(()) => reached += 1,
+                Err(TrySendError::Full(_)) => {
+                    println!("🐢 WS client {} is too slow, disconnecting", id);
+                    dropped.push(*id);
+                }
+                Err(TrySendError::Closed(_)) => dropped.push(*id),
+            }
+        }
+
+        // Dropping the sender ends the client's socket task
+        for id in dropped {
+            self.clients.remove(&id);
+        }
+
+        reached
+    }
+}
@@ -0,0 +1,275 @@
+//! # Transfer Jobs
+//!
+//! This module keeps track of the original files requested with `copy_files` until they arrive.
+//!
+//! ## Features
+//! - Every requested hash becomes a row of the `transfer_jobs` table, so requests survive phones going
+//!   offline and server restarts.
+//! - A job is `pending` until its phone is connected, `dispatched` once `send_raw` was sent, `uploading`
+//!   once the phone opened an upload session for the hash, and `done` when the file was stored.
+//! - Jobs are re-dispatched as soon as the owning device reconnects.
+//! - Dispatched jobs whose upload does not arrive are retried with exponential backoff (see `retry_delay`)
+//!   and marked `failed` after `MAX_ATTEMPTS` attempts.
+//! - Every state change is pushed to desktop viewers as a `transfer` message.
+//!
+//! ## Notes
+//! - Requesting a hash that already has an unfinished job re-dispatches that job instead of creating another.
+
+use chrono::{DateTime, Utc};
+use cube_db::{Repository, SqliteRepository, TransferJob, TransferState};
+use std::{sync::Arc, time::Duration};
+use uuid::Uuid;
+
+use super::protocol::{ErrorCode, ServerMessage};
+use super::registry::{ClientRole, ConnectionId};
+use crate::state::AppState;
+use crate::utils::hash::is_valid_hash;
+
+/// Number of `send_raw` attempts before a job is marked as failed.
+pub const MAX_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry; doubled after every attempt.
+const RETRY_BASE: Duration = Duration::from_secs(30);
+
+/// Upper bound for the delay between two attempts.
+const RETRY_MAX: Duration = Duration::from_secs(30 * 60);
+
+/// Time an upload session may take before its job is retried.
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Interval between two scans for jobs that are due for a retry.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Builds the `transfer` message sent to desktop viewers.
+pub fn to_message(job: &TransferJob) -> ServerMessage {
+    ServerMessage::Transfer {
+        id: job.id.clone(),
+        hash: job.hash.clone(),
+        device_id: job.device_id.clone(),
+        state: job.state,
+        attempts: job.attempts,
+        error: job.last_error.clone(),
+    }
+}
+
+/// Delay before retrying a job that was dispatched `attempts` times.
+pub fn retry_delay(attempts: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+    RETRY_BASE.saturating_mul(factor).min(RETRY_MAX)
+}
+
+/// Creates (or reuses) a job for every requested hash and dispatches the ones whose device is connected.
+///
+/// Only desktop viewers may request copies (see `ws::handle_socket`). Malformed hashes get an `invalid_message`
+/// error and hashes that no device announced get an `unavailable` error, sent back to connection `from`.
+pub async fn request_copies(state: &AppState, from: ConnectionId, hashes: Vec<String>) {
+    for hash in hashes {
+        if !is_valid_hash(&hash) {
+            let reply =
+                ServerMessage::error(ErrorCode::InvalidMessage, format!("Invalid hash {hash}"));
+            state.ws_state.lock().await.send_to(from, &reply);
+            continue;
+        }
+
+        let lookup = hash.clone();
+        let device_id = state
+            .db(move |repo| repo.upload_device(&lookup))
+            .await
+            .ok()
+            .flatten();
+
+        let Some(device_id) = device_id else {
+            let reply =
+                ServerMessage::error(ErrorCode::Unavailable, format!("No device holds {hash}"));
+            state.ws_state.lock().await.send_to(from, &reply);
+            continue;
+        };
+
+        match find_or_create_job(state, &hash, &device_id).await {
+            Ok(job) => dispatch(state, job).await,
+            Err(e) => {
+                println!("❌ Erro ao criar transferência de {}: {}", hash, e);
+                let reply =
+                    ServerMessage::error(ErrorCode::Unavailable, format!("Could not queue {hash}"));
+                state.ws_state.lock().await.send_to(from, &reply);
+            }
+        }
+    }
+}
+
+/// Re-dispatches every unfinished job of a device; called when the device connects.
+pub async fn dispatch_device(state: &AppState, device_id: &str) {
+    let device = device_id.to_string();
+    let jobs = select_jobs(state, move |repo| repo.active_jobs_for_device(&device)).await;
+
+    if jobs.is_empty() {
+        return;
+    }
+
+    println!(
+        "🔁 Re-dispatching {} transfer(s) to device {}",
+        jobs.len(),
+        device_id
+    );
+    for job in jobs {
+        dispatch(state, job).await;
+    }
+}
+
+/// Marks the unfinished jobs of `hash` on the devices of `username` as `uploading`; called when that user opens
+/// an upload session.
+pub async fn mark_uploading(state: &AppState, hash: &str, username: &str) {
+    let next_attempt_at = Utc::now() + UPLOAD_TIMEOUT;
+    update_jobs_of_hash(
+        state,
+        hash,
+        username,
+        TransferState::Uploading,
+        Some(next_attempt_at),
+    )
+    .await;
+}
+
+/// Marks the unfinished jobs of `hash` on the devices of `username` as `done`; called when that user's file was
+/// stored.
+pub async fn mark_done(state: &AppState, hash: &str, username: &str) {
+    update_jobs_of_hash(state, hash, username, TransferState::Done, None).await;
+}
+
+/// Periodically retries dispatched jobs whose upload did not arrive in time.
+///
+/// Runs forever; spawn it once at startup.
+pub async fn start_transfer_worker(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(RETRY_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let jobs = select_jobs(&state, |repo| repo.due_jobs(Utc::now())).await;
+
+        for mut job in jobs {
+            if job.attempts >= MAX_ATTEMPTS {
+                job.state = TransferState::Failed;
+                job.last_error = Some(format!("File not received after {} attempts", job.attempts));
+                println!("❌ Transfer of {} failed", job.hash);
+                save(&state, &job, None).await;
+                notify(&state, &job).await;
+            } else {
+                dispatch(&state, job).await;
+            }
+        }
+    }
+}
+
+/// Sends `send_raw` for a job if its device is connected, otherwise leaves it `pending`.
+async fn dispatch(state: &AppState, mut job: TransferJob) {
+    let next_attempt_at = {
+        let mut clients = state.ws_state.lock().await;
+        if clients.is_device_connected(&job.device_id) {
+            job.attempts += 1;
+            job.state = TransferState::Dispatched;
+            println!(
+                "⬇️ Sending download for {} to device {} (attempt {})",
+                job.hash, job.device_id, job.attempts
+            );
+            clients.send_to_device(
+                &job.device_id,
+                &ServerMessage::SendRaw {
+                    hash: job.hash.clone(),
+                },
+            );
+            Some(Utc::now() + retry_delay(job.attempts))
+        } else {
+            job.state = TransferState::Pending;
+            None
+        }
+    };
+
+    save(state, &job, next_attempt_at).await;
+    notify(state, &job).await;
+}
+
+/// Returns the unfinished job of `hash` on `device_id`, or inserts a new `pending` one.
+async fn find_or_create_job(
+    state: &AppState,
+    hash: &str,
+    device_id: &str,
+) -> Result<TransferJob, String> {
+    let device = device_id.to_string();
+    let existing = state
+        .db(move |repo| repo.active_jobs_for_device(&device))
+        .await?;
+
+    if let Some(job) = existing.into_iter().find(|job| job.hash == hash) {
+        return Ok(job);
+    }
+
+    let job = TransferJob {
+        id: Uuid::new_v4().to_string(),
+        hash: hash.to_string(),
+        device_id: device_id.to_string(),
+        state: TransferState::Pending,
+        attempts: 0,
+        last_error: None,
+    };
+    let new_job = job.clone();
+    state.db(move |repo| repo.insert_job(&new_job)).await?;
+
+    Ok(job)
+}
+
+/// Moves every unfinished job of `hash` on the devices of `username` to `new_state` and notifies desktop viewers.
+async fn update_jobs_of_hash(
+    state: &AppState,
+    hash: &str,
+    username: &str,
+    new_state: TransferState,
+    next_attempt_at: Option<DateTime<Utc>>,
+) {
+    let (lookup, owner) = (hash.to_string(), username.to_string());
+    let jobs = select_jobs(state, move |repo| {
+        repo.active_jobs_for_hash(&lookup, &owner)
+    })
+    .await;
+
+    for mut job in jobs {
+        job.state = new_state;
+        save(state, &job, next_attempt_at).await;
+        notify(state, &job).await;
+    }
+}
+
+/// Runs a job query on the database worker, logging errors as an empty list.
+async fn select_jobs<F>(state: &AppState, f: F) -> Vec<TransferJob>
+where
+    F: FnOnce(&SqliteRepository) -> cube_db::Result<Vec<TransferJob>> + Send + 'static,
+{
+    match state.db(f).await {
+        Ok(jobs) => jobs,
+        Err(e) => {
+            println!("❌ Erro ao ler transferências: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Writes the state, attempts and error of a job.
+async fn save(state: &AppState, job: &TransferJob, next_attempt_at: Option<DateTime<Utc>>) {
+    let saved = job.clone();
+    let result = state
+        .db(move |repo| repo.save_job(&saved, next_attempt_at))
+        .await;
+
+    if let Err(e) = result {
+        println!("❌ Erro ao salvar transferência {}: {}", job.id, e);
+    }
+}
+
+/// Pushes the job's current state to desktop viewers.
+async fn notify(state: &AppState, job: &TransferJob) {
+    state
+        .ws_state
+        .lock()
+        .await
+        .send_to_role(ClientRole::Desktop, &to_message(job));
+}
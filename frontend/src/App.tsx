@@ -4,7 +4,6 @@ import { FluentProvider, Label, webLightTheme } from "@fluentui/react-components
 import { useEffect, useRef, useState } from "react";
 
 import { ConnectionStatus } from "./components/ConnectionStatus";
-import Database from "@tauri-apps/plugin-sql";
 import { Menu } from '@tauri-apps/api/menu';
 import { PhotoGrid } from "./PhotoGrid";
 import { QrCodePanel } from "./qr/qrCodePanel";
@@ -18,7 +17,6 @@ let trayStarted = false;
 function App() {
   const [token, setToken] = useState<string | null>(null);
   const [isOnline, setIsOnline] = useState(false);
-  const [db, setDb] = useState<any | null>(null);
   
   const menuRef = useRef<TrayIcon | null>(null);
 
@@ -72,21 +70,6 @@ function App() {
 
   }, []);
 
-  // Carrega o banco de dados ao iniciar o app
-  useEffect(() => {
-    async function initDb() {
-      try {
-        const dbInstance = await Database.load("sqlite:uploads.db");
-        setDb(dbInstance);
-        console.log("✅ Banco de dados carregado com sucesso.");
-      } catch (error) {
-        console.error("❌ Erro ao carregar banco de dados:", error);
-      }
-    }
-
-    initDb();
-  }, []);
-
   return (
     <main className="container">
       <FluentProvider
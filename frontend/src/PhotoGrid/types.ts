@@ -6,4 +6,25 @@ export type Photo = {
   name: string;
   size: string;
   status: PhotoStatus;
+  metadata?: PhotoMetadata;
+};
+
+export type PhotoMetadata = {
+  hash: string;
+  camera_make: string | null;
+  camera_model: string | null;
+  lens_model: string | null;
+  exposure_time: string | null;
+  f_number: number | null;
+  iso: number | null;
+  focal_length: number | null;
+  gps_latitude: number | null;
+  gps_longitude: number | null;
+  taken_at: string | null;
+};
+
+export type PhotoPage = {
+  items: Photo[];
+  total: number;
+  next_cursor: string | null;
 };
@@ -0,0 +1,12 @@
+import { invoke } from "@tauri-apps/api/core";
+
+// Chave que identifica esta janela para o servidor embutido do app, gerada a cada execução.
+let pending: Promise<string> | null = null;
+
+export function desktopKey(): Promise<string> {
+  pending ??= invoke<string>("desktop_key").catch((err: unknown) => {
+    pending = null;
+    throw err;
+  });
+  return pending;
+}
@@ -1,22 +1,59 @@
 import { useEffect, useRef, useState } from "react";
+import { desktopKey } from "../desktopKey";
+
+// Versão do protocolo WS falada por este cliente (ver cube-server/src/ws/protocol.rs)
+export const PROTOCOL_VERSION = 1;
+
+// Estado de um pedido de cópia (copy_files), enviado pelo servidor a cada mudança
+export interface TransferUpdate {
+  id: string;
+  hash: string;
+  device_id: string;
+  state: "pending" | "dispatched" | "uploading" | "done" | "failed";
+  attempts: number;
+  error: string | null;
+}
 
 interface UseWebSocketOptions {
-  onTokenReceived: (token: string) => void;
+  onPaired: (username: string) => void;
+  onTransfer?: (transfer: TransferUpdate) => void;
   onStatusChange?: (connected: boolean) => void;
 }
 
 export function useWebSocket({
-  onTokenReceived,
+  onPaired,
+  onTransfer,
   onStatusChange,
 }: UseWebSocketOptions) {
   const wsRef = useRef<WebSocket | null>(null);
   const [connected, setConnected] = useState(false);
   const reconnectDelay = useRef(1000); // ms
 
-  const connect = () => {
+  const reconnect = () => {
+    setTimeout(() => {
+      reconnectDelay.current = Math.min(reconnectDelay.current * 2, 15000);
+      connect();
+    }, reconnectDelay.current);
+  };
+
+  const connect = async () => {
     if (wsRef.current?.readyState === WebSocket.OPEN) return;
 
-    const ws = new WebSocket("ws://bruno-linux:8080/ws");
+    let key: string;
+    try {
+      key = await desktopKey();
+    } catch (err) {
+      console.error("🔴 Chave desktop indisponível:", err);
+      reconnect();
+      return;
+    }
+
+    // Visualizador desktop: recebe eventos de todos os celulares, só aceito na própria máquina do servidor
+    // e com a chave desktop. Usa 127.0.0.1: um nome de host resolvido para o IP da rede local não chega pela
+    // interface de loopback e seria recusado
+    const url = new URL("ws://127.0.0.1:8080/ws?role=desktop");
+    url.searchParams.set("desktop_key", key);
+    const ws = new WebSocket(url);
     wsRef.current = ws;
 
     ws.onopen = () => {
@@ -29,9 +66,25 @@ export function useWebSocket({
     ws.onmessage = (event) => {
       try {
         const data = JSON.parse(event.data);
-        if (data.token) {
-          console.log("📥 Token recebido via WS:", data.token);
-          onTokenReceived(data.token);
+        switch (data.name) {
+          case "hello":
+            if (data.payload?.version !== PROTOCOL_VERSION) {
+              console.warn(
+                `⚠️ Versão do protocolo diferente: servidor ${data.payload?.version}, cliente ${PROTOCOL_VERSION}`
+              );
+            }
+            break;
+          case "paired":
+            console.log("📥 Celular pareado via WS:", data.payload.username);
+            onPaired(data.payload.username);
+            break;
+          case "transfer":
+            console.log(`📦 Transferência ${data.payload.hash}: ${data.payload.state}`);
+            onTransfer?.(data.payload);
+            break;
+          case "error":
+            console.error("❌ Erro do servidor WS:", data.payload);
+            break;
         }
       } catch (err) {
         console.error("❌ Erro ao processar WS:", err);
@@ -46,11 +99,7 @@ export function useWebSocket({
       console.warn("🟡 WebSocket desconectado");
       setConnected(false);
       onStatusChange?.(false);
-
-      setTimeout(() => {
-        reconnectDelay.current = Math.min(reconnectDelay.current * 2, 15000);
-        connect();
-      }, reconnectDelay.current);
+      reconnect();
     };
   };
 
@@ -65,7 +114,7 @@ export function useWebSocket({
   return {
     send: (data: any) => {
       if (connected && wsRef.current?.readyState === WebSocket.OPEN) {
-        wsRef.current.send(JSON.stringify(data));
+        wsRef.current.send(JSON.stringify({ v: PROTOCOL_VERSION, ...data }));
       } else {
         console.warn("🔌 WS não está conectado.");
       }
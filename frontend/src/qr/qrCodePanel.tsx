@@ -1,4 +1,3 @@
-import Database from "@tauri-apps/plugin-sql";
 import { QrCard } from "./QrCard";
 import React from "react";
 import { invoke } from "@tauri-apps/api/core";
@@ -9,18 +8,13 @@ export const QrCodePanel: React.FC = () => {
    
   React.useEffect(() => {
     async function get_qr_code() {
-      const db = await Database.load("sqlite:uploads.db");
       try {
+        // O backend guarda o código no banco do servidor HTTP
         const qrVars = await invoke<{ code: string; ip: string; expires_in: number }>(
           "get_qr_code"
         );
 
-        const { code, ip, expires_in } = qrVars;
-
-        await db.execute(
-          "INSERT INTO auth_codes (code, ip, created_at) VALUES (?, ?, ?)",
-          [code, ip, expires_in],
-        );
+        const { code, ip } = qrVars;
 
         setLink(`http://${ip}:8080?code=${code}`);
       } catch (err) {